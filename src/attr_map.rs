@@ -0,0 +1,101 @@
+use fuser::{FileAttr, FileType};
+
+use crate::config::{Config, IdMapping, ModeOverride, Timestamps};
+
+// How the attributes of backing files are presented to the kernel
+pub struct AttrMap {
+    uid_map: Vec<IdMapping>,
+    gid_map: Vec<IdMapping>,
+    squash_owner: Option<(u32, u32)>,
+    file_mode: Option<ModeOverride>,
+    dir_mode: Option<ModeOverride>,
+    no_exec: bool,
+    timestamps: Timestamps,
+}
+
+impl AttrMap {
+    pub fn new(config: &Config) -> AttrMap {
+        AttrMap {
+            uid_map: config.uid_map.clone(),
+            gid_map: config.gid_map.clone(),
+            squash_owner: config.squash_owner,
+            file_mode: config.file_mode,
+            dir_mode: config.dir_mode,
+            no_exec: config.no_exec,
+            timestamps: config.timestamps,
+        }
+    }
+
+    // Present the attributes of a backing file
+    pub fn apply(&self, fileattr: &mut FileAttr) {
+        match self.squash_owner {
+            Some((uid, gid)) => {
+                fileattr.uid = uid;
+                fileattr.gid = gid;
+            }
+            None => {
+                fileattr.uid = map_id(&self.uid_map, fileattr.uid, false);
+                fileattr.gid = map_id(&self.gid_map, fileattr.gid, false);
+            }
+        }
+
+        // The permissions of a symlink are never used
+        let mode = match fileattr.kind {
+            FileType::Directory => self.dir_mode,
+            FileType::Symlink => None,
+            _ => self.file_mode,
+        };
+        if let Some(mode) = mode {
+            fileattr.perm = (fileattr.perm & mode.and) | mode.or;
+        }
+        if self.no_exec && fileattr.kind != FileType::Directory {
+            fileattr.perm &= !0o111;
+        }
+
+        let times = [
+            &mut fileattr.atime,
+            &mut fileattr.mtime,
+            &mut fileattr.ctime,
+            &mut fileattr.crtime,
+        ];
+        match self.timestamps {
+            Timestamps::Unchanged => (),
+            Timestamps::Clamp(limit) => {
+                for time in times {
+                    *time = (*time).min(limit);
+                }
+            }
+            Timestamps::Fixed(fixed) => {
+                for time in times {
+                    *time = fixed;
+                }
+            }
+        }
+    }
+
+    // The backing uid for a uid given to us by the kernel
+    pub fn backing_uid(&self, uid: u32) -> u32 {
+        map_id(&self.uid_map, uid, true)
+    }
+
+    // The backing gid for a gid given to us by the kernel
+    pub fn backing_gid(&self, gid: u32) -> u32 {
+        map_id(&self.gid_map, gid, true)
+    }
+}
+
+// Map id from backing to presented, or the reverse. The first mapping
+// containing id is used, and an id which isn't mapped is unchanged.
+fn map_id(map: &[IdMapping], id: u32, reverse: bool) -> u32 {
+    for mapping in map {
+        let (from, to) = if reverse {
+            (mapping.presented, mapping.backing)
+        } else {
+            (mapping.backing, mapping.presented)
+        };
+        if id >= from && id - from < mapping.count {
+            return to + (id - from);
+        }
+    }
+    id
+}
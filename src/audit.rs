@@ -0,0 +1,108 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing::warn;
+
+// The process which made a request
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Caller {
+    pub fn new(req: &fuser::Request<'_>) -> Caller {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+
+    // The name of the caller's command, or ? if it has exited or the request
+    // came from the kernel
+    pub fn command(&self) -> String {
+        #[cfg(target_os = "linux")]
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", self.pid));
+        #[cfg(target_os = "freebsd")]
+        let comm = crate::freebsd::command(self.pid);
+        match comm {
+            Ok(comm) => comm.trim_end().to_string(),
+            Err(_) => "?".into(),
+        }
+    }
+}
+
+// A file or pipe to which a JSON object is written on a line of its own for
+// every audited request
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    // Record that caller made an op request about path, which failed with
+    // errno if set
+    pub fn record(&self, op: &str, caller: &Caller, path: &str, errno: Option<i32>) {
+        let timestamp = match timestamp() {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            "{{\"time\":\"{}\",\"op\":\"{}\",\"uid\":{},\"gid\":{},\"pid\":{},\"path\":",
+            timestamp, op, caller.uid, caller.gid, caller.pid
+        );
+        json_string(&mut line, path);
+        match errno {
+            Some(errno) => {
+                let _ = write!(line, ",\"result\":\"error\",\"errno\":{}}}", errno);
+            }
+            None => line.push_str(",\"result\":\"ok\"}"),
+        }
+        line.push('\n');
+
+        // A single write keeps lines whole for readers of a pipe
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("audit log: {}", err);
+        }
+    }
+}
+
+// The current time in UTC, in RFC 3339 format with microseconds
+pub fn timestamp() -> Option<String> {
+    let now = time::now_utc();
+    let seconds = now.strftime("%Y-%m-%dT%H:%M:%S").ok()?;
+    Some(format!("{}.{:06}Z", seconds, now.tm_nsec / 1000))
+}
+
+// Append value to out as a JSON string
+pub fn json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
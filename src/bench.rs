@@ -0,0 +1,232 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error_chain::bail;
+use passfs::errors::*;
+
+const DIRS: usize = 10;
+const SMALL_FILE_SIZE: usize = 4096;
+const SEQUENTIAL_BLOCK: usize = 128 * 1024;
+const RANDOM_BLOCK: usize = 4096;
+const RANDOM_READS: usize = 10000;
+
+pub struct Options {
+    // Number of small files in the generated tree
+    pub files: usize,
+    // Size of the file used for read throughput
+    pub file_size: u64,
+    // Extra arguments for the mounted passfs
+    pub args: Vec<String>,
+}
+
+// Mount passfs over a generated tree in a temporary directory, and compare
+// the time taken by common operations through the mount with the time taken
+// by the same operations on the tree directly.
+pub fn run(options: &Options) -> Result<()> {
+    let base = env::temp_dir().join(format!("passfs-bench-{}", std::process::id()));
+    let root = base.join("root");
+    let mnt = base.join("mnt");
+    fs::create_dir_all(&root).chain_err(|| format!("Error creating {}", root.display()))?;
+    fs::create_dir_all(&mnt).chain_err(|| format!("Error creating {}", mnt.display()))?;
+
+    let result = generate(&root, options)
+        .chain_err(|| "Error generating benchmark tree")
+        .and_then(|()| {
+            let mut child = mount(&root, &mnt, &options.args)?;
+            let result = compare(&root, &mnt, options);
+            unmount(&mnt, &mut child);
+            result
+        });
+
+    if let Err(err) = fs::remove_dir_all(&base) {
+        eprintln!("Error removing {}: {}", base.display(), err);
+    }
+    result
+}
+
+fn generate(root: &Path, options: &Options) -> io::Result<()> {
+    let data = vec![0x5a; SMALL_FILE_SIZE];
+    for i in 0..options.files {
+        let dir = root.join(format!("dir{}", i % DIRS));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("file{}", i)), &data)?;
+    }
+
+    let mut large = File::create(root.join("large"))?;
+    let block = vec![0xa5; SEQUENTIAL_BLOCK];
+    let mut written = 0;
+    while written < options.file_size {
+        let len = (options.file_size - written).min(block.len() as u64) as usize;
+        large.write_all(&block[..len])?;
+        written += len as u64;
+    }
+    large.sync_all()
+}
+
+// Start passfs on mnt as a child process, and wait for the mount to appear
+fn mount(root: &Path, mnt: &Path, args: &[String]) -> Result<Child> {
+    let exe = env::current_exe().chain_err(|| "Error finding passfs executable")?;
+    let mut child = Command::new(exe)
+        .args(args)
+        .arg(root)
+        .arg(mnt)
+        .spawn()
+        .chain_err(|| "Error starting passfs")?;
+
+    let unmounted = fs::metadata(mnt)
+        .chain_err(|| format!("Error reading {}", mnt.display()))?
+        .dev();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            bail!("passfs exited before mounting: {}", status);
+        }
+        if fs::metadata(mnt).is_ok_and(|m| m.dev() != unmounted) {
+            return Ok(child);
+        }
+        if Instant::now() > deadline {
+            unmount(mnt, &mut child);
+            bail!("Timed out waiting for passfs to mount {}", mnt.display());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn unmount(mnt: &Path, child: &mut Child) {
+    // FreeBSD has no fusermount, but lets users unmount their own mounts
+    #[cfg(target_os = "linux")]
+    let mut command = Command::new("fusermount");
+    #[cfg(target_os = "linux")]
+    command.arg("-u");
+    #[cfg(target_os = "freebsd")]
+    let mut command = Command::new("umount");
+    let unmounted = command
+        .arg(mnt)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !unmounted {
+        eprintln!("Error unmounting {}: killing passfs", mnt.display());
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+fn compare(root: &Path, mnt: &Path, options: &Options) -> Result<()> {
+    // Benchmarks which count bytes rather than operations are reported as
+    // throughput
+    type Benchmark = fn(&Path, &Options) -> io::Result<u64>;
+    let benchmarks: &[(&str, bool, Benchmark)] = &[
+        ("lookup", false, lookup),
+        ("getattr", false, getattr),
+        ("readdir", false, readdir),
+        ("sequential read", true, sequential_read),
+        ("random read", true, random_read),
+    ];
+
+    println!(
+        "{:<16} {:>14} {:>14} {:>8}",
+        "", "direct", "passfs", "ratio"
+    );
+    for (name, bytes, benchmark) in benchmarks {
+        let (direct, count) =
+            time(|| benchmark(root, options)).chain_err(|| format!("Error running {}", name))?;
+        let (passfs, _) =
+            time(|| benchmark(mnt, options)).chain_err(|| format!("Error running {}", name))?;
+        println!(
+            "{:<16} {:>14} {:>14} {:>7.2}x",
+            name,
+            rate(count, direct, *bytes),
+            rate(count, passfs, *bytes),
+            passfs.as_secs_f64() / direct.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+// Return the time taken by f, and the count it returned
+fn time<F>(f: F) -> io::Result<(Duration, u64)>
+where
+    F: FnOnce() -> io::Result<u64>,
+{
+    let start = Instant::now();
+    let count = f()?;
+    Ok((start.elapsed(), count))
+}
+
+fn rate(count: u64, elapsed: Duration, bytes: bool) -> String {
+    let per_sec = count as f64 / elapsed.as_secs_f64();
+    if bytes {
+        format!("{:.1} MiB/s", per_sec / (1024.0 * 1024.0))
+    } else {
+        format!("{:.0} ops/s", per_sec)
+    }
+}
+
+fn small_file(top: &Path, i: usize) -> PathBuf {
+    top.join(format!("dir{}", i % DIRS))
+        .join(format!("file{}", i))
+}
+
+// Each returns the number of operations or bytes it processed
+
+fn lookup(top: &Path, options: &Options) -> io::Result<u64> {
+    for i in 0..options.files {
+        fs::symlink_metadata(small_file(top, i))?;
+    }
+    Ok(options.files as u64)
+}
+
+fn getattr(top: &Path, options: &Options) -> io::Result<u64> {
+    let file = File::open(small_file(top, 0))?;
+    for _ in 0..options.files {
+        file.metadata()?;
+    }
+    Ok(options.files as u64)
+}
+
+fn readdir(top: &Path, _options: &Options) -> io::Result<u64> {
+    let mut count = 0;
+    for i in 0..DIRS {
+        for entry in fs::read_dir(top.join(format!("dir{}", i)))? {
+            entry?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn sequential_read(top: &Path, _options: &Options) -> io::Result<u64> {
+    let mut file = File::open(top.join("large"))?;
+    let mut buf = vec![0; SEQUENTIAL_BLOCK];
+    let mut total = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(total),
+            n => total += n as u64,
+        }
+    }
+}
+
+fn random_read(top: &Path, options: &Options) -> io::Result<u64> {
+    let file = File::open(top.join("large"))?;
+    let blocks = (options.file_size / RANDOM_BLOCK as u64).max(1);
+    let mut buf = vec![0; RANDOM_BLOCK];
+
+    // xorshift with a fixed seed, so both runs read the same blocks
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut total = 0;
+    for _ in 0..RANDOM_READS {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let offset = (state % blocks) * RANDOM_BLOCK as u64;
+        total += file.read_at(&mut buf, offset)? as u64;
+    }
+    Ok(total)
+}
@@ -0,0 +1,708 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+/// How the kernel page cache is used for the contents of files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// The kernel caches file contents, but discards the cache whenever a
+    /// file is opened.
+    #[default]
+    Auto,
+
+    /// The kernel keeps cached file contents across opens. This is only safe
+    /// if the backing tree is never modified other than through passfs.
+    Keep,
+
+    /// The kernel doesn't cache file contents at all, so every read sees the
+    /// current contents of the backing file. Shared writable mmap is not
+    /// supported in this mode on older kernels.
+    Direct,
+}
+
+/// How a branch is chosen among several which could be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BranchPolicy {
+    /// Use the first branch, in order of priority.
+    #[default]
+    FirstFound,
+    /// Use the branch with the most free space.
+    MostFreeSpace,
+    /// Use the first branch which already contains the directory of the
+    /// file, so that new files are kept beside their siblings.
+    PathPreserving,
+}
+
+/// Who checks that the caller of a request may access the file it is about,
+/// from the mode and owner the file is presented with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PermissionCheck {
+    /// Nobody: every caller may do whatever our own credentials allow.
+    #[default]
+    Off,
+    /// The kernel, by mounting with default_permissions.
+    Kernel,
+    /// We do, in lookup, open, opendir and access, in every request which
+    /// adds or removes an entry of a directory, honouring the sticky bit, and
+    /// in setattr, setxattr and removexattr.
+    Daemon,
+}
+
+/// What to do with files larger than max_file_size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Oversize {
+    /// Hide them, as if they don't exist.
+    #[default]
+    Hide,
+    /// Show them, but fail to open them with EFBIG.
+    Deny,
+}
+
+/// How the timestamps of presented files are normalised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timestamps {
+    /// Present timestamps as they are.
+    #[default]
+    Unchanged,
+    /// Present any timestamp later than this as this.
+    Clamp(SystemTime),
+    /// Present every timestamp as this.
+    Fixed(SystemTime),
+}
+
+/// Maps a range of count ids, starting at backing in the backing tree, to the
+/// same number of ids starting at presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    pub backing: u32,
+    pub presented: u32,
+    pub count: u32,
+}
+
+/// Changes the permission bits of presented files to (perm & and) | or.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeOverride {
+    pub and: u16,
+    pub or: u16,
+}
+
+/// Rewrites from in the names of backing files to to in the names they are
+/// presented with. from is literal, but may be anchored to the start of a
+/// name with ^ or to the end with $.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameRule {
+    pub from: String,
+    pub to: String,
+}
+
+/// Whose requests an access policy applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Principal {
+    /// The user with this uid.
+    Uid(u32),
+    /// Members of the group with this gid, as their primary group or a
+    /// supplementary one.
+    Gid(u32),
+}
+
+/// What an access policy allows done to the files it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyOp {
+    /// Open files for reading.
+    Read,
+    /// Open files for writing.
+    Write,
+    /// List directories.
+    List,
+}
+
+/// Allows principal to look up files matching any of patterns, which are
+/// gitignore-style globs as used by filter rules, and to do operations to
+/// them. A pattern matching a directory also matches everything below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessPolicy {
+    pub principal: Principal,
+    pub patterns: Vec<String>,
+    pub operations: Vec<PolicyOp>,
+}
+
+/// Whose requests share a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitKey {
+    /// Every process of a user.
+    #[default]
+    Uid,
+    /// Each process.
+    Pid,
+}
+
+/// Limits the rate at which each caller may make requests and read and
+/// write data, with token buckets holding a second's worth of each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub ops_per_sec: Option<NonZeroU32>,
+    pub bytes_per_sec: Option<NonZeroU64>,
+    pub per: RateLimitKey,
+    /// Delay reads and writes until the caller is within their limits again,
+    /// rather than failing them. Other requests always fail. The reply is
+    /// delayed, so other requests are handled in the meantime.
+    pub delay: bool,
+}
+
+/// Fails requests with an error, to test how applications handle a flaky
+/// disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    /// The operations it applies to, by their FUSE names such as "read" or
+    /// "getattr", or every operation if empty. Releasing files is never
+    /// failed.
+    pub ops: Vec<String>,
+    /// A gitignore-style glob, as used by filter rules, which the path of the
+    /// file must match. A pattern ending in / never matches.
+    pub pattern: Option<String>,
+    /// The errno requests fail with, such as EIO, ENOSPC or EACCES.
+    pub errno: i32,
+    /// The probability that a request it applies to fails, from 0 to 1.
+    pub probability: f64,
+}
+
+/// Delays requests, to test how applications behave on a slow filesystem such
+/// as one mounted over a network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyRule {
+    /// The operations it applies to, by their FUSE names, or every operation
+    /// if empty.
+    pub ops: Vec<String>,
+    /// How long every request is delayed by.
+    pub delay: Duration,
+    /// The most a request is delayed by beyond delay. Each request is delayed
+    /// by a random part of it.
+    pub jitter: Duration,
+}
+
+/// A rule deciding whether files matching a gitignore-style glob are visible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterRule {
+    Include(String),
+    Exclude(String),
+}
+
+/// A transformation applied to the content of files matching a
+/// gitignore-style glob, as used by filter rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformRule {
+    pub pattern: String,
+    pub transform: Transform,
+}
+
+/// How the content of a file is transformed.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Transform {
+    /// The content is piped through a shell command, and its output is
+    /// presented instead.
+    Command(String),
+
+    /// The content is piped through decode, like Command. Content written to
+    /// the file is piped through encode when the file is flushed, and its
+    /// output is stored in the backing file.
+    Codec { decode: String, encode: String },
+
+    /// The content is stored compressed with zstd, in chunks which are each
+    /// compressed on their own, after a header giving the size of the
+    /// content and an index of the chunks. A read only decompresses the
+    /// chunks it needs. Content written to the file is compressed again when
+    /// the file is flushed. A file stored as a single zstd stream can still
+    /// be read, and is stored in chunks once it has been written.
+    Compress,
+
+    /// CRLF line endings are converted to LF.
+    ToLf,
+
+    /// LF line endings are converted to CRLF.
+    ToCrlf,
+
+    /// Each ${NAME} placeholder is replaced with the value of NAME.
+    /// Placeholders for names without a value are left as they are.
+    Substitute(BTreeMap<String, String>),
+
+    /// The bytes matched by each redaction are replaced with *, except for
+    /// newlines, so the content keeps its size and line structure.
+    Redact(Vec<Redaction>),
+}
+
+// The config is printed in the stats directory and by the control socket,
+// which any user may read, so the values a transform substitutes are left
+// out
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            Transform::Codec { decode, encode } => f
+                .debug_struct("Codec")
+                .field("decode", decode)
+                .field("encode", encode)
+                .finish(),
+            Transform::Compress => f.write_str("Compress"),
+            Transform::ToLf => f.write_str("ToLf"),
+            Transform::ToCrlf => f.write_str("ToCrlf"),
+            Transform::Substitute(values) => f
+                .debug_tuple("Substitute")
+                .field(&values.keys().collect::<Vec<_>>())
+                .finish(),
+            Transform::Redact(redactions) => f.debug_tuple("Redact").field(redactions).finish(),
+        }
+    }
+}
+
+/// Bytes of a file's content which are hidden by a redact transform.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Redaction {
+    /// len bytes starting at offset.
+    Bytes { offset: u64, len: u64 },
+    /// Every occurrence of this text.
+    Text(String),
+    /// The rest of the line after every occurrence of this marker, such as
+    /// "password=".
+    Value(String),
+}
+
+// Printing redacted text with the config would defeat the redaction, and a
+// marker can give away where the secret is, so neither is printed
+impl fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redaction::Bytes { offset, len } => f
+                .debug_struct("Bytes")
+                .field("offset", offset)
+                .field("len", len)
+                .finish(),
+            Redaction::Text(_) => f.write_str("Text(..)"),
+            Redaction::Value(_) => f.write_str("Value(..)"),
+        }
+    }
+}
+
+/// A read-only file which appears in the root alongside the real entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualFile {
+    pub name: String,
+    pub content: VirtualContent,
+}
+
+/// Where the content of a virtual file comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VirtualContent {
+    /// The content is given literally.
+    Literal(Vec<u8>),
+    /// The content is the output of a shell command, which is run once when
+    /// the filesystem is mounted.
+    Command(String),
+}
+
+/// A request made of the filesystem, sent to access_events once it has been
+/// answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessEvent {
+    /// The FUSE operation, such as "open".
+    pub op: &'static str,
+    /// The path relative to the root of the file the request was about, or
+    /// its inode number if it has no known path.
+    pub path: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    /// The errno the request failed with, or None if it succeeded.
+    pub errno: Option<i32>,
+}
+
+/// Runtime configuration of a passfs mount.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Allow operations which modify the backing tree. When false, every
+    /// modifying operation fails with EROFS.
+    pub read_write: bool,
+
+    /// Ask the kernel to cache writes and flush them to us in batches rather
+    /// than passing every write straight through. This is much faster for
+    /// small writes, but changes made directly to the backing tree while a
+    /// file is open may be overwritten. Only used with read_write.
+    pub writeback_cache: bool,
+
+    /// Let users other than the one who mounted the filesystem use it. This
+    /// is only safe if check_permissions is set. Unless we are root, it needs
+    /// user_allow_other in /etc/fuse.conf.
+    pub allow_other: bool,
+
+    /// How the permissions of the caller of each request are checked.
+    pub check_permissions: PermissionCheck,
+
+    /// Restrict each caller to the parts of the backing tree the policies
+    /// applying to them allow, so one mount can serve different subsets of
+    /// the tree to different users. If there are any, a caller may only look
+    /// up the files a policy applying to them covers and the directories
+    /// leading to them, and may only open files and list directories as such
+    /// a policy allows. Directories leading to covered files can be looked up
+    /// but not listed. Changing a file or a directory's entries needs Write,
+    /// and reading a file's attributes needs it to be one the caller may look
+    /// up. Requests failing this fail with EACCES, and callers no policy
+    /// applies to can't access anything. The kernel would share what it
+    /// caches between callers, so every timeout is 0 if there are policies.
+    pub access_policies: Vec<AccessPolicy>,
+
+    /// Limit the rate of each caller's requests, so that one can't keep the
+    /// backing store busy to the exclusion of others. Requests exceeding it
+    /// fail with EAGAIN, apart from flushing and releasing files and statfs,
+    /// which are never limited, nor are requests the kernel makes itself,
+    /// such as writing back cached writes. A caller may exceed it briefly
+    /// with a large read or write, and is then held back until they have
+    /// paid for it.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Errors injected into requests, for testing. The first rule which
+    /// applies to a request and fails it decides its errno.
+    pub faults: Vec<FaultRule>,
+
+    /// Delays injected into requests, for testing. The first rule which
+    /// applies to a request decides its delay. The reply is delayed, so
+    /// other requests are handled in the meantime.
+    pub latencies: Vec<LatencyRule>,
+
+    /// Give legal but awkward answers, to flush out applications which
+    /// assume more than they may, and to exercise passfs's own handling of
+    /// offsets. Reads return a random number of bytes, if the cache mode is
+    /// Direct, as otherwise the kernel would take a short read for the end of
+    /// the file. Directory reads stop after a random number of entries.
+    pub chaos: bool,
+
+    /// The seed of the random choice of requests to fail, of how long to
+    /// delay them and of chaos, so that a test can be repeated. The time is used if None.
+    pub fault_seed: Option<u64>,
+
+    /// Once mounted, switch to this uid and gid and drop every supplementary
+    /// group, so that a process started as root to mount keeps no more
+    /// privilege than it needs. The root and every file already open stay
+    /// open, but backing files are accessed with the permissions of this
+    /// user from then on.
+    pub run_as: Option<(u32, u32)>,
+
+    /// Once mounted, restrict the threads serving requests with Landlock to
+    /// reading beneath the root, branches and upper directory, and writing
+    /// there too if read_write is set, so a bug in handling paths can't serve
+    /// files from elsewhere. Nothing is restricted if the kernel doesn't
+    /// support Landlock. Transforms running commands can't be used. Linux
+    /// only.
+    pub landlock: bool,
+
+    /// Once mounted, restrict the process with a seccomp filter to the
+    /// syscalls it needs, so that a bug in handling a request can't be used
+    /// to make any other. Other syscalls fail with EPERM. Nothing can be
+    /// executed afterwards, so transforms running commands can't be used.
+    /// Linux only.
+    pub seccomp: bool,
+
+    /// Further directories merged with the root into one namespace. A name
+    /// is found in the root if it exists there, and otherwise in the first
+    /// branch containing it. Directories which exist in several places are
+    /// listed with the entries of all of them, and an entry of the root or
+    /// an earlier branch hides any entry with the same name in a later one.
+    pub branches: Vec<PathBuf>,
+
+    /// How the branch holding a file is chosen when it is in several
+    /// branches. PathPreserving is the same as FirstFound here.
+    pub search_policy: BranchPolicy,
+
+    /// How the branch in which a new file is created is chosen. Directories
+    /// leading to it are copied into that branch if they are only in others.
+    /// A file renamed goes in the branch of any file it replaces, or else
+    /// the one chosen for a new file, and is copied there from its own. A
+    /// file linked stays in the branch it is in.
+    pub create_policy: BranchPolicy,
+
+    /// A directory which is written instead of the root. The root becomes
+    /// the first branch below it and is never modified: a file in the root
+    /// or any branch is copied into the upper directory before it is
+    /// changed, along with the directories leading to it, and new files are
+    /// always created there, whatever the branch policies. Removing something which is in a lower branch
+    /// leaves a whiteout in the upper directory, an empty file named .wh.
+    /// followed by its name, which hides it. Requires read_write.
+    pub upper: Option<PathBuf>,
+
+    /// Record every visible file and directory with its attributes when the
+    /// filesystem is mounted, and present them as they were then, whatever
+    /// happens to the backing tree afterwards. Only the content of files is
+    /// read from the backing tree. Directories which can't be read when it is
+    /// recorded are presented as empty. Can't be used with read_write.
+    pub snapshot: bool,
+
+    /// With snapshot, hold every file open from when it is recorded, so
+    /// its content is read from the same file even if it is replaced or
+    /// deleted. The file can still be modified in place, in which case its
+    /// content no longer matches the recorded size. This needs a file
+    /// descriptor for every file in the tree.
+    pub pin_snapshot: bool,
+
+    /// Hide anything which is not on the same filesystem as the root, like
+    /// find -xdev. Mount points below the root are omitted from directory
+    /// listings and can't be looked up.
+    pub one_file_system: bool,
+
+    /// Let the kernel NFS server re-export the mount. Generations are never
+    /// zero, and the file handles of the most recent 65536 inodes the kernel
+    /// forgot are kept, so that NFS clients can still open them by inode
+    /// number. Inode numbers are the backing st_ino, so they are stable
+    /// across mounts: this implies one_file_system, and can't be used with
+    /// branches, upper or snapshot. Opening by file handle needs
+    /// CAP_DAC_READ_SEARCH, so it can't be used with run_as either, and the
+    /// export needs an fsid option. Linux only.
+    pub nfs_export: bool,
+
+    /// Look up names ignoring case if they don't exist with the case given,
+    /// while preserving the case of names which are created.
+    pub case_insensitive: bool,
+
+    /// Rules applied in order to the name of every file, to give the name it
+    /// is presented with. Looking up a presented name finds the file it was
+    /// rewritten from. Names which aren't presented by any file, such as those
+    /// of new files, are used as given.
+    pub rename_rules: Vec<RenameRule>,
+
+    /// Rules deciding which files are visible. The last rule matching a path
+    /// decides whether it is visible. A directory which matches no rule is
+    /// visible, and any other file which matches no rule is visible unless
+    /// there are include rules. Hidden files can't be looked up and are
+    /// omitted from directory listings.
+    pub filter_rules: Vec<FilterRule>,
+
+    /// Hide files and directories whose names start with a dot, whatever
+    /// filter_rules say.
+    pub hide_hidden: bool,
+
+    /// Hide block and character devices, whatever filter_rules say.
+    pub hide_devices: bool,
+
+    /// Hide sockets, whatever filter_rules say.
+    pub hide_sockets: bool,
+
+    /// Hide FIFOs, whatever filter_rules say.
+    pub hide_fifos: bool,
+
+    /// Hide directories which have no visible entries, after every other
+    /// rule deciding which files are visible has been applied. A directory
+    /// containing only such directories is hidden too, so finding out whether
+    /// a directory is empty may read everything below it.
+    pub hide_empty_dirs: bool,
+
+    /// Gitignore-style globs, as used by filter rules, matching directories
+    /// which are listed as empty, like a directory with mode 0711. Their
+    /// entries can still be looked up by name, which suits a secret store
+    /// where knowing a name is the credential.
+    pub unlisted_dirs: Vec<String>,
+
+    /// Paths relative to the root which are exported. If any are given, the
+    /// root appears to contain only these, along with the directories leading
+    /// to them, and everything else is hidden.
+    pub exports: Vec<PathBuf>,
+
+    /// Directories more than this many levels below the root appear empty.
+    /// There is no limit if this is None.
+    pub max_depth: Option<usize>,
+
+    /// Read-only files presented in the root. A virtual file hides any real
+    /// entry of the root with the same name, and can't be modified, removed
+    /// or replaced.
+    pub virtual_files: Vec<VirtualFile>,
+
+    /// Present a read-only directory .passfs in the root, containing the
+    /// files stats, with metrics in the Prometheus text format, config, with
+    /// this configuration, and version. Their content is generated whenever
+    /// they are opened.
+    pub stats_dir: bool,
+
+    /// Rules transforming the content of regular files. The first rule whose
+    /// pattern matches the path of a file applies to it. The transformed
+    /// content is produced when the file is first opened, and kept in memory
+    /// until the backing file changes. A transformed file is presented with
+    /// the size of its backing file until then, and the size of its
+    /// transformed content once it is known, and is always read directly
+    /// rather than through the page cache. Transformed files can only be
+    /// written if their transform is a codec or Compress.
+    pub transforms: Vec<TransformRule>,
+
+    /// Regular files larger than this many bytes are handled according to
+    /// oversize, both when they are looked up and when they are opened. Reads
+    /// never return anything beyond this offset, even from a file which grew
+    /// after it was opened. There is no limit if this is zero.
+    pub max_file_size: u64,
+
+    /// What to do with files larger than max_file_size.
+    pub oversize: Oversize,
+
+    /// Mappings applied to the owner of every file, and reversed when the
+    /// owner is changed. The first mapping containing an id is used, and ids
+    /// which aren't mapped are presented unchanged.
+    pub uid_map: Vec<IdMapping>,
+
+    /// Mappings applied to the group of every file, like uid_map.
+    pub gid_map: Vec<IdMapping>,
+
+    /// Present every file as owned by this uid and gid, ignoring uid_map and
+    /// gid_map. Changing the owner of a file still changes the backing file,
+    /// but it will continue to be presented as owned by these.
+    pub squash_owner: Option<(u32, u32)>,
+
+    /// Override the permission bits presented for files other than
+    /// directories and symlinks. This only changes how files are presented:
+    /// access to the backing files is unchanged.
+    pub file_mode: Option<ModeOverride>,
+
+    /// Override the permission bits presented for directories, like
+    /// file_mode.
+    pub dir_mode: Option<ModeOverride>,
+
+    /// Present every file other than a directory without execute bits, after
+    /// file_mode, so that a tree exported for inspection can't be used to
+    /// run anything. The kernel refuses to execute a file with none.
+    pub no_exec: bool,
+
+    /// Mount with noexec, so that nothing in the mount can be executed
+    /// whatever its mode.
+    pub mount_noexec: bool,
+
+    /// Normalise the access, modification, change and creation times of every
+    /// file, like SOURCE_DATE_EPOCH, so that builds reading the tree are
+    /// reproducible.
+    pub timestamps: Timestamps,
+
+    /// Pad reads which extend past the end of a file with zeros, so every
+    /// read returns the full number of bytes requested. By default a read is
+    /// truncated at the end of the file.
+    pub zero_fill_reads: bool,
+
+    /// How long the kernel may cache file attributes returned by getattr and
+    /// setattr. Zero, the default, means every stat is passed through to the
+    /// backing tree, which is necessary if it may change behind our back.
+    pub attr_timeout: Duration,
+
+    /// How long the kernel may cache the result of a lookup, and the
+    /// attributes returned with it. fuser doesn't let us set these
+    /// separately.
+    pub entry_timeout: Duration,
+
+    /// How long the kernel may cache that a name doesn't exist. Names created
+    /// through passfs are visible immediately, but names created directly in
+    /// the backing tree may not be visible until this expires. Zero, the
+    /// default, disables negative caching.
+    pub negative_timeout: Duration,
+
+    /// How the kernel may cache the contents of files.
+    pub cache_mode: CacheMode,
+
+    /// The number of worker threads which handle I/O and other operations
+    /// on existing files, so that many requests can be in flight against the
+    /// backing tree at once and a slow one doesn't hold up the rest. Requests
+    /// which create, remove or look up names are always handled in turn. With
+    /// 0 every request is handled on the thread which receives it.
+    pub threads: usize,
+
+    /// The maximum number of released backing files to keep open for reuse
+    /// by later opens of the same file. 0 disables the cache.
+    pub fd_cache_size: usize,
+
+    /// The most inodes to keep open at once. Inodes the kernel has forgotten
+    /// are kept, so that they are reused if they are looked up again, until
+    /// there are more than this, when those forgotten longest ago are
+    /// discarded. Inodes the kernel still refers to are never discarded, so
+    /// it may hold more than this. If this is zero, inodes are discarded as
+    /// soon as the kernel forgets them.
+    pub max_inodes: usize,
+
+    /// How long a complete directory listing may be reused by later opens of
+    /// the same directory. Listings are discarded when we modify the
+    /// directory, but changes made directly to the backing tree are not seen
+    /// until they expire. Listings are not cached if this is zero.
+    pub dir_cache_timeout: Duration,
+
+    /// When a file handle is being read sequentially, ask the backing
+    /// filesystem to prefetch this many bytes beyond the current read. 0
+    /// disables prefetching.
+    pub read_ahead: u64,
+
+    /// Map files of at least this many bytes into memory when they are
+    /// opened read-only, and serve reads from the mapping. Reading a mapping
+    /// past the end of a file which has been truncated is fatal, so this must
+    /// only be used if files in the backing tree are never truncated while
+    /// open. Callers could truncate them through the mount with read_write,
+    /// so files are never mapped then. 0 disables mapping.
+    pub mmap_threshold: u64,
+
+    /// The largest write the kernel may send us in a single request. Larger
+    /// writes mean fewer requests for streaming workloads. The kernel's
+    /// default is used if not set.
+    pub max_write: Option<u32>,
+
+    /// The most the kernel may read ahead of a reader. The kernel's default is
+    /// used if not set.
+    pub max_readahead: Option<u32>,
+
+    /// The most background requests, such as readahead and writeback, the
+    /// kernel may have outstanding at once. Raising this helps throughput,
+    /// lowering it keeps latency down for foreground requests.
+    pub max_background: Option<u16>,
+
+    /// The number of outstanding background requests at which the kernel
+    /// considers us congested and holds back further background work.
+    pub congestion_threshold: Option<u16>,
+
+    /// An address such as localhost:9100 on which to serve counters and
+    /// latency histograms of every FUSE operation over HTTP, at /metrics in
+    /// the Prometheus text format. Needs the metrics feature.
+    pub metrics_address: Option<String>,
+
+    /// Log every request which takes at least this long to reply to, with
+    /// the path it was about and its result. Nothing is logged if this is
+    /// zero.
+    pub slow_op_threshold: Duration,
+
+    /// A file or named pipe to which a line of JSON is appended for every
+    /// lookup, open, create, opendir and readdir, giving the time, the uid,
+    /// gid and pid of the caller, the path and whether it succeeded.
+    pub audit_log: Option<PathBuf>,
+
+    /// A file to which a JSON object is written on a line of its own for every
+    /// request refused because the mount is read-only, the file is hidden by
+    /// filter rules or no access policy allows it, so that operators can
+    /// alert on attempted writes or out-of-policy access. Unlike audit_log,
+    /// this covers every kind of request.
+    pub denial_log: Option<PathBuf>,
+
+    /// A shell command run for every denial, given the JSON object
+    /// describing it on its standard input. Commands are run one at a time
+    /// by a thread of their own, and denials are dropped if too many are
+    /// waiting for them.
+    pub denial_hook: Option<String>,
+
+    /// A file to which every request is written, with its arguments and
+    /// timing, so that it can be replayed on a directory by `passfs replay`
+    /// to compare performance or reproduce a bug. The data written to files
+    /// isn't recorded.
+    pub record: Option<PathBuf>,
+
+    /// A unix socket to create, on which commands may be sent to ask for
+    /// statistics, the open handles, the inodes referenced by the kernel or
+    /// this configuration. The socket is removed when the filesystem is
+    /// unmounted.
+    pub control_socket: Option<PathBuf>,
+
+    /// A channel on which an event is sent for every request which would be
+    /// audited, as it is answered. This lets an application embedding passfs
+    /// react to a file being opened. Events are dropped once the receiver
+    /// has hung up.
+    pub access_events: Option<Sender<AccessEvent>>,
+
+    /// Log every request with its path and result, and the pid, command, uid
+    /// and gid of the process which made it, like strace.
+    pub log_callers: bool,
+}
@@ -0,0 +1,142 @@
+use std::ffi::CString;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+// The commands served on the control socket
+const COMMANDS: &str = "stats, handles, inodes, config";
+
+// How long we wait for the filesystem to describe its state
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a client may take to send its command or read the reply, so one
+// which never does can't stop others being served
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the filesystem knows of the files the kernel is using
+pub struct Dump {
+    pub handles: String,
+    pub inodes: String,
+}
+
+// The state of the filesystem is only available to the thread handling FUSE
+// requests. The control thread asks for it by setting wanted and making a
+// request of the mount, and the filesystem publishes a dump in reply while
+// handling the next request.
+pub struct Introspection {
+    wanted: AtomicBool,
+    dump: Mutex<Option<Dump>>,
+    published: Condvar,
+}
+
+impl Introspection {
+    pub fn new() -> Introspection {
+        Introspection {
+            wanted: AtomicBool::new(false),
+            dump: Mutex::new(None),
+            published: Condvar::new(),
+        }
+    }
+
+    // Whether a dump has been asked for since the last was published
+    pub fn wanted(&self) -> bool {
+        self.wanted.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn publish(&self, dump: Dump) {
+        *self.dump.lock().unwrap() = Some(dump);
+        self.published.notify_all();
+    }
+
+    fn request(&self, mountpoint: &Path) -> Option<Dump> {
+        *self.dump.lock().unwrap() = None;
+        self.wanted.store(true, Ordering::Relaxed);
+
+        // statfs always reaches us rather than being answered by the kernel.
+        // Its result doesn't matter.
+        if let Ok(path) = CString::new(mountpoint.as_os_str().as_bytes()) {
+            let mut statvfs: libc::statvfs = unsafe { std::mem::zeroed() };
+            unsafe { libc::statvfs(path.as_ptr(), &mut statvfs) };
+        }
+
+        let dump = self.dump.lock().unwrap();
+        let (mut dump, _) = self
+            .published
+            .wait_timeout_while(dump, DUMP_TIMEOUT, |dump| dump.is_none())
+            .unwrap();
+        dump.take()
+    }
+}
+
+// Serve commands on a unix socket at path, from a new thread. Every
+// connection sends a command on a line and is sent the reply in text until
+// the connection is closed. A socket left at path by a process which has
+// exited is replaced.
+pub fn serve(
+    path: &Path,
+    mountpoint: PathBuf,
+    config: Config,
+    metrics: Arc<Metrics>,
+    introspection: Arc<Introspection>,
+) -> io::Result<()> {
+    let listener = bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| {
+                stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                let mut command = String::new();
+                BufReader::new(&stream).read_line(&mut command)?;
+                let reply = match command.trim() {
+                    "stats" => metrics.render(),
+                    "config" => format!("{:#?}\n", config),
+                    "handles" | "inodes" => match introspection.request(&mountpoint) {
+                        Some(dump) if command.trim() == "handles" => dump.handles,
+                        Some(dump) => dump.inodes,
+                        None => "error: the filesystem didn't respond\n".into(),
+                    },
+                    command => format!("error: unknown command {:?}: use {}\n", command, COMMANDS),
+                };
+                (&stream).write_all(reply.as_bytes())
+            });
+            if let Err(err) = result {
+                warn!("control: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Bind a unix socket at path. If there is already one there which nothing is
+// listening on, it was left by a process which exited without removing it.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(err);
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+// Send command to the control socket at path, returning the reply
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
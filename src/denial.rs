@@ -0,0 +1,132 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+use tracing::warn;
+
+use crate::audit::{json_string, timestamp, Caller};
+
+// How many denials may wait for the hook before further ones are dropped
+const HOOK_QUEUE: usize = 64;
+
+// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    // It would have modified a read-only mount
+    ReadOnly,
+    // It was about a file hidden by filter rules
+    Filtered,
+    // No access policy allowed it
+    Policy,
+}
+
+impl Denial {
+    pub fn name(self) -> &'static str {
+        match self {
+            Denial::ReadOnly => "read-only",
+            Denial::Filtered => "filtered",
+            Denial::Policy => "policy",
+        }
+    }
+
+    // The errno the request fails with
+    pub fn errno(self) -> i32 {
+        match self {
+            Denial::ReadOnly => libc::EROFS,
+            Denial::Filtered => libc::ENOENT,
+            Denial::Policy => libc::EACCES,
+        }
+    }
+}
+
+// Reports every denial as a JSON object on a line of its own, to a file and
+// to a hook command, either of which may be absent
+pub struct DenialLog {
+    file: Option<Mutex<File>>,
+    hook: Option<SyncSender<String>>,
+}
+
+impl DenialLog {
+    pub fn new(path: Option<&Path>, hook: Option<&str>) -> io::Result<DenialLog> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().append(true).create(true).open(path)?,
+            )),
+            None => None,
+        };
+        let hook = hook.map(|command| {
+            let (sender, receiver) = mpsc::sync_channel::<String>(HOOK_QUEUE);
+            let command = command.to_string();
+            thread::spawn(move || {
+                for line in receiver {
+                    if let Err(err) = run_hook(&command, &line) {
+                        warn!("denial hook: {}", err);
+                    }
+                }
+            });
+            sender
+        });
+        Ok(DenialLog { file, hook })
+    }
+
+    // Report that caller's op request about path was refused
+    pub fn record(&self, denial: Denial, op: &str, caller: &Caller, path: &str) {
+        let timestamp = match timestamp() {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            "{{\"time\":\"{}\",\"denial\":\"{}\",\"op\":\"{}\",\"uid\":{},\"gid\":{},\"pid\":{},\"path\":",
+            timestamp,
+            denial.name(),
+            op,
+            caller.uid,
+            caller.gid,
+            caller.pid
+        );
+        json_string(&mut line, path);
+        let _ = writeln!(line, ",\"errno\":{}}}", denial.errno());
+
+        if let Some(file) = &self.file {
+            if let Err(err) = file.lock().unwrap().write_all(line.as_bytes()) {
+                warn!("denial log: {}", err);
+            }
+        }
+        if let Some(hook) = &self.hook {
+            if let Err(TrySendError::Full(_)) = hook.try_send(line) {
+                warn!("denial hook is behind: dropping {} denial", denial.name());
+            }
+        }
+    }
+}
+
+// Run command with line as its stdin
+fn run_hook(command: &str, line: &str) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    // The hook is waited for even if it doesn't read its input
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(line.as_bytes()),
+        None => Ok(()),
+    };
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command, status
+        )));
+    }
+    Ok(())
+}
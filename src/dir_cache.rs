@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use fuser::FileType;
+use tracing::debug;
+
+// An entry of a cached directory listing, as it is returned to the kernel
+pub struct ListedEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+pub type Listing = Arc<Vec<ListedEntry>>;
+
+// Complete directory listings, keyed by the inode of the directory, which are
+// reused by opendir until they expire. We invalidate a listing whenever we
+// modify its directory, but changes made directly to the backing tree are not
+// seen until the listing expires.
+pub struct DirCache {
+    ttl: Duration,
+    listings: BTreeMap<u64, (Instant, Listing)>,
+}
+
+impl DirCache {
+    // A cache with a ttl of 0 never keeps anything
+    pub fn new(ttl: Duration) -> DirCache {
+        DirCache {
+            ttl,
+            listings: BTreeMap::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.ttl > Duration::ZERO
+    }
+
+    // Return the listing of ino, if we have one which hasn't expired
+    pub fn get(&mut self, ino: u64) -> Option<Listing> {
+        let (created, listing) = self.listings.get(&ino)?;
+        if created.elapsed() < self.ttl {
+            debug!("dir cache: reusing listing of inode={}", ino);
+            return Some(Arc::clone(listing));
+        }
+
+        self.listings.remove(&ino);
+        None
+    }
+
+    pub fn insert(&mut self, ino: u64, listing: Listing) {
+        if self.enabled() {
+            self.listings.insert(ino, (Instant::now(), listing));
+        }
+    }
+
+    // Discard the listing of ino, because it has changed or been forgotten
+    pub fn invalidate(&mut self, ino: u64) {
+        self.listings.remove(&ino);
+    }
+}
@@ -0,0 +1,141 @@
+use std::ffi::{CStr, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::IntoRawFd;
+use std::ptr::NonNull;
+
+#[cfg(target_os = "linux")]
+use libc::{__errno_location as errno_location, readdir64 as readdir};
+#[cfg(target_os = "freebsd")]
+use libc::{__error as errno_location, readdir};
+
+// An entry read from a directory, with the inode number and type which the
+// backing filesystem returned in the dirent
+pub struct DirEntry {
+    pub ino: u64,
+    // One of the DT_* constants, which may be DT_UNKNOWN
+    pub file_type: u8,
+    pub name: OsString,
+    // The offset of the next entry in the directory
+    pub offset: i64,
+}
+
+// A directory stream over an open directory. Unlike openat::DirIter this
+// exposes d_ino, d_type and d_off, which lets readdir avoid a stat per entry
+// and resume from an arbitrary offset.
+pub struct DirStream {
+    dir: NonNull<libc::DIR>,
+    // The offset of the next entry we will return
+    offset: i64,
+}
+
+// A stream is only used through &mut self, so it may move to another thread
+unsafe impl Send for DirStream {}
+
+impl DirStream {
+    pub fn new(file: File) -> io::Result<DirStream> {
+        let fd = file.into_raw_fd();
+        match NonNull::new(unsafe { libc::fdopendir(fd) }) {
+            Some(dir) => Ok(DirStream { dir, offset: 0 }),
+            None => {
+                let err = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    // The fd of the directory, for use with *at() syscalls
+    pub fn fd(&self) -> libc::c_int {
+        unsafe { libc::dirfd(self.dir.as_ptr()) }
+    }
+
+    // Continue reading from offset, which is either 0 or an offset returned
+    // in a previous DirEntry
+    pub fn seek(&mut self, offset: i64) {
+        if offset == self.offset {
+            return;
+        }
+
+        if offset == 0 {
+            unsafe { libc::rewinddir(self.dir.as_ptr()) };
+        } else {
+            unsafe { libc::seekdir(self.dir.as_ptr(), location(offset)) };
+        }
+        self.offset = offset;
+    }
+
+    // The offset of the entry after dirent, the last one read
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    fn next_offset(&self, dirent: &libc::dirent64) -> i64 {
+        dirent.d_off
+    }
+
+    // d_off can't be passed to seekdir() on FreeBSD, which only accepts what
+    // telldir() returned, nor on 32-bit Linux, where it may not fit in the
+    // long seekdir() takes. telldir() may return 0, so it is offset by 1 to
+    // keep 0 for the start of the directory.
+    #[cfg(any(target_os = "freebsd", target_pointer_width = "32"))]
+    fn next_offset<T>(&self, _dirent: &T) -> i64 {
+        let location = unsafe { libc::telldir(self.dir.as_ptr()) };
+        location as i64 + 1
+    }
+}
+
+// The location to pass to seekdir() to continue from offset
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn location(offset: i64) -> libc::c_long {
+    offset as libc::c_long
+}
+
+#[cfg(any(target_os = "freebsd", target_pointer_width = "32"))]
+fn location(offset: i64) -> libc::c_long {
+    (offset - 1) as libc::c_long
+}
+
+impl Iterator for DirStream {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // readdir() returns NULL both at the end of the stream and on
+            // error, and only sets errno on error
+            unsafe { *errno_location() = 0 };
+            let dirent = unsafe { readdir(self.dir.as_ptr()) };
+            if dirent.is_null() {
+                let err = io::Error::last_os_error();
+                return match err.raw_os_error() {
+                    Some(0) => None,
+                    _ => Some(Err(err)),
+                };
+            }
+
+            let dirent = unsafe { &*dirent };
+            self.offset = self.next_offset(dirent);
+
+            let name = unsafe { CStr::from_ptr(dirent.d_name.as_ptr()) }.to_bytes();
+            if name == b"." || name == b".." {
+                continue;
+            }
+
+            #[cfg(target_os = "linux")]
+            let ino = dirent.d_ino;
+            #[cfg(target_os = "freebsd")]
+            let ino = dirent.d_fileno;
+
+            return Some(Ok(DirEntry {
+                ino,
+                file_type: dirent.d_type,
+                name: OsStr::from_bytes(name).to_os_string(),
+                offset: self.offset,
+            }));
+        }
+    }
+}
+
+impl Drop for DirStream {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.dir.as_ptr()) };
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use tracing::debug;
+
+// Backing files which the kernel has released, kept open so that a later open
+// of the same inode with the same flags can reuse them. We never use the file
+// offset of a backing file, so a reused fd behaves exactly like a new one.
+//
+// Entries are keyed by inode number and open flags. When the cache is full the
+// least recently released file is closed.
+pub struct FdCache {
+    capacity: usize,
+    files: BTreeMap<(u64, i32), (u64, Arc<File>)>,
+    // Keys of files, ordered by when they were released
+    lru: BTreeMap<u64, (u64, i32)>,
+    next_tick: u64,
+}
+
+impl FdCache {
+    // A cache with a capacity of 0 never keeps anything
+    pub fn new(capacity: usize) -> FdCache {
+        FdCache {
+            capacity,
+            files: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    // Remove and return a cached file for ino opened with flags
+    pub fn take(&mut self, ino: u64, flags: i32) -> Option<Arc<File>> {
+        let (tick, file) = self.files.remove(&(ino, flags))?;
+        self.lru.remove(&tick);
+        debug!("fd cache: reusing inode={} flags={:o}", ino, flags);
+        Some(file)
+    }
+
+    // Add a released file to the cache
+    pub fn insert(&mut self, ino: u64, flags: i32, file: Arc<File>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        // If we already have one we keep the newer, which is just as good
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some((old_tick, _)) = self.files.insert((ino, flags), (tick, file)) {
+            self.lru.remove(&old_tick);
+        }
+        self.lru.insert(tick, (ino, flags));
+
+        while self.files.len() > self.capacity {
+            let (_, key) = match self.lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.files.remove(&key);
+        }
+    }
+
+    // Close every cached file for ino
+    pub fn forget(&mut self, ino: u64) {
+        let keys: Vec<_> = self
+            .files
+            .range((ino, i32::MIN)..=(ino, i32::MAX))
+            .map(|(key, (tick, _))| (*key, *tick))
+            .collect();
+        for (key, tick) in keys {
+            self.files.remove(&key);
+            self.lru.remove(&tick);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use fuser::FileType;
+
+use crate::config::{Config, FilterRule};
+
+// A gitignore-style glob. * and ? match any characters other than /, ** also
+// matches /, and [...] matches a character class. A pattern containing a /
+// other than at the end is matched against the whole path relative to the
+// root, otherwise it is matched against the final component, at any depth. A
+// pattern ending in / only matches directories.
+pub struct Pattern {
+    glob: Vec<u8>,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let mut glob = pattern.as_bytes();
+        let dir_only = glob.ends_with(b"/");
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        let anchored = glob.contains(&b'/');
+        if glob.starts_with(b"/") {
+            glob = &glob[1..];
+        }
+
+        Pattern {
+            glob: glob.to_vec(),
+            anchored,
+            dir_only,
+        }
+    }
+
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let text = if self.anchored {
+            path.as_os_str()
+        } else {
+            match path.file_name() {
+                Some(name) => name,
+                None => return false,
+            }
+        };
+        glob_match(&self.glob, text.as_bytes())
+    }
+
+    // Whether the pattern may match something below the directory at path,
+    // relative to the root. An unanchored pattern may match at any depth.
+    pub fn may_match_below(&self, path: &Path) -> bool {
+        if !self.anchored {
+            return true;
+        }
+
+        let mut globs = self.glob.split(|c| *c == b'/');
+        for component in path.iter() {
+            match globs.next() {
+                Some(glob) if glob.windows(2).any(|glob| glob == b"**") => return true,
+                Some(glob) if glob_match(glob, component.as_bytes()) => (),
+                _ => return false,
+            }
+        }
+        globs.next().is_some()
+    }
+}
+
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            // ** matches across directories, and **/ also matches nothing
+            if let Some(rest) = rest.strip_prefix(b"*") {
+                if let Some(after) = rest.strip_prefix(b"/") {
+                    if glob_match(after, text) {
+                        return true;
+                    }
+                }
+                return (0..=text.len()).any(|i| glob_match(rest, &text[i..]));
+            }
+
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some((b'?', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        Some((b'[', rest)) => match (text.split_first(), class_match(rest, text.first())) {
+            (Some((_, text)), Some((true, rest))) => glob_match(rest, text),
+            (_, Some((false, _))) | (None, _) => false,
+            // An unterminated class matches a literal [
+            (Some((c, text)), None) => *c == b'[' && glob_match(rest, text),
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => match text.split_first() {
+            Some((c, text)) if *c == rest[0] => glob_match(&rest[1..], text),
+            _ => false,
+        },
+        Some((p, rest)) => match text.split_first() {
+            Some((c, text)) if c == p => glob_match(rest, text),
+            _ => false,
+        },
+    }
+}
+
+// Match c against the class at the start of glob, which follows a [. Returns
+// whether it matched and the rest of the glob after the class, or None if the
+// class is not terminated.
+fn class_match<'a>(glob: &'a [u8], c: Option<&u8>) -> Option<(bool, &'a [u8])> {
+    let (negated, mut glob) = match glob.split_first() {
+        Some((b'!', rest)) | Some((b'^', rest)) => (true, rest),
+        _ => (false, glob),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (start, rest) = glob.split_first()?;
+        // A ] at the start of the class is a literal
+        if *start == b']' && !first {
+            let matched = c.is_some_and(|c| *c != b'/' && matched != negated);
+            return Some((matched, rest));
+        }
+        first = false;
+
+        let (end, rest) = match rest {
+            [b'-', end, rest @ ..] if *end != b']' => (end, rest),
+            _ => (start, rest),
+        };
+        if let Some(c) = c {
+            matched |= start <= c && c <= end;
+        }
+        glob = rest;
+    }
+}
+
+// Decides which files are visible through the mount
+pub struct Filter {
+    rules: Vec<(bool, Pattern)>,
+    has_includes: bool,
+    hide_hidden: bool,
+    exports: Vec<PathBuf>,
+    hide_devices: bool,
+    hide_sockets: bool,
+    hide_fifos: bool,
+}
+
+impl Filter {
+    pub fn new(config: &Config) -> Filter {
+        let rules: Vec<_> = config
+            .filter_rules
+            .iter()
+            .map(|rule| match rule {
+                FilterRule::Include(pattern) => (true, Pattern::new(pattern)),
+                FilterRule::Exclude(pattern) => (false, Pattern::new(pattern)),
+            })
+            .collect();
+        let has_includes = rules.iter().any(|(include, _)| *include);
+
+        Filter {
+            rules,
+            has_includes,
+            hide_hidden: config.hide_hidden,
+            exports: config.exports.clone(),
+            hide_devices: config.hide_devices,
+            hide_sockets: config.hide_sockets,
+            hide_fifos: config.hide_fifos,
+        }
+    }
+
+    // Whether every file is visible
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+            && !self.hide_hidden
+            && self.exports.is_empty()
+            && !(self.hide_devices || self.hide_sockets || self.hide_fifos)
+    }
+
+    // Whether the file of type kind at path, relative to the root, is
+    // visible.
+    //
+    // If there are exports, only they, everything below them, and the
+    // directories leading to them are visible, so the root appears to contain
+    // only the exports. Hidden types of file and dotfiles are hidden
+    // regardless of the rules. Otherwise the last rule which matches decides.
+    // If no rule matches, a directory is visible, and other files are visible
+    // unless there are include rules.
+    pub fn visible(&self, path: &Path, kind: FileType) -> bool {
+        let is_dir = kind == FileType::Directory;
+        if !self.exports.is_empty() {
+            let exported = self
+                .exports
+                .iter()
+                .any(|export| path.starts_with(export) || (is_dir && export.starts_with(path)));
+            if !exported {
+                return false;
+            }
+        }
+
+        let hidden_type = match kind {
+            FileType::BlockDevice | FileType::CharDevice => self.hide_devices,
+            FileType::Socket => self.hide_sockets,
+            FileType::NamedPipe => self.hide_fifos,
+            _ => false,
+        };
+        if hidden_type {
+            return false;
+        }
+
+        if self.hide_hidden {
+            let name = path.file_name().map_or(&[][..], |name| name.as_bytes());
+            if name.starts_with(b".") {
+                return false;
+            }
+        }
+
+        for (include, pattern) in self.rules.iter().rev() {
+            if pattern.matches(path, is_dir) {
+                return *include;
+            }
+        }
+        is_dir || !self.has_includes
+    }
+}
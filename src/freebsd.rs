@@ -0,0 +1,356 @@
+// FreeBSD versions of the functions lib.rs uses on Linux. FreeBSD 13 has
+// O_PATH handles and accepts AT_EMPTY_PATH in the *at() syscalls, but has no
+// /proc/self/fd, statx, openat2 or renameat2, and extended attributes are in
+// namespaces rather than having the namespace as a prefix of their name.
+// fusefs(5) speaks the Linux protocol, so the kernel's requests carry Linux
+// flags and xattr names. The errnos in replies are passed to callers as they
+// are, though, so they are FreeBSD's own, as errno() returns them.
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::FileAttr;
+use openat::Dir;
+use tracing::trace_span;
+
+use crate::{check_ret, cstr, fstat, stat_to_fileattr, BackingId, EMPTY_PATH};
+
+// FreeBSD has neither EBADFD nor ENODATA, so we reply with the errnos its own
+// filesystems use instead
+pub const EBADFD: i32 = libc::EBADF;
+pub const ENODATA: i32 = libc::ENOATTR;
+
+// Flags of setxattr and rename requests, from linux/xattr.h and linux/fs.h
+pub const XATTR_CREATE: i32 = 1;
+pub const XATTR_REPLACE: i32 = 2;
+pub const RENAME_NOREPLACE: u32 = 1;
+pub const RENAME_EXCHANGE: u32 = 2;
+
+fn empty_path() -> *const libc::c_char {
+    EMPTY_PATH.as_ptr() as *const libc::c_char
+}
+
+fn set_errno(errno: i32) {
+    unsafe { *libc::__error() = errno };
+}
+
+// Open the file referred to by an O_PATH handle with flags
+pub fn reopen(handle: &File, flags: i32) -> io::Result<File> {
+    let flags = flags | libc::O_EMPTY_PATH | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(handle.as_raw_fd(), empty_path(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// openat() which fails rather than resolve path to anywhere outside dirfd
+pub fn openat2_beneath(
+    dirfd: libc::c_int,
+    path: &CString,
+    flags: i32,
+    mode: u32,
+) -> io::Result<File> {
+    let _syscall = trace_span!("openat").entered();
+    let flags = flags | libc::O_RESOLVE_BENEATH;
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags, mode as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// PassFs::new refuses nfs_export on FreeBSD, so there are never any file
+// handles to open
+pub fn file_handle(_handle: &File) -> io::Result<Vec<u32>> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+pub fn open_by_handle(_root: &Dir, _file_handle: &[u32]) -> io::Result<File> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+pub fn handle_path(_root: &Dir, _handle: &File) -> io::Result<PathBuf> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+// The identity of file in the backing tree
+pub fn backing_id(file: &File) -> io::Result<BackingId> {
+    Ok(BackingId::from_stat(&fstat(file)?))
+}
+
+// The attributes of file to return to the kernel, including its birth time
+// if the backing filesystem records it. FUSE has no field for st_flags, so
+// file flags set with chflags aren't presented.
+pub fn file_attr(ino: u64, file: &File) -> io::Result<FileAttr> {
+    let stat = fstat(file)?;
+    let mut fileattr = stat_to_fileattr(ino, &stat);
+    // st_birthtime is -1 if the filesystem doesn't record it
+    if stat.st_birthtime >= 0 {
+        fileattr.crtime = UNIX_EPOCH
+            .checked_add(Duration::new(
+                stat.st_birthtime as u64,
+                stat.st_birthtime_nsec as u32,
+            ))
+            .unwrap_or(UNIX_EPOCH);
+    }
+    Ok(fileattr)
+}
+
+pub fn chmod_handle(handle: &File, mode: libc::mode_t) -> io::Result<()> {
+    check_ret(unsafe {
+        libc::fchmodat(handle.as_raw_fd(), empty_path(), mode, libc::AT_EMPTY_PATH)
+    })
+}
+
+pub fn utimens_handle(handle: &File, times: &[libc::timespec; 2]) -> io::Result<()> {
+    check_ret(unsafe {
+        libc::utimensat(
+            handle.as_raw_fd(),
+            empty_path(),
+            times.as_ptr(),
+            libc::AT_EMPTY_PATH,
+        )
+    })
+}
+
+// Create a hard link at path in dir to the file referred to by handle
+pub fn link_handle(handle: &File, dir: &Dir, path: &Path) -> io::Result<()> {
+    let newpath = cstr(path)?;
+    check_ret(unsafe {
+        libc::linkat(
+            handle.as_raw_fd(),
+            empty_path(),
+            dir.as_raw_fd(),
+            newpath.as_ptr(),
+            libc::AT_EMPTY_PATH,
+        )
+    })
+}
+
+// renameat2() with no flags, which is all FreeBSD supports. Like the Linux
+// syscall it returns -1 and sets errno on failure.
+pub unsafe fn renameat2(
+    olddirfd: libc::c_int,
+    oldpath: *const libc::c_char,
+    newdirfd: libc::c_int,
+    newpath: *const libc::c_char,
+    flags: libc::c_uint,
+) -> libc::c_int {
+    if flags != 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    libc::renameat(olddirfd, oldpath, newdirfd, newpath)
+}
+
+// fallocate() with the default mode, which is all FreeBSD supports, as
+// posix_fallocate(). Unlike posix_fallocate() it returns -1 and sets errno on
+// failure.
+pub unsafe fn fallocate(
+    fd: libc::c_int,
+    mode: libc::c_int,
+    offset: libc::off_t,
+    len: libc::off_t,
+) -> libc::c_int {
+    let errno = match mode {
+        0 => libc::posix_fallocate(fd, offset, len),
+        _ => libc::EOPNOTSUPP,
+    };
+    if errno != 0 {
+        set_errno(errno);
+        return -1;
+    }
+    0
+}
+
+// The namespaces of extended attributes, by the prefix of their Linux names
+const XATTR_NAMESPACES: [(libc::c_int, &[u8]); 2] = [
+    (libc::EXTATTR_NAMESPACE_USER, b"user."),
+    (libc::EXTATTR_NAMESPACE_SYSTEM, b"system."),
+];
+
+// The namespace of the extended attribute with the Linux name name, and its
+// name within the namespace
+fn extattr_name(name: &CStr) -> io::Result<(libc::c_int, CString)> {
+    for (namespace, prefix) in XATTR_NAMESPACES {
+        if let Some(name) = name.to_bytes().strip_prefix(prefix) {
+            return Ok((namespace, CString::new(name)?));
+        }
+    }
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+// The extattr_*_fd() syscalls don't accept an O_PATH fd, so the xattr
+// functions reopen the handle for reading. Writing an attribute only
+// requires write permission on the file, not a writable fd.
+
+// Set the extended attribute name of the file referred to by handle.
+// extattr_set_fd() has no flags, so whether the attribute exists is checked
+// beforehand, which can race with another change.
+pub fn set_xattr(handle: &File, name: &CString, value: &[u8], flags: i32) -> io::Result<()> {
+    let (namespace, name) = extattr_name(name)?;
+    let file = reopen(handle, libc::O_RDONLY)?;
+
+    if flags != 0 {
+        let ret = unsafe {
+            libc::extattr_get_fd(
+                file.as_raw_fd(),
+                namespace,
+                name.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        let exists = match ret {
+            ret if ret >= 0 => true,
+            _ => match io::Error::last_os_error() {
+                err if err.raw_os_error() == Some(libc::ENOATTR) => false,
+                err => return Err(err),
+            },
+        };
+        if exists && flags & XATTR_CREATE != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if !exists && flags & XATTR_REPLACE != 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOATTR));
+        }
+    }
+
+    let ret = unsafe {
+        libc::extattr_set_fd(
+            file.as_raw_fd(),
+            namespace,
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Remove the extended attribute name of the file referred to by handle
+pub fn remove_xattr(handle: &File, name: &CString) -> io::Result<()> {
+    let (namespace, name) = extattr_name(name)?;
+    let file = reopen(handle, libc::O_RDONLY)?;
+    check_ret(unsafe { libc::extattr_delete_fd(file.as_raw_fd(), namespace, name.as_ptr()) })
+}
+
+// Return the value of the extended attribute name of the file referred to by
+// handle
+pub fn get_xattr(handle: &File, name: &CString) -> io::Result<Vec<u8>> {
+    let (namespace, name) = extattr_name(name)?;
+    let file = reopen(handle, libc::O_RDONLY)?;
+    read_sized(|buffer, size| unsafe {
+        libc::extattr_get_fd(file.as_raw_fd(), namespace, name.as_ptr(), buffer, size)
+    })
+}
+
+// Return the names of the extended attributes of the file referred to by
+// handle, each with its namespace's prefix and terminated by a nul. System
+// attributes are only listed if we are permitted to read them, as on Linux.
+pub fn list_xattrs(handle: &File) -> io::Result<Vec<u8>> {
+    let file = reopen(handle, libc::O_RDONLY)?;
+    let mut names = Vec::new();
+    for (namespace, prefix) in XATTR_NAMESPACES {
+        let list = read_sized(|buffer, size| unsafe {
+            libc::extattr_list_fd(file.as_raw_fd(), namespace, buffer, size)
+        });
+        let list = match list {
+            Ok(list) => list,
+            Err(err)
+                if namespace == libc::EXTATTR_NAMESPACE_SYSTEM
+                    && err.raw_os_error() == Some(libc::EPERM) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+
+        // Each name is preceded by its length in a byte, and isn't terminated
+        let mut rest = &list[..];
+        while let Some((&len, tail)) = rest.split_first() {
+            let (name, tail) = tail.split_at((len as usize).min(tail.len()));
+            names.extend_from_slice(prefix);
+            names.extend_from_slice(name);
+            names.push(0);
+            rest = tail;
+        }
+    }
+    Ok(names)
+}
+
+// Read a value whose size is returned by read when passed a null buffer.
+// The extattr syscalls truncate rather than fail if the value grew after we
+// asked for its size, so we ask for one more byte than we need and retry if
+// we got it.
+fn read_sized(read: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut value = vec![0u8; size as usize + 1];
+        let ret = read(value.as_mut_ptr() as *mut libc::c_void, value.len());
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if (ret as usize) < value.len() {
+            value.truncate(ret as usize);
+            return Ok(value);
+        }
+    }
+}
+
+// Fill buffer with the information of kind what about process pid, and
+// return how much of it was filled in bytes
+fn sysctl_proc<T>(pid: u32, what: libc::c_int, buffer: &mut [T]) -> io::Result<usize> {
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, what, pid as libc::c_int];
+    let mut size = std::mem::size_of_val(buffer);
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            &mut size,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+// The supplementary groups of process pid, or none if it has exited
+pub fn caller_groups(pid: u32) -> Vec<u32> {
+    let mut groups = vec![0 as libc::gid_t; libc::NGROUPS as usize];
+    match sysctl_proc(pid, libc::KERN_PROC_GROUPS, &mut groups) {
+        Ok(size) => {
+            groups.truncate(size / std::mem::size_of::<libc::gid_t>());
+            groups
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+// The name of process pid's command
+pub fn command(pid: u32) -> io::Result<String> {
+    let mut proc: [libc::kinfo_proc; 1] = unsafe { std::mem::zeroed() };
+    let size = sysctl_proc(pid, libc::KERN_PROC_PID, &mut proc)?;
+    // Nothing is returned for a process which has exited
+    if size < std::mem::size_of::<libc::kinfo_proc>() {
+        return Err(io::Error::from_raw_os_error(libc::ESRCH));
+    }
+    let comm = unsafe { CStr::from_ptr(proc[0].ki_comm.as_ptr()) };
+    Ok(comm.to_string_lossy().into_owned())
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Values are counted in buckets whose width doubles with every power of two,
+// with each power of two divided into SUB_BUCKETS linear buckets, in the style
+// of HdrHistogram. Every value is recorded to within 1/SUB_BUCKETS of its true
+// value, from nanoseconds to centuries, in a fixed amount of memory.
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+// A histogram of u64 values which may be recorded from many threads at once
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.buckets[bucket(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The number of values recorded which are certainly no greater than bound
+    pub fn count_to(&self, bound: u64) -> u64 {
+        self.buckets
+            .iter()
+            .enumerate()
+            .take_while(|(index, _)| highest(*index) <= bound)
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // The value below which the fraction quantile of values recorded fall,
+    // or 0 if nothing has been recorded
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let rank = (quantile * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest(index);
+            }
+        }
+        0
+    }
+}
+
+// The bucket in which value is counted
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let sub_bucket = (value >> (exponent - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+// The highest value counted in bucket index
+fn highest(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    let shift = exponent - SUB_BITS;
+    let lowest = (SUB_BUCKETS as u64 + sub_bucket) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
@@ -0,0 +1,157 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::filter::Pattern;
+use crate::metrics::Op;
+
+struct Fault {
+    // Empty for every operation
+    ops: Vec<Op>,
+    pattern: Option<Pattern>,
+    errno: i32,
+    probability: f64,
+}
+
+impl Fault {
+    fn applies(&self, op: Op, path: Option<&Path>) -> bool {
+        if !applies_to(&self.ops, op) {
+            return false;
+        }
+        match (&self.pattern, path) {
+            (None, _) => true,
+            (Some(pattern), Some(path)) => pattern.matches(path, false),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+struct Latency {
+    // Empty for every operation
+    ops: Vec<Op>,
+    delay: Duration,
+    jitter: Duration,
+}
+
+fn applies_to(ops: &[Op], op: Op) -> bool {
+    ops.is_empty() || ops.contains(&op)
+}
+
+fn parse_ops(names: &[String]) -> Result<Vec<Op>, String> {
+    names
+        .iter()
+        .map(|name| Op::from_name(name).ok_or_else(|| format!("unknown op {}", name)))
+        .collect()
+}
+
+// The most entries a directory read returns in chaos mode
+const MAX_CHAOS_ENTRIES: usize = 32;
+
+// Injects errors and delays into requests, as Config::faults and
+// Config::latencies describe, and shortens answers, as Config::chaos does
+pub struct Injector {
+    faults: Vec<Fault>,
+    latencies: Vec<Latency>,
+    chaos: bool,
+    // The state of a xorshift64* generator, which needn't be unpredictable
+    random: u64,
+}
+
+impl Injector {
+    pub fn new(config: &Config) -> Result<Injector, String> {
+        let faults = config
+            .faults
+            .iter()
+            .map(|rule| {
+                Ok(Fault {
+                    ops: parse_ops(&rule.ops)?,
+                    pattern: rule.pattern.as_deref().map(Pattern::new),
+                    errno: rule.errno,
+                    probability: rule.probability,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let latencies = config
+            .latencies
+            .iter()
+            .map(|rule| {
+                Ok(Latency {
+                    ops: parse_ops(&rule.ops)?,
+                    delay: rule.delay,
+                    jitter: rule.jitter,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let seed = config.fault_seed.unwrap_or_else(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            now.as_nanos() as u64 ^ u64::from(std::process::id())
+        });
+        Ok(Injector {
+            faults,
+            latencies,
+            chaos: config.chaos,
+            // The state must never be 0
+            random: seed | 1,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.is_empty() && self.latencies.is_empty()
+    }
+
+    // How long an op request should be delayed by, which is decided by the
+    // first latency rule which applies to it
+    pub fn latency(&mut self, op: Op) -> Duration {
+        let (delay, jitter) = match self.latencies.iter().find(|l| applies_to(&l.ops, op)) {
+            Some(latency) => (latency.delay, latency.jitter),
+            None => return Duration::ZERO,
+        };
+        delay + jitter.mul_f64(self.random())
+    }
+
+    // The errno an op request about path should fail with, if any. Each fault
+    // which applies is tried in turn, and fails the request with its
+    // probability.
+    pub fn fault(&mut self, op: Op, path: Option<&Path>) -> Option<i32> {
+        for i in 0..self.faults.len() {
+            if self.faults[i].applies(op, path) && self.chance(self.faults[i].probability) {
+                return Some(self.faults[i].errno);
+            }
+        }
+        None
+    }
+
+    // How many bytes of size to return from a read, from 1 up to size
+    pub fn read_len(&mut self, size: u32) -> u32 {
+        if !self.chaos || size == 0 {
+            return size;
+        }
+        1 + (self.random() * size as f64) as u32
+    }
+
+    // How many entries a directory read may return before it stops, which
+    // is at least 1 so that it doesn't look like the end of the directory
+    pub fn listing_len(&mut self) -> usize {
+        if !self.chaos {
+            return usize::MAX;
+        }
+        1 + (self.random() * MAX_CHAOS_ENTRIES as f64) as usize
+    }
+
+    // true with the given probability
+    fn chance(&mut self, probability: f64) -> bool {
+        self.random() < probability
+    }
+
+    // A number from 0 up to, but not including, 1
+    fn random(&mut self) -> f64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        let random = self.random.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (random >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use crate::config::Config;
+
+// Access rights from linux/landlock.h. Executing isn't handled, so it is
+// never restricted.
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// Since ABI 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+// Since ABI 3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const WRITE: u64 = ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM
+    | ACCESS_FS_REFER
+    | ACCESS_FS_TRUNCATE;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// A Landlock ruleset allowing reads beneath the root and every other
+// directory files are served from, and writes beneath them too if the mount
+// is read-write. Callers' details may be read from /proc, and the control
+// socket may be removed when we exit.
+pub struct Ruleset {
+    fd: File,
+    handled: u64,
+}
+
+impl Ruleset {
+    // Returns None if the kernel doesn't support Landlock
+    pub fn new(root: &Path, config: &Config) -> io::Result<Option<Ruleset>> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        let handled = match abi {
+            abi if abi >= 3 => READ | WRITE,
+            2 => (READ | WRITE) & !ACCESS_FS_TRUNCATE,
+            1 => (READ | WRITE) & !(ACCESS_FS_REFER | ACCESS_FS_TRUNCATE),
+            _ => match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => return Ok(None),
+                _ => return Err(io::Error::last_os_error()),
+            },
+        };
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = Ruleset {
+            fd: unsafe { File::from_raw_fd(fd as i32) },
+            handled,
+        };
+
+        let served = if config.read_write {
+            READ | WRITE
+        } else {
+            READ
+        };
+        ruleset.allow(root, served)?;
+        for branch in config.branches.iter().chain(&config.upper) {
+            ruleset.allow(branch, served)?;
+        }
+        ruleset.allow(Path::new("/proc"), ACCESS_FS_READ_FILE)?;
+        if let Some(dir) = config.control_socket.as_ref().and_then(|s| s.parent()) {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            ruleset.allow(dir, ACCESS_FS_REMOVE_FILE)?;
+        }
+        Ok(Some(ruleset))
+    }
+
+    // Allow access beneath path, as far as the kernel can restrict it
+    fn allow(&self, path: &Path, access: u64) -> io::Result<()> {
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)?;
+        let attr = PathBeneathAttr {
+            allowed_access: access & self.handled,
+            parent_fd: dir.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Restrict the calling thread, and any it starts, to the ruleset. This
+    // can't be undone, and doesn't affect files which are already open.
+    pub fn restrict_self(&self) -> io::Result<()> {
+        // Without CAP_SYS_ADMIN, a thread may only restrict itself if it can't
+        // gain privileges by executing anything
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result =
+            unsafe { libc::syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd(), 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
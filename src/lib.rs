@@ -1,3 +1,13 @@
+// Every inode is held by an O_PATH handle, which is reopened through
+// /proc/self/fd and used with AT_EMPTY_PATH, and attributes come from statx.
+// macOS has none of these, nor a way to open a file without read access or
+// to reopen a descriptor with other flags, so a port would need a different
+// handle layer built on paths, which would lose track of renamed files.
+// FreeBSD 13 has O_PATH and AT_EMPTY_PATH, and the freebsd module stands in
+// for the rest.
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+compile_error!("passfs only runs on Linux and FreeBSD");
+
 #[macro_use]
 extern crate error_chain;
 
@@ -6,20 +16,141 @@ pub mod errors {
 }
 use errors::*;
 
-use libc::stat;
+mod attr_map;
+use attr_map::AttrMap;
+
+mod audit;
+use audit::{AuditLog, Caller};
+
+mod checksum;
+use checksum::{hex_digest, Checksums};
+
+mod config;
+pub use config::{
+    AccessEvent, AccessPolicy, BranchPolicy, CacheMode, Config, FaultRule, FilterRule, IdMapping,
+    LatencyRule, ModeOverride, Oversize, PermissionCheck, PolicyOp, Principal, RateLimit,
+    RateLimitKey, Redaction, RenameRule, Timestamps, Transform, TransformRule, VirtualContent,
+    VirtualFile,
+};
+
+mod control;
+pub use control::request as control_request;
+use control::{Dump, Introspection};
+
+mod denial;
+use denial::{Denial, DenialLog};
+
+mod dir_cache;
+use dir_cache::{DirCache, ListedEntry, Listing};
+
+mod dir_stream;
+use dir_stream::{DirEntry, DirStream};
+
+mod fd_cache;
+use fd_cache::FdCache;
+
+mod filter;
+use filter::{Filter, Pattern};
+
+#[cfg(target_os = "freebsd")]
+mod freebsd;
+#[cfg(target_os = "freebsd")]
+use freebsd::{
+    backing_id, caller_groups, chmod_handle, fallocate, file_attr, file_handle, get_xattr,
+    handle_path, link_handle, list_xattrs, open_by_handle, openat2_beneath, remove_xattr,
+    renameat2, reopen, set_xattr, utimens_handle, EBADFD, ENODATA, RENAME_EXCHANGE,
+    RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE,
+};
+#[cfg(target_os = "freebsd")]
+use libc::posix_fadvise;
+#[cfg(target_os = "linux")]
+use libc::{
+    fallocate64 as fallocate, posix_fadvise64 as posix_fadvise, renameat2, EBADFD, ENODATA,
+    RENAME_EXCHANGE, RENAME_NOREPLACE, XATTR_CREATE, XATTR_REPLACE,
+};
+
+mod histogram;
+
+#[cfg(target_os = "linux")]
+mod landlock;
+#[cfg(target_os = "linux")]
+use landlock::Ruleset;
+
+mod inject;
+use inject::Injector;
+
+mod mmap;
+use mmap::Mapping;
+
+mod metrics;
+use metrics::{Metered, Metrics, Op};
+
+mod name_index;
+use name_index::{fold_case, NameIndex};
+
+mod policy;
+use policy::Policies;
+
+mod rate_limit;
+use rate_limit::{RateLimiter, Verdict};
+
+mod record;
+use record::Recorder;
+
+mod rename;
+use rename::Renamer;
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp;
+
+mod snapshot;
+use snapshot::Snapshot;
+
+mod stats_dir;
+use stats_dir::{StatsDir, StatsFile, STATS_DIR};
+
+pub mod testing;
+
+mod transform;
+use transform::Transforms;
+
+mod virtual_files;
+use virtual_files::VirtualFiles;
+
+mod workers;
+use workers::Workers;
+
+// libc's stat has 32-bit sizes and inode numbers on 32-bit Linux, so stat()
+// fails with EOVERFLOW for a large file or one with a large inode number.
+// stat64 and its functions are the same as stat everywhere else, as stat is
+// on FreeBSD.
+#[cfg(target_os = "freebsd")]
+use libc::{fstat as fstat64, fstatat as fstatat64, stat};
+#[cfg(target_os = "linux")]
+use libc::{fstat64, fstatat64, stat64 as stat};
+use std::cell::RefCell;
 use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
-use std::ffi::OsStr;
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use fuser::{
-    self, FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, Request, TimeOrNow,
+    self, consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, Session, TimeOrNow,
 };
-use log::{debug, warn};
-use openat::{self, Dir, DirIter, SimpleType};
+use openat::{self, Dir};
+use tracing::{debug, debug_span, field, info, trace_span, warn};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 struct Fh(u64);
@@ -33,401 +164,5507 @@ impl Fh {
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 struct Inode(u64);
 
+// The identity of a file in the backing tree
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct BackingId {
+    dev: u64,
+    ino: u64,
+}
+
+impl BackingId {
+    fn from_stat(stat: &stat) -> BackingId {
+        BackingId {
+            dev: stat.st_dev,
+            ino: stat.st_ino,
+        }
+    }
+}
+
+// Inode numbers handed out for files which can't use their own st_ino start
+// here
+const FIRST_SYNTHETIC_INODE: u64 = 1 << 63;
+
+// With nfs_export, at most this many file handles of forgotten inodes are
+// kept. nfsd gets ESTALE for the oldest beyond that, as it would after the
+// file had been deleted.
+const MAX_FILE_HANDLES: usize = 1 << 16;
+
+// Assigns FUSE inode numbers to files in the backing tree. The backing tree
+// may span several filesystems, so st_ino alone is not unique. Files on the
+// same filesystem as the root keep their own st_ino, which keeps inode numbers
+// stable and consistent with what readdir reports. Anything else is given a
+// synthetic inode number, which is remembered for the lifetime of the mount.
+//
+// The backing filesystem may reuse an inode number once the file using it
+// has been deleted. We bump the generation of an inode number whenever we
+// delete the file using it, or find that something else has, so that (ino,
+// generation) always identifies a single file. Generations start at first_generation, which is 1 with
+// nfs_export so that the file handles nfsd gives out never have generation 0.
+struct InodeNumbers {
+    root: BackingId,
+    // The devices of the roots of any further branches
+    branch_devs: Vec<u64>,
+    synthetic: BTreeMap<BackingId, u64>,
+    next_synthetic: u64,
+    generations: BTreeMap<u64, u64>,
+    first_generation: u64,
+}
+
+impl InodeNumbers {
+    fn new(root: &stat, branches: &[stat], first_generation: u64) -> InodeNumbers {
+        InodeNumbers {
+            root: BackingId::from_stat(root),
+            branch_devs: branches.iter().map(|branch| branch.st_dev).collect(),
+            synthetic: BTreeMap::new(),
+            next_synthetic: FIRST_SYNTHETIC_INODE,
+            generations: BTreeMap::new(),
+            first_generation,
+        }
+    }
+
+    fn generation(&self, ino: u64) -> u64 {
+        self.generations
+            .get(&ino)
+            .copied()
+            .unwrap_or(self.first_generation)
+    }
+
+    // Called when the file with stat has been deleted, so its inode number
+    // may be reused by a different file
+    fn retire(&mut self, stat: &stat) {
+        let id = BackingId::from_stat(stat);
+        let ino = match self.synthetic.remove(&id) {
+            // A new file with the same backing id will get a new synthetic
+            // number, so there's no need to track a generation
+            Some(ino) if ino >= FIRST_SYNTHETIC_INODE => {
+                return debug!("retire synthetic inode={}", ino)
+            }
+            // A copy of a file which kept the inode number of the original
+            Some(ino) => ino,
+            None => self.get(stat),
+        };
+
+        let generation = self.generations.entry(ino).or_insert(self.first_generation);
+        *generation += 1;
+        debug!("retire inode={}: generation={}", ino, generation);
+    }
+
+    fn get(&mut self, stat: &stat) -> u64 {
+        self.get_id(BackingId::from_stat(stat))
+    }
+
+    fn get_id(&mut self, id: BackingId) -> u64 {
+        if id == self.root {
+            return 1;
+        }
+
+        if let Some(ino) = self.synthetic.get(&id) {
+            return *ino;
+        }
+
+        if id.dev == self.root.dev && id.ino != 1 && id.ino < FIRST_SYNTHETIC_INODE {
+            return id.ino;
+        }
+
+        let ino = self.next_synthetic;
+        self.next_synthetic += 1;
+        debug!("synthetic inode={} for dev={} ino={}", ino, id.dev, id.ino);
+        self.synthetic.insert(id, ino);
+        ino
+    }
+
+    // Give the file with stat the inode number ino, which was used by the
+    // file it was copied from
+    fn alias(&mut self, stat: &stat, ino: u64) {
+        debug!(
+            "alias inode={} to dev={} ino={}",
+            ino, stat.st_dev, stat.st_ino
+        );
+        self.synthetic.insert(BackingId::from_stat(stat), ino);
+    }
+
+    // Whether stat refers to a file on the same filesystem as the root, or as
+    // the root of a branch
+    fn same_device(&self, stat: &stat) -> bool {
+        stat.st_dev == self.root.dev || self.branch_devs.contains(&stat.st_dev)
+    }
+}
+
+// nlookup is the number of references the kernel holds to this inode: every
+// entry we return in a reply adds one, and forget() drops them.
+//
+// handle is an O_PATH fd for the backing file, which is used for every
+// operation on the inode itself. It continues to refer to the same file if the
+// file or any of its ancestors is renamed, or if it is deleted.
+//
+// paths are only used to resolve names in a directory. A file with multiple
+// hard links may be known by several paths, and the most recently seen path is
+// first. A file which has been deleted has no paths.
+//
+// lru_key is the key of the inode in inode_lru if it is there, and otherwise
+// 0.
 #[derive(Debug)]
 struct InodeEntry {
-    rc: u64,
-    path: PathBuf,
+    nlookup: u64,
+    handle: Arc<File>,
+    paths: Vec<PathBuf>,
+    lru_key: u64,
 }
 
 impl InodeEntry {
-    fn new(rc: u64, path: PathBuf) -> InodeEntry {
-        InodeEntry { rc, path: PathBuf::from(path) }
+    fn new(nlookup: u64, handle: File, path: PathBuf) -> InodeEntry {
+        InodeEntry {
+            nlookup,
+            handle: Arc::new(handle),
+            paths: vec![path],
+            lru_key: 0,
+        }
+    }
+
+    fn add_path(&mut self, path: PathBuf) {
+        self.paths.retain(|known| *known != path);
+        self.paths.insert(0, path);
+    }
+
+    fn remove_path(&mut self, path: &Path) {
+        self.paths.retain(|known| known != path);
     }
 }
 
+// Tracks reads on a file handle to detect sequential access
+#[derive(Debug, Default)]
+struct ReadAhead {
+    // Where the next read will start if access is sequential
+    next_offset: u64,
+    // How far ahead we have already asked the kernel to prefetch
+    prefetched: u64,
+}
+
+// There is no backend trait between PassFs and the backing tree: requests
+// are served with syscalls on O_PATH handles and file descriptors, by the
+// dispatch thread and the workers, and behaviour such as openat2 confinement,
+// statx attributes, whiteouts and copy-up depends on the kernel's answers. A
+// mock in-memory backend could only stand in for all of that by duplicating
+// it, so applications embedding passfs should test against a real directory
+// instead.
+//
+// For the same reason there is no async session behind a feature: without an
+// ops layer to share, an implementation against fuse3 would be a second copy
+// of every request handler. The handlers block in syscalls anyway, so async
+// applications should run the fuser session on a thread of its own, as
+// testing::mount_temp does, and unmount it to end it.
 pub struct PassFs {
+    config: Config,
     root: Dir,
-    open_dirs: BTreeMap<Fh, (Dir, DirIter)>,
-    open_files: BTreeMap<Fh, File>,
-    inuse_fhs: BTreeSet<Fh>,
+    // Directories merged below root, in order of priority
+    branches: Vec<Dir>,
+    // Open directories, with the device each is on
+    open_dirs: BTreeMap<Fh, (u64, DirStream)>,
+    // Open directories which are read from a cached listing instead
+    dir_listings: BTreeMap<Fh, Listing>,
+    // Open files, with the flags the backing file was opened with. These are
+    // shared with worker threads while they handle a request.
+    open_files: BTreeMap<Fh, (Inode, i32, Arc<File>)>,
+    // File handles are shared by open files and directories. Released handles
+    // are reused before we allocate a new one from next_fh.
+    free_fhs: Vec<Fh>,
+    next_fh: u64,
+    read_ahead: BTreeMap<Fh, ReadAhead>,
+    mappings: BTreeMap<Fh, Arc<Mapping>>,
     inode_map: BTreeMap<Inode, InodeEntry>,
+    // With max_inodes, inodes in inode_map which the kernel has forgotten,
+    // ordered by when it did
+    inode_lru: BTreeMap<u64, Inode>,
+    next_lru_key: u64,
+    inode_numbers: InodeNumbers,
+    workers: Workers,
+    fd_cache: FdCache,
+    dir_cache: DirCache,
+    filter: Filter,
+    policies: Policies,
+    // Directories which are listed as empty
+    unlisted_dirs: Vec<Pattern>,
+    rate_limiter: Option<RateLimiter>,
+    injector: Injector,
+    renamer: Renamer,
+    name_index: NameIndex,
+    // Shared with worker threads which reply with attributes
+    attr_map: Arc<AttrMap>,
+    transforms: Transforms,
+    // Shared with worker threads which compute checksums
+    checksums: Arc<Checksums>,
+    virtual_files: VirtualFiles,
+    // Open virtual files, with their content
+    virtual_opens: BTreeMap<Fh, Arc<Vec<u8>>>,
+    // The directory describing the filesystem in the root, if enabled
+    stats_dir: Option<StatsDir>,
+    // Shared with the control socket, if there is one
+    introspection: Option<Arc<Introspection>>,
+    // The tree as it was when mounted, which is presented instead of the
+    // backing tree if set
+    snapshot: Option<Snapshot>,
+    // Shared with worker threads which reply to requests, and with the
+    // metrics endpoint
+    metrics: Arc<Metrics>,
+    // Shared with worker threads which reply to audited requests
+    audit_log: Option<Arc<AuditLog>>,
+    denial_log: Option<Arc<DenialLog>>,
+    recorder: Option<Arc<Recorder>>,
+    // With nfs_export, the file handles of inodes which have left inode_map,
+    // by which nfsd may still look them up, with their keys in
+    // file_handle_lru, which holds them in the order they were kept
+    file_handles: BTreeMap<Inode, (u64, Vec<u32>)>,
+    file_handle_lru: BTreeMap<u64, Inode>,
+    next_file_handle: u64,
 }
 
 impl PassFs {
-    fn new(root_path: &str) -> Result<Self> {
-        let root = Dir::open(root_path)
-            .chain_err(|| format!("Unable to open passfs root directory {}", root_path))?;
+    fn new(root_path: &str, mut config: Config) -> Result<Self> {
+        if config.nfs_export {
+            if cfg!(not(target_os = "linux")) {
+                bail!("Unable to export over NFS: only supported on Linux");
+            }
+            if !config.branches.is_empty() || config.upper.is_some() || config.snapshot {
+                bail!("Unable to export over NFS with branches, an upper directory or a snapshot");
+            }
+            // Opening a file by its handle needs CAP_DAC_READ_SEARCH, which
+            // is dropped with the other capabilities
+            if config.run_as.is_some() {
+                bail!("Unable to export over NFS as another user");
+            }
+            // Synthetic inode numbers for other filesystems would change
+            // from one mount to the next
+            config.one_file_system = true;
+        }
+
+        // The kernel caches entries and attributes for every caller alike, so
+        // a caller could otherwise reach a file another caller looked up
+        // without our deciding whether they may
+        if !config.access_policies.is_empty() {
+            config.entry_timeout = Duration::ZERO;
+            config.attr_timeout = Duration::ZERO;
+            config.negative_timeout = Duration::ZERO;
+        }
+
+        // With an upper directory we write there instead, and the root is
+        // the first branch below it
+        let mut branch_paths = config.branches.clone();
+        let root_path = match &config.upper {
+            Some(upper) => {
+                branch_paths.insert(0, PathBuf::from(root_path));
+                upper.clone()
+            }
+            None => PathBuf::from(root_path),
+        };
+
+        let root = Dir::open(&root_path).chain_err(|| {
+            format!(
+                "Unable to open passfs root directory {}",
+                root_path.display()
+            )
+        })?;
+        let root_stat = stat_at(&root, Path::new(".")).chain_err(|| {
+            format!(
+                "Unable to stat passfs root directory {}",
+                root_path.display()
+            )
+        })?;
+        let mut branches = Vec::with_capacity(branch_paths.len());
+        let mut branch_stats = Vec::with_capacity(branch_paths.len());
+        for branch_path in &branch_paths {
+            let branch = Dir::open(branch_path)
+                .chain_err(|| format!("Unable to open passfs branch {}", branch_path.display()))?;
+            let stat = stat_at(&branch, Path::new("."))
+                .chain_err(|| format!("Unable to stat passfs branch {}", branch_path.display()))?;
+            branches.push(branch);
+            branch_stats.push(stat);
+        }
+        let workers = Workers::new(config.threads);
+        let fd_cache = FdCache::new(config.fd_cache_size);
+        let dir_cache = DirCache::new(config.dir_cache_timeout);
+        let filter = Filter::new(&config);
+        let policies = Policies::new(&config);
+        let unlisted_dirs = config
+            .unlisted_dirs
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect();
+        let rate_limiter = config.rate_limit.clone().map(RateLimiter::new);
+        let injector = Injector::new(&config)
+            .map_err(|err| format!("Invalid fault or latency rule: {}", err))?;
+        let renamer = Renamer::new(&config.rename_rules);
+        let attr_map = Arc::new(AttrMap::new(&config));
+        let transforms = Transforms::new(&config.transforms);
+        let metrics = Arc::new(Metrics::new(config.slow_op_threshold));
+        let audit_log = match &config.audit_log {
+            Some(path) => {
+                Some(Arc::new(AuditLog::open(path).chain_err(|| {
+                    format!("Unable to open audit log {}", path.display())
+                })?))
+            }
+            None => None,
+        };
+        let denial_log = if config.denial_log.is_some() || config.denial_hook.is_some() {
+            let log = DenialLog::new(config.denial_log.as_deref(), config.denial_hook.as_deref())
+                .chain_err(|| "Unable to open denial log")?;
+            Some(Arc::new(log))
+        } else {
+            None
+        };
+        let recorder = match &config.record {
+            Some(path) => {
+                Some(Arc::new(Recorder::open(path).chain_err(|| {
+                    format!("Unable to open recording {}", path.display())
+                })?))
+            }
+            None => None,
+        };
+        let virtual_files = VirtualFiles::new(&config.virtual_files)
+            .chain_err(|| "Unable to create virtual files")?;
+        let stats_dir = if config.stats_dir {
+            Some(StatsDir::new())
+        } else {
+            None
+        };
+        let inode_numbers =
+            InodeNumbers::new(&root_stat, &branch_stats, u64::from(config.nfs_export));
         let mut passfs = PassFs {
+            config,
             root,
+            branches,
             open_dirs: BTreeMap::new(),
+            dir_listings: BTreeMap::new(),
             open_files: BTreeMap::new(),
-            inuse_fhs: BTreeSet::new(),
+            free_fhs: Vec::new(),
+            next_fh: 0,
+            read_ahead: BTreeMap::new(),
+            mappings: BTreeMap::new(),
             inode_map: BTreeMap::new(),
+            inode_lru: BTreeMap::new(),
+            next_lru_key: 1,
+            inode_numbers,
+            workers,
+            fd_cache,
+            dir_cache,
+            filter,
+            policies,
+            unlisted_dirs,
+            rate_limiter,
+            injector,
+            renamer,
+            name_index: NameIndex::new(),
+            attr_map,
+            transforms,
+            checksums: Arc::new(Checksums::new()),
+            virtual_files,
+            virtual_opens: BTreeMap::new(),
+            stats_dir,
+            introspection: None,
+            snapshot: None,
+            metrics,
+            audit_log,
+            denial_log,
+            recorder,
+            file_handles: BTreeMap::new(),
+            file_handle_lru: BTreeMap::new(),
+            next_file_handle: 0,
         };
-        passfs.inode_map.insert(Inode(1), InodeEntry::new(1, ".".into()));
+        let root_handle = passfs
+            .open_at(Path::new("."), libc::O_PATH | libc::O_DIRECTORY, 0)
+            .chain_err(|| {
+                format!(
+                    "Unable to open passfs root directory {}",
+                    root_path.display()
+                )
+            })?;
+        passfs
+            .inode_map
+            .insert(Inode(1), InodeEntry::new(1, root_handle, ".".into()));
+        if passfs.config.snapshot {
+            let snapshot = passfs.take_snapshot().chain_err(|| {
+                format!(
+                    "Unable to snapshot passfs root directory {}",
+                    root_path.display()
+                )
+            })?;
+            passfs.snapshot = Some(snapshot);
+        }
         Ok(passfs)
     }
 
-    fn get_fh(&mut self) -> Fh {
-        let fd = Fh(self
-            .inuse_fhs
-            .iter()
-            .enumerate()
-            // Find the first gap in the list of open fds
-            .find(|&(i, fd)| i as u64 != fd.value())
-            .map(|(i, _)| i as u64)
-            // If no gap, return the next number
-            .unwrap_or(self.inuse_fhs.len() as u64));
-        self.inuse_fhs.insert(fd);
-        fd
-    }
-}
+    // Record every visible file below the root as it is now
+    fn take_snapshot(&mut self) -> io::Result<Snapshot> {
+        let pin = self.config.pin_snapshot;
+        let mut snapshot = Snapshot::new();
+        let (handle, stat) = self.open_handle(Path::new("."))?;
+        snapshot.add(1, stat, PathBuf::new(), Some(handle).filter(|_| pin), None);
 
-impl Filesystem for PassFs {
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let path = match self.inode_map.get(&Inode(ino)) {
-            Some(inode_entry) => &inode_entry.path,
-            None => return reply.error(libc::ENOENT),
-        };
+        let mut dirs = vec![(1, PathBuf::new())];
+        while let Some((parent, dir_path)) = dirs.pop() {
+            if self.too_deep(&dir_path) {
+                continue;
+            }
 
-        let metadata = self.root.metadata(path);
-        match metadata {
-            Ok(metadata) => reply.attr(&Duration::new(0, 0), &stat_to_fileattr(&metadata.stat())),
-            Err(err) => {
-                let err = err.raw_os_error().unwrap_or(libc::EIO);
-                reply.error(err);
-                self.inode_map.remove(&Inode(ino));
+            let open_path = if parent == 1 {
+                Path::new(".")
+            } else {
+                &dir_path
+            };
+            // A directory we can't read is presented as empty, rather than
+            // failing the mount
+            let entries = self
+                .open_at(open_path, libc::O_RDONLY | libc::O_DIRECTORY, 0)
+                .and_then(DirStream::new);
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!("Skipping {} in snapshot: {}", open_path.display(), err);
+                    continue;
+                }
+            };
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        warn!(
+                            "Skipping the rest of {} in snapshot: {}",
+                            open_path.display(),
+                            err
+                        );
+                        break;
+                    }
+                };
+                let path = dir_path.join(&entry.name);
+                let (handle, stat) = match self.open_handle(&path) {
+                    Ok(opened) => opened,
+                    // Removed since we listed it
+                    Err(err) if err.raw_os_error() == Some(libc::ENOENT) => continue,
+                    Err(err) => return Err(err),
+                };
+
+                if self.config.one_file_system && !self.inode_numbers.same_device(&stat) {
+                    continue;
+                }
+                if self.config.oversize == Oversize::Hide && oversized(&self.config, &stat) {
+                    continue;
+                }
+                let kind = mode_file_type(stat.st_mode).unwrap_or(FileType::RegularFile);
+                if !self.filter.visible(&path, kind) {
+                    continue;
+                }
+
+                let ino = self.inode_numbers.get(&stat);
+                let target = match kind {
+                    FileType::Symlink => Some(read_link(&handle)?),
+                    _ => None,
+                };
+                if kind == FileType::RegularFile {
+                    self.transforms.attach(ino, &path);
+                }
+                let name = self.renamer.present(&entry.name).into_owned();
+                let key = self.name_key(&name);
+                let handle = Some(handle).filter(|_| pin);
+                if snapshot.add(ino, stat, path.clone(), handle, target)
+                    && kind == FileType::Directory
+                {
+                    dirs.push((ino, path));
+                }
+                snapshot.link(parent, key, name, ino);
             }
         }
+        Ok(snapshot)
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let mut path = if parent == 1 {
-            PathBuf::new()
-        } else {
-            match self.inode_map.get(&Inode(parent)) {
-                Some(inode_entry) => inode_entry.path.clone(),
-                None => return reply.error(libc::ENOENT),
-            }
+    // The attributes of inode as they were when the snapshot was taken, if
+    // it is in the snapshot
+    fn snapshot_attr(&mut self, inode: Inode) -> io::Result<Option<FileAttr>> {
+        let stat = match self
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.get(inode.0))
+        {
+            Some(node) => node.stat,
+            None => return Ok(None),
         };
-        path.push(name);
-
-        let metadata = self.root.metadata(&path);
-        match metadata {
-            Ok(metadata) => {
-                let stat = metadata.stat();
-                let fileattr = stat_to_fileattr(&stat);
-                self.inode_map
-                    .entry(Inode(fileattr.ino))
-                    .and_modify(|inode_entry| {
-                        inode_entry.rc += 1;
-                        debug!("lookup inode={}: rc={}", fileattr.ino, inode_entry.rc);
-                    })
-                    .or_insert(InodeEntry::new(0, path));
-                reply.entry(&Duration::new(0, 0), &fileattr, 0);
-            }
-            Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
+        let mut fileattr = stat_to_fileattr(inode.0, &stat);
+        if let Some(size) = self.transformed_size(inode)? {
+            set_size(&mut fileattr, size);
         }
+        self.attr_map.apply(&mut fileattr);
+        Ok(Some(fileattr))
     }
 
-    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
-        let inode = Inode(ino);
-        let rc = if let Entry::Occupied(mut inode_entry) = self.inode_map.entry(inode) {
-            let inode_entry = inode_entry.get_mut();
-            inode_entry.rc -= nlookup;
-            inode_entry.rc
+    // Wrap reply to record the outcome of op by req on ino, or on its entry
+    // name, in metrics
+    fn meter<R>(
+        &mut self,
+        req: &Request<'_>,
+        op: Op,
+        ino: u64,
+        name: Option<&OsStr>,
+        reply: R,
+    ) -> Metered<R> {
+        let open_handles = self.open_files.len()
+            + self.open_dirs.len()
+            + self.dir_listings.len()
+            + self.virtual_opens.len();
+        self.metrics.set_gauges(self.inode_map.len(), open_handles);
+        if let Some(introspection) = self.introspection.as_ref().filter(|i| i.wanted()) {
+            introspection.publish(self.dump());
+        }
+
+        // Describing the request is only worth it if it could be logged
+        let logged = self.metrics.logs_slow_ops() || self.config.log_callers;
+        let subject = if logged || self.denial_log.is_some() {
+            Some(self.describe(ino, name))
         } else {
-            return debug!("forget inode={}: doesn't exist", ino);
+            None
         };
+        let span = debug_span!(
+            "request",
+            op = op.name(),
+            ino,
+            pid = req.pid(),
+            uid = req.uid(),
+            gid = req.gid(),
+            fh = field::Empty,
+            size = field::Empty
+        );
+        let mut reply = Metered::new(reply, op, subject, span, &self.metrics);
+        if let Some(denial_log) = &self.denial_log {
+            reply = reply.report_denials(Arc::clone(denial_log), Caller::new(req));
+        }
+        if let (Some(recorder), Some(path)) = (&self.recorder, self.known_path(ino)) {
+            let path = match name {
+                Some(name) => path.join(name),
+                None => path,
+            };
+            let record = recorder.begin(op.name(), &Caller::new(req), &path);
+            reply = reply.record_to(Arc::clone(recorder), record);
+        }
+        // Injected latency holds back the reply rather than the request, so
+        // that the requests queued behind it aren't held up too. Releases
+        // are never delayed, as they aren't subject to injection.
+        if !self.injector.is_empty() && !matches!(op, Op::Release | Op::Releasedir) {
+            let latency = self.injector.latency(op);
+            if latency > Duration::ZERO {
+                reply = reply.delay(latency, self.workers.timer());
+            }
+        }
+        if self.config.log_callers {
+            let caller = Caller::new(req);
+            let command = caller.command();
+            return reply.log_caller(caller, command);
+        }
+        reply
+    }
 
-        debug!("forget inode={}: rc={}", ino, rc);
-
-        if rc == 0 {
-            self.inode_map.remove(&inode);
+    // Add the path of ino, or of its entry name, to the record of the request
+    // replied to by reply, if it is being recorded
+    fn record_path<R>(
+        &self,
+        reply: Metered<R>,
+        key: &str,
+        ino: u64,
+        name: Option<&OsStr>,
+    ) -> Metered<R> {
+        if !reply.recording() {
+            return reply;
         }
+        let path = self.known_path(ino).map(|path| match name {
+            Some(name) => path.join(name),
+            None => path,
+        });
+        reply.arg(
+            key,
+            path.as_ref().map(|path| path.to_string_lossy()).as_deref(),
+        )
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        let path = match self.inode_map.get(&Inode(ino)) {
-            Some(inode_entry) => &inode_entry.path,
-            None => return reply.error(libc::ENOENT),
+    // Charge the caller of req for a request reading or writing bytes, failing
+    // with EAGAIN if they have exceeded their rate limit. Returns how long a
+    // read or write must wait before it is done. Requests the kernel makes
+    // itself, such as writing back dirty pages, have no caller: they were
+    // paid for when the data was written, and failing them would lose it.
+    fn throttle(&mut self, req: &Request<'_>, bytes: u64) -> io::Result<Duration> {
+        let verdict = match &mut self.rate_limiter {
+            Some(_) if req.pid() == 0 => return Ok(Duration::ZERO),
+            Some(rate_limiter) => rate_limiter.check(&Caller::new(req), bytes),
+            None => return Ok(Duration::ZERO),
         };
+        match verdict {
+            Verdict::Admit => Ok(Duration::ZERO),
+            Verdict::Delay(delay) => Ok(delay),
+            Verdict::Refuse => Err(io::Error::from_raw_os_error(libc::EAGAIN)),
+        }
+    }
 
-        let dir = match self.root.sub_dir(path) {
-            Ok(dir) => dir,
-            Err(err) => return reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
-        };
+    // If we check permissions, fail with EACCES unless the caller of req may
+    // access ino as mask asks
+    fn check_access(&mut self, req: &Request<'_>, ino: u64, mask: i32) -> io::Result<()> {
+        if self.config.check_permissions != PermissionCheck::Daemon {
+            return Ok(());
+        }
+        let fileattr = self.presented_attr(ino)?;
+        if permitted(req, &fileattr, mask) {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        }
+    }
 
-        match dir.list_dir(".") {
-            Ok(iter) => {
-                let fh = self.get_fh();
-                self.open_dirs.insert(fh, (dir, iter));
-                reply.opened(fh.value(), 0)
-            }
-            Err(err) => {
-                let err = err.raw_os_error().unwrap_or(libc::EIO);
-                if err == libc::ENOENT {
-                    self.inode_map.remove(&Inode(ino));
-                }
-                reply.error(err)
+    // Check that the caller of req may change the extended attribute name of
+    // ino, under PermissionCheck::Daemon. As the kernel decides it, user
+    // attributes need write access, and the owner must change any others,
+    // such as ACLs.
+    fn check_xattr_write(&mut self, req: &Request<'_>, ino: u64, name: &OsStr) -> io::Result<()> {
+        if self.config.check_permissions != PermissionCheck::Daemon {
+            return Ok(());
+        }
+        let fileattr = self.presented_attr(ino)?;
+        if name.as_bytes().starts_with(b"user.") {
+            if !permitted(req, &fileattr, libc::W_OK) {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
             }
+        } else if req.uid() != 0 && req.uid() != fileattr.uid {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
         }
+        Ok(())
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if offset < 0 {
-            return reply.error(libc::EINVAL);
+    // Check that the caller of req may remove the entry for the file with
+    // stat from the directory parent, under PermissionCheck::Daemon. Only
+    // the owner of the file or of the directory may if the directory is
+    // sticky, like /tmp.
+    fn check_sticky(&mut self, req: &Request<'_>, parent: u64, stat: &stat) -> io::Result<()> {
+        if self.config.check_permissions != PermissionCheck::Daemon || req.uid() == 0 {
+            return Ok(());
+        }
+        let dir = self.presented_attr(parent)?;
+        if dir.perm & 0o1000 == 0 || req.uid() == dir.uid {
+            return Ok(());
+        }
+        let mut fileattr = stat_to_fileattr(0, stat);
+        self.attr_map.apply(&mut fileattr);
+        if req.uid() == fileattr.uid {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EPERM))
         }
+    }
 
-        let (dir, diriter) = match self.open_dirs.get_mut(&Fh(fh)) {
-            None => {
-                reply.error(libc::EBADFD);
-                return;
-            }
-            Some(dir) => dir,
-        };
+    // A path relative to the root at which ino was found, if it has one
+    fn known_path(&self, ino: u64) -> Option<PathBuf> {
+        if ino == 1 {
+            return Some(PathBuf::new());
+        }
+        self.inode_map
+            .get(&Inode(ino))
+            .and_then(|inode_entry| inode_entry.paths.first().cloned())
+    }
 
-        for entry in diriter {
-            match entry {
-                Ok(entry) => {
-                    let kind = match entry.simple_type() {
-                        Some(SimpleType::Symlink) => FileType::Symlink,
-                        Some(SimpleType::Dir) => FileType::Directory,
-                        Some(SimpleType::File) => FileType::RegularFile,
-                        // CharDevice is our catch-all weird device type here.
-                        // It looks like you really can't extract the actual
-                        // data from Entry
-                        Some(SimpleType::Other) => FileType::CharDevice,
-                        // WTF does None mean here?
-                        None => FileType::CharDevice,
-                    };
-
-                    // Unfortunately, although the dirent retrived by the openat
-                    // library contains the inode they decided not to give it to
-                    // us. We make another system call to fetch it for realz
-                    // this time.
-                    let file_name = entry.file_name();
-
-                    let metadata = match dir.metadata(file_name) {
-                        Ok(metadata) => metadata,
-                        Err(err) => return reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
-                    };
-
-                    if reply.add(metadata.stat().st_ino, 0, kind, file_name) {
-                        // add returns true if the reply buffer is full
-                        return reply.ok();
-                    }
-                },
-                Err(err) => return reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
-            }
+    // Delay an op request about ino, or its entry name, if latency is to be
+    // injected, and then return the errno it should fail with, if a fault is
+    // to be injected
+    fn inject(&mut self, op: Op, ino: u64, name: Option<&OsStr>) -> Option<i32> {
+        if self.injector.is_empty() {
+            return None;
         }
-        reply.ok()
+        let path = self.known_path(ino).map(|dir| match name {
+            Some(name) => dir.join(name),
+            None => dir,
+        });
+        self.injector.fault(op, path.as_deref())
     }
 
-    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
-        let fh = Fh(fh);
-        if self.open_dirs.remove(&fh).is_none() {
-            warn!("releasedir, but {:?} is not in open_dirs", fh)
+    // Whether the access policies, if any, allow the caller of req to do op to
+    // ino. Virtual files and the stats directory aren't part of the backing
+    // tree, so policies don't apply to them.
+    fn policy_allows(&self, req: &Request<'_>, ino: u64, op: PolicyOp) -> bool {
+        if self.policies.is_empty() || self.virtual_files.attr(ino).is_some() || self.is_stats(ino)
+        {
+            return true;
         }
+        let is_dir = self.is_dir(ino);
+        self.known_path(ino)
+            .is_some_and(|path| self.policies.allows(&Caller::new(req), &path, is_dir, op))
+    }
 
-        if !self.inuse_fhs.remove(&fh) {
-            warn!("releasedir, but {:?} is not in inuse_fhs", fh)
+    // Whether the access policies, if any, allow the caller of req to look up
+    // ino. The kernel caches entries and attributes for every caller alike,
+    // so a caller may reach an inode another looked up, and requests about
+    // an inode check this again.
+    fn policy_allows_lookup(&self, req: &Request<'_>, ino: u64) -> bool {
+        if self.policies.is_empty()
+            || ino == 1
+            || self.virtual_files.attr(ino).is_some()
+            || self.is_stats(ino)
+        {
+            return true;
         }
+        let is_dir = self.is_dir(ino);
+        self.known_path(ino)
+            .is_some_and(|path| self.policies.may_lookup(&Caller::new(req), &path, is_dir))
+    }
 
-        reply.ok()
+    // Whether the access policies, if any, allow the caller of req to do op to
+    // the file at path, relative to the root, which may not exist yet
+    fn policy_allows_path(
+        &self,
+        req: &Request<'_>,
+        path: &Path,
+        is_dir: bool,
+        op: PolicyOp,
+    ) -> bool {
+        self.policies.is_empty() || self.policies.allows(&Caller::new(req), path, is_dir, op)
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
-        let mask = libc::O_APPEND | libc::O_CREAT | libc::O_TRUNC;
+    // Whether ino is a directory in the backing tree
+    fn is_dir(&self, ino: u64) -> bool {
+        self.handle(Inode(ino))
+            .and_then(|handle| fstat(&handle))
+            .is_ok_and(|stat| stat.st_mode & libc::S_IFMT == libc::S_IFDIR)
+    }
 
-        if flags & mask != 0 {
-            return reply.error(libc::EROFS);
+    // The attributes of ino as getattr presents them, apart from size
+    fn presented_attr(&mut self, ino: u64) -> io::Result<FileAttr> {
+        if let Some(mut fileattr) = self.virtual_files.attr(ino) {
+            self.attr_map.apply(&mut fileattr);
+            return Ok(fileattr);
+        }
+        if let Some(fileattr) = self.stats_dir.as_ref().and_then(|dir| dir.attr(ino)) {
+            return Ok(fileattr);
         }
+        if let Some(fileattr) = self.snapshot_attr(Inode(ino))? {
+            return Ok(fileattr);
+        }
+        let mut fileattr = file_attr(ino, &*self.handle(Inode(ino))?)?;
+        self.attr_map.apply(&mut fileattr);
+        Ok(fileattr)
+    }
 
-        let path = match self.inode_map.get(&Inode(ino)) {
-            Some(inode_entry) => &inode_entry.path,
-            None => return reply.error(libc::ENOENT),
-        };
+    // Describe the open handles and the inodes we hold, a line for each
+    fn dump(&self) -> Dump {
+        let mut handles = String::new();
+        for (fh, (inode, flags, _)) in &self.open_files {
+            let path = self.describe(inode.0, None);
+            handles += &format!("{} file {} flags={:#o} {}\n", fh.0, inode.0, flags, path);
+        }
+        for fh in self.open_dirs.keys() {
+            handles += &format!("{} dir\n", fh.0);
+        }
+        for (fh, listing) in &self.dir_listings {
+            handles += &format!("{} listing entries={}\n", fh.0, listing.len());
+        }
+        for (fh, content) in &self.virtual_opens {
+            handles += &format!("{} virtual size={}\n", fh.0, content.len());
+        }
 
-        match self.root.open_file(path) {
-            Ok(file) => {
-                let fh = self.get_fh();
-                self.open_files.insert(fh, file);
-                reply.opened(fh.value(), 0)
-            }
-            Err(err) => {
-                let err = err.raw_os_error().unwrap_or(libc::EIO);
-                if err == libc::ENOENT {
-                    self.inode_map.remove(&Inode(ino));
-                }
-                reply.error(err)
-            }
+        let mut inodes = String::new();
+        for (inode, entry) in &self.inode_map {
+            let path = entry.paths.first().map(|path| path.display());
+            inodes += &match path {
+                Some(path) => format!("{} nlookup={} {}\n", inode.0, entry.nlookup, path),
+                None => format!("{} nlookup={} (deleted)\n", inode.0, entry.nlookup),
+            };
         }
+        Dump { handles, inodes }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        if offset < 0 {
-            return reply.error(libc::EINVAL);
+    // Record the outcome of req in the audit log and send it as an access
+    // event, if either is wanted
+    fn audit<R>(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        name: Option<&OsStr>,
+        reply: Metered<R>,
+    ) -> Metered<R> {
+        if self.audit_log.is_none() && self.config.access_events.is_none() {
+            return reply;
         }
+        reply.observe(
+            Caller::new(req),
+            self.describe(ino, name),
+            self.audit_log.clone(),
+            self.config.access_events.clone(),
+        )
+    }
 
-        let fh = Fh(fh);
-        let mut file = match self.open_files.get(&fh) {
-            Some(file) => file,
-            None => return reply.error(libc::EBADFD),
-        };
-        if let Err(err) = file.seek(SeekFrom::Start(offset as u64)) {
-            return reply.error(err.raw_os_error().unwrap_or(libc::EIO));
+    // Describe the file ino, or its entry name, by path if we know one
+    fn describe(&self, ino: u64, name: Option<&OsStr>) -> String {
+        let path = self
+            .inode_map
+            .get(&Inode(ino))
+            .and_then(|entry| entry.paths.first());
+        match (path, name) {
+            (Some(path), Some(name)) => path.join(name).display().to_string(),
+            (Some(path), None) => path.display().to_string(),
+            (None, Some(name)) => format!("inode {}/{}", ino, name.to_string_lossy()),
+            (None, None) => format!("inode {}", ino),
+        }
+    }
+
+    fn get_fh(&mut self) -> Fh {
+        self.free_fhs.pop().unwrap_or_else(|| {
+            let fh = Fh(self.next_fh);
+            self.next_fh += 1;
+            fh
+        })
+    }
+
+    // Return fh for reuse. It must have been removed from open_files,
+    // open_dirs, dir_listings or virtual_opens.
+    fn put_fh(&mut self, fh: Fh) {
+        self.free_fhs.push(fh);
+    }
+
+    // Return the path relative to root of name in the directory parent, which
+    // must be visible through the filter. A file which is hidden doesn't
+    // exist as far as the caller is concerned, and a name which would be
+    // hidden whatever it was can't be created.
+    fn child_path(&mut self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        let path = self.lookup_path(parent, name)?;
+        if self.filter.is_empty() {
+            return Ok(path);
         }
 
-        let mut buffer = vec![0u8; size as usize];
-        let mut pos = 0;
-        while pos < buffer.len() {
-            match file.read(&mut buffer[pos..]) {
-                Ok(0) => break,
-                Ok(bytesin) => pos += bytesin,
-                Err(err) => return reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
+        let visible = match stat_at(self.branch(&path), &path) {
+            Ok(stat) => {
+                let kind = mode_file_type(stat.st_mode).unwrap_or(FileType::RegularFile);
+                if !self.filter.visible(&path, kind) {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+                true
             }
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                self.filter.visible(&path, FileType::RegularFile)
+                    || self.filter.visible(&path, FileType::Directory)
+            }
+            Err(err) => return Err(err),
+        };
+        if !visible {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
         }
-        reply.data(&buffer[..pos])
+        Ok(path)
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        let fh = Fh(fh);
-        if self.open_files.remove(&fh).is_none() {
-            warn!("release, but {:?} is not in open_files", fh)
+    // Return the path relative to root of name in the directory parent,
+    // whether or not it is visible. lookup checks visibility itself, so that
+    // it can report the denial and cache that the name doesn't exist.
+    fn lookup_path(&mut self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        // name must be a single component of parent. Pushing an absolute name
+        // would replace the path entirely, and .. would walk out of parent,
+        // potentially to somewhere outside root. A NUL would end the path
+        // early when it is passed to the kernel.
+        let bytes = name.as_bytes();
+        if bytes.is_empty()
+            || bytes == b"."
+            || bytes == b".."
+            || bytes.contains(&b'/')
+            || bytes.contains(&0)
+        {
+            warn!("Rejecting invalid name {:?} in inode={}", name, parent);
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
 
-        if !self.inuse_fhs.remove(&fh) {
-            warn!("release, but {:?} is not in inuse_fhs", fh)
+        // The name of a virtual file can't be used for anything else, nor can
+        // names reserved for whiteouts, and nothing can be created in the
+        // stats directory
+        if (parent == 1 && self.reserved_name(name)) || self.is_stats(parent) {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        if self.config.upper.is_some() && whiteout_target(name).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
         }
 
-        reply.ok()
-    }
-
-    fn create(
-        &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
-        reply: ReplyCreate,
-    ) {
-        reply.error(libc::EROFS)
+        let mut path = self.dir_path(Inode(parent))?;
+        match self.backing_name(Inode(parent), &path, name)? {
+            Some(backing_name) => path.push(backing_name),
+            None => path.push(name),
+        }
+        Ok(path)
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        reply.error(libc::EROFS)
+    // Whether name in the root is taken by a virtual file or the stats
+    // directory
+    fn reserved_name(&self, name: &OsStr) -> bool {
+        self.virtual_files.find(name).is_some() || (self.stats_dir.is_some() && name == STATS_DIR)
     }
 
-    fn setxattr(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
-        _flags: i32,
-        _position: u32,
-        reply: ReplyEmpty,
-    ) {
-        reply.error(libc::EROFS)
+    // Whether ino is the stats directory or one of its files
+    fn is_stats(&self, ino: u64) -> bool {
+        self.stats_dir.as_ref().is_some_and(|dir| dir.contains(ino))
     }
 
-    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        reply.error(libc::EPERM)
+    // Generate the content of a file in the stats directory
+    fn stats_content(&self, file: StatsFile) -> Vec<u8> {
+        match file {
+            StatsFile::Stats => self.metrics.render().into_bytes(),
+            StatsFile::Config => format!("{:#?}\n", self.config).into_bytes(),
+            StatsFile::Version => format!("passfs {}\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+        }
     }
-}
 
-fn stat_to_fileattr(stat: &stat) -> FileAttr {
-    let kind = match stat.st_mode & libc::S_IFMT {
-        libc::S_IFSOCK => FileType::Socket,
-        libc::S_IFLNK => FileType::Symlink,
-        libc::S_IFREG => FileType::RegularFile,
-        libc::S_IFBLK => FileType::BlockDevice,
-        libc::S_IFDIR => FileType::Directory,
-        libc::S_IFCHR => FileType::CharDevice,
-        libc::S_IFIFO => FileType::NamedPipe,
-        _ => {
-            warn! {"Unrecognised file type {:o} for inode {:x}", stat.st_mode, stat.st_ino};
-            FileType::RegularFile
+    // Return the backing name of the entry of directory inode, at path, which
+    // is presented as name, if it isn't name itself. A name which isn't
+    // presented by any entry is used as given, so new files are created with
+    // the name they were given.
+    fn backing_name(
+        &mut self,
+        inode: Inode,
+        path: &Path,
+        name: &OsStr,
+    ) -> io::Result<Option<OsString>> {
+        if !self.config.case_insensitive && self.renamer.is_empty() {
+            return Ok(None);
         }
-    };
 
-    fn get_system_time(time: i64) -> SystemTime {
+        // Without rename rules a name which exists is presented as itself
+        if self.renamer.is_empty() {
+            let path = path.join(name);
+            match stat_at(self.branch(&path), &path) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => (),
+                _ => return Ok(None),
+            }
+        }
+
+        let handle = self.handle(inode)?;
+        let stat = fstat(&handle)?;
+        let mtime = (stat.st_mtime as i64, stat.st_mtime_nsec as i64);
+        if !self.name_index.is_current(inode.0, mtime) {
+            let dir = reopen(&handle, libc::O_RDONLY | libc::O_DIRECTORY)?;
+            let mut names = Vec::new();
+            for entry in DirStream::new(dir)? {
+                let name = entry?.name;
+                names.push((self.name_key(&self.renamer.present(&name)), name));
+            }
+            self.name_index.insert(inode.0, mtime, names);
+        }
+        Ok(self.name_index.find(inode.0, &self.name_key(name)))
+    }
+
+    // The key by which a presented name is found in name_index
+    fn name_key(&self, name: &OsStr) -> Vec<u8> {
+        if self.config.case_insensitive {
+            fold_case(name)
+        } else {
+            name.as_bytes().to_vec()
+        }
+    }
+
+    // Return a path relative to root which refers to the directory inode.
+    //
+    // The backing tree may have been modified behind our back since we
+    // recorded its paths, so a path may now refer to a different file. We
+    // check each path against the inode's handle and forget any which don't
+    // match. If none of them match we return ESTALE rather than operating on
+    // the wrong directory.
+    fn dir_path(&mut self, inode: Inode) -> io::Result<PathBuf> {
+        if inode == Inode(1) {
+            return Ok(PathBuf::new());
+        }
+
+        let inode_entry = self
+            .inode_map
+            .get(&inode)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+
+        // A deleted directory has no paths, and nothing can be created in it
+        if inode_entry.paths.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let id = backing_id(&inode_entry.handle)?;
+        let mut stale = Vec::new();
+        let mut found = None;
+        for path in &inode_entry.paths {
+            match stat_at(self.branch(path), path) {
+                Ok(stat) if BackingId::from_stat(&stat) == id => {
+                    found = Some(path.clone());
+                    break;
+                }
+                _ => stale.push(path.clone()),
+            }
+        }
+
+        if let Some(inode_entry) = self.inode_map.get_mut(&inode) {
+            for path in stale {
+                debug!("inode={}: {:?} is stale", inode.0, path);
+                inode_entry.remove_path(&path);
+            }
+        }
+        found.ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))
+    }
+
+    // Reply to a lookup of a name which doesn't exist. If negative caching is
+    // enabled we return an entry with inode 0, which the kernel caches as a
+    // name which doesn't exist.
+    fn reply_not_found(&self, reply: Metered<ReplyEntry>) {
+        if self.config.negative_timeout.is_zero() {
+            return reply.error(libc::ENOENT);
+        }
+
+        let fileattr = FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            padding: 0,
+            flags: 0,
+        };
+        reply.entry(&self.config.negative_timeout, &fileattr, 0)
+    }
+
+    // Record a read of size bytes at offset on fh, and return the range we
+    // should prefetch, if any. Once a handle is being read sequentially we
+    // keep the prefetched range a full window ahead of the reader, topping it
+    // up whenever the reader gets within half a window of its end.
+    fn read_ahead(&mut self, fh: Fh, offset: u64, size: u32) -> Option<(u64, u64)> {
+        let window = self.config.read_ahead;
+        if window == 0 {
+            return None;
+        }
+
+        let state = self.read_ahead.entry(fh).or_default();
+        let sequential = offset == state.next_offset;
+        state.next_offset = offset + size as u64;
+        if !sequential {
+            state.prefetched = 0;
+            return None;
+        }
+
+        let end = state.next_offset;
+        if state.prefetched >= end + window / 2 {
+            return None;
+        }
+
+        let start = state.prefetched.max(end);
+        state.prefetched = end + window;
+        Some((start, state.prefetched - start))
+    }
+
+    // Map file for reading if it is at least the configured size, and callers
+    // can't truncate it under the mapping, which would kill us with SIGBUS
+    fn map_file(&self, file: &File) -> Option<Mapping> {
+        let threshold = self.config.mmap_threshold;
+        if threshold == 0 || self.config.read_write {
+            return None;
+        }
+
+        let size = match fstat(file) {
+            Ok(stat) => stat.st_size as u64,
+            Err(_) => return None,
+        };
+        if size < threshold || size > usize::MAX as u64 {
+            return None;
+        }
+
+        // Sequential advice makes the kernel read ahead aggressively
+        let advice = if self.config.read_ahead > 0 {
+            libc::MADV_SEQUENTIAL
+        } else {
+            libc::MADV_NORMAL
+        };
+        match Mapping::new(file, size as usize, advice) {
+            Ok(mapping) => Some(mapping),
+            Err(err) => {
+                debug!("mmap: {}", err);
+                None
+            }
+        }
+    }
+
+    // The flags to return to the kernel when opening a file.
+    //
+    // Kernels from 6.9 can pass reads and writes straight through to a backing
+    // file registered with FUSE_DEV_IOC_BACKING_OPEN, if the open reply
+    // carries FOPEN_PASSTHROUGH and the backing id. fuser neither exposes the
+    // FUSE device nor has a backing id in its open reply, so every read and
+    // write comes through us.
+    fn open_flags(&self) -> u32 {
+        match self.config.cache_mode {
+            CacheMode::Auto => 0,
+            CacheMode::Keep => consts::FOPEN_KEEP_CACHE,
+            CacheMode::Direct => consts::FOPEN_DIRECT_IO,
+        }
+    }
+
+    // Return the O_PATH handle of inode. The kernel should never refer to an
+    // inode we don't know about.
+    fn handle(&self, inode: Inode) -> io::Result<Arc<File>> {
+        if let Some(inode_entry) = self.inode_map.get(&inode) {
+            return Ok(Arc::clone(&inode_entry.handle));
+        }
+
+        // A file in the snapshot is opened by the path it had, unless it was
+        // pinned
+        match self
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.get(inode.0))
+        {
+            Some(node) => match &node.handle {
+                Some(handle) => Ok(Arc::clone(handle)),
+                None => Ok(Arc::new(self.open_handle(&node.path)?.0)),
+            },
+            None => Err(io::Error::from_raw_os_error(libc::ESTALE)),
+        }
+    }
+
+    // Open an O_PATH handle for path without following a trailing symlink, and
+    // return it with its stat
+    fn open_handle(&self, path: &Path) -> io::Result<(File, stat)> {
+        let handle = self.open_at(path, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+        let stat = fstat(&handle)?;
+        Ok((handle, stat))
+    }
+
+    // Open an O_PATH handle for the entry at path, whose last component is in
+    // the directory parent, without following a trailing symlink, and return
+    // it with its stat. Unless directories are merged from several branches,
+    // the entry is opened by name relative to the handle of parent rather
+    // than by its whole path, so renaming or replacing a directory above it
+    // between our looking up parent and the entry can't make us open
+    // something else.
+    fn open_child(&self, parent: u64, path: &Path) -> io::Result<(File, stat)> {
+        let name = match path.file_name() {
+            Some(name) if self.branches.is_empty() => cstr(Path::new(name))?,
+            _ => return self.open_handle(path),
+        };
+        let parent = self.handle(Inode(parent))?;
+
+        // name is a single component, so it can't lead outside parent
+        let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = trace_span!("openat")
+            .in_scope(|| unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), flags) });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let handle = unsafe { File::from_raw_fd(fd) };
+        let stat = fstat(&handle)?;
+        Ok((handle, stat))
+    }
+
+    // Record a new kernel reference to the inode with stat found at path, and
+    // return its attributes and generation. This must be called exactly once
+    // for every entry returned to the kernel. handle is kept if this is the
+    // first reference to the inode.
+    fn ref_inode(&mut self, handle: File, stat: &stat, path: PathBuf) -> (FileAttr, u64) {
+        let mut ino = self.inode_numbers.get(stat);
+
+        // The file we know by this inode number may have been deleted by
+        // something other than us, and its backing inode number reused by the
+        // file we found. We retire it as we would have if we had deleted it,
+        // which gives the new file either a new generation or a new synthetic
+        // inode number.
+        let reused = ino != 1
+            && matches!(self.inode_map.get(&Inode(ino)), Some(inode_entry)
+                if replaced(&inode_entry.handle, stat));
+        let mut handle = Some(handle);
+        if reused {
+            self.inode_numbers.retire(stat);
+            let retired = std::mem::replace(&mut ino, self.inode_numbers.get(stat));
+            debug!("inode={}: reused by a new file as inode={}", retired, ino);
+
+            // With the same inode number, the kernel holds on to its
+            // references until it sees the new generation, but everything
+            // else we know about the inode is of the deleted file
+            if let Some(inode_entry) = self.inode_map.get_mut(&Inode(ino)) {
+                inode_entry.handle = Arc::new(handle.take().unwrap());
+                inode_entry.paths.clear();
+                self.forget_cached(ino);
+            }
+        }
+
+        if let Some((key, _)) = self.file_handles.remove(&Inode(ino)) {
+            self.file_handle_lru.remove(&key);
+        }
+        let inode_entry = self
+            .inode_map
+            .entry(Inode(ino))
+            .and_modify(|inode_entry| inode_entry.nlookup += 1)
+            .or_insert_with(|| InodeEntry::new(1, handle.take().unwrap(), path.clone()));
+
+        // Another hard link may have brought us here
+        inode_entry.add_path(path);
+        debug!("lookup inode={}: nlookup={}", ino, inode_entry.nlookup);
+
+        // An inode the kernel had forgotten can't be evicted once it is
+        // referred to again
+        let lru_key = std::mem::take(&mut inode_entry.lru_key);
+        self.inode_lru.remove(&lru_key);
+
+        let mut fileattr = stat_to_fileattr(ino, stat);
+        self.attr_map.apply(&mut fileattr);
+        (fileattr, self.inode_numbers.generation(ino))
+    }
+
+    // Return the transformed content of inode and its size, if a transform
+    // applies to it, producing it here if it hasn't been already
+    fn transformed(&mut self, inode: Inode) -> io::Result<Option<(Arc<File>, u64)>> {
+        if !self.transforms.applies(inode.0) {
+            return Ok(None);
+        }
+        let handle = self.handle(inode)?;
+        self.transforms.get(inode.0, &handle)
+    }
+
+    // The size of the transformed content of inode, if a transform applies
+    // to it and the size is known without producing the content. Until it
+    // is, the file is presented with the size of its backing file.
+    fn transformed_size(&self, inode: Inode) -> io::Result<Option<u64>> {
+        if !self.transforms.applies(inode.0) {
+            return Ok(None);
+        }
+        let handle = self.handle(inode)?;
+        self.transforms.size(inode.0, &handle)
+    }
+
+    // Truncate the transformed content of inode to size, if given, and write
+    // it back. Returns the size of the content.
+    fn truncate_transformed(&mut self, inode: Inode, size: Option<u64>) -> io::Result<Option<u64>> {
+        let (file, current) = match self.transformed(inode)? {
+            Some(transformed) => transformed,
+            None => return Ok(None),
+        };
+        let size = match size {
+            Some(size) => size,
+            None => return Ok(Some(current)),
+        };
+
+        self.transforms.set_len(inode.0, &file, size)?;
+        self.write_back(inode)?;
+        Ok(Some(size))
+    }
+
+    // Store any content written to transformed inode in its backing file
+    fn write_back(&mut self, inode: Inode) -> io::Result<()> {
+        self.copy_up_inode(inode)?;
+        let encoded = match self.transforms.encode(inode.0)? {
+            Some(encoded) => encoded,
+            None => return Ok(()),
+        };
+        let handle = self.replace_content(inode, &encoded)?;
+        self.transforms.stored(inode.0, &handle)
+    }
+
+    // Replace the content of the backing file of regular file inode with
+    // content, and return its new handle. The content is written to a new
+    // file which is renamed over the old one, so that a failure part way
+    // leaves the old content as it was. A file with other links is written
+    // in place instead, as the new file would be split from them.
+    fn replace_content(&mut self, inode: Inode, content: &File) -> io::Result<Arc<File>> {
+        let path = self.dir_path(inode)?;
+        let branch = self.branch(&path);
+        let stat = stat_at(branch, &path)?;
+        let mut input = reopen(content, libc::O_RDONLY)?;
+        if stat.st_nlink > 1 {
+            let flags = libc::O_WRONLY | libc::O_TRUNC | libc::O_NOFOLLOW;
+            io::copy(&mut input, &mut open_beneath(branch, &path, flags, 0)?)?;
+            return self.handle(inode);
+        }
+
+        let mut temp = OsString::from(".");
+        temp.push(path.file_name().unwrap_or_default());
+        temp.push(format!(".passfs-{}", std::process::id()));
+        let temp = path.with_file_name(temp);
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+        let mut output = open_beneath(branch, &temp, flags, 0o600)?;
+        let result = io::copy(&mut input, &mut output)
+            .and_then(|_| {
+                let fd = output.as_raw_fd();
+                match check_ret(unsafe { libc::fchown(fd, stat.st_uid, stat.st_gid) }) {
+                    Err(err) if err.raw_os_error() == Some(libc::EPERM) => (),
+                    result => result?,
+                }
+                check_ret(unsafe { libc::fchmod(fd, stat.st_mode & 0o7777) })
+            })
+            .and_then(|()| output.sync_data())
+            .and_then(|()| {
+                let (ctemp, cpath) = (cstr(&temp)?, cstr(&path)?);
+                let fd = branch.as_raw_fd();
+                check_ret(unsafe { renameat2(fd, ctemp.as_ptr(), fd, cpath.as_ptr(), 0) })
+            });
+        if let Err(err) = result {
+            let _ = branch.remove_file(&temp);
+            return Err(err);
+        }
+
+        // The new file keeps the inode number of the one it replaced
+        let (handle, stat) = self.open_handle(&path)?;
+        self.inode_numbers.alias(&stat, inode.0);
+        let handle = Arc::new(handle);
+        if let Some(inode_entry) = self.inode_map.get_mut(&inode) {
+            inode_entry.handle = Arc::clone(&handle);
+        }
+        self.fd_cache.forget(inode.0);
+        Ok(handle)
+    }
+
+    // Look up inode ino for nfsd, or its parent directory if parent is set,
+    // adding a reference like any other lookup, and return its attributes and
+    // generation. nfsd may ask for an inode which the kernel has forgotten,
+    // which is reopened by its file handle. A file handle is no way around
+    // the filter, the access policies or permission checks, so the inode is
+    // only found if it could be reached by its path.
+    fn export_lookup(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        parent: bool,
+    ) -> io::Result<(FileAttr, u64)> {
+        let ino = if parent {
+            let path = self.dir_path(Inode(ino))?;
+            match path.parent().filter(|parent| *parent != Path::new("")) {
+                Some(parent) => {
+                    let (handle, stat) = self.open_handle(parent)?;
+                    self.check_export(req, parent, &stat)?;
+                    return Ok(self.export_ref(handle, &stat, parent.to_path_buf()));
+                }
+                None => 1,
+            }
+        } else {
+            ino
+        };
+
+        // Nothing holds references to the root, virtual files or the stats
+        // directory, which are never forgotten
+        let generation = self.inode_numbers.generation(ino);
+        if let Some(mut fileattr) = self.virtual_files.attr(ino) {
+            self.attr_map.apply(&mut fileattr);
+            return Ok((fileattr, generation));
+        }
+        if let Some(fileattr) = self.stats_dir.as_ref().and_then(|dir| dir.attr(ino)) {
+            return Ok((fileattr, generation));
+        }
+        if ino == 1 {
+            let handle = self.handle(Inode(1))?;
+            let mut fileattr = file_attr(ino, &handle)?;
+            self.attr_map.apply(&mut fileattr);
+            return Ok((fileattr, generation));
+        }
+
+        let (handle, path) = match self.inode_map.get(&Inode(ino)) {
+            Some(inode_entry) => {
+                let handle = inode_entry.handle.try_clone()?;
+                (handle, self.dir_path(Inode(ino))?)
+            }
+            None => {
+                let (_, file_handle) = self
+                    .file_handles
+                    .get(&Inode(ino))
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ESTALE))?;
+                let handle = open_by_handle(&self.root, file_handle)?;
+                let path = handle_path(&self.root, &handle)?;
+                (handle, path)
+            }
+        };
+
+        // The file handle may now belong to a different file with the same
+        // inode number, and the path to yet another
+        let stat = fstat(&handle)?;
+        let same_file = matches!(stat_at(&self.root, &path), Ok(found)
+            if BackingId::from_stat(&found) == BackingId::from_stat(&stat));
+        if self.inode_numbers.get(&stat) != ino || !same_file {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+        self.check_export(req, &path, &stat)?;
+        Ok(self.export_ref(handle, &stat, path))
+    }
+
+    // Check that the caller of req could look up the file with stat at path,
+    // for export_lookup. A file hidden by the filter is as good as gone.
+    fn check_export(&mut self, req: &Request<'_>, path: &Path, stat: &stat) -> io::Result<()> {
+        let kind = mode_file_type(stat.st_mode).unwrap_or(FileType::RegularFile);
+        if !self.filter.visible(path, kind) {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+        if !self.policies.is_empty()
+            && !self
+                .policies
+                .may_lookup(&Caller::new(req), path, kind == FileType::Directory)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        if self.config.check_permissions != PermissionCheck::Daemon {
+            return Ok(());
+        }
+        for dir in path.ancestors().skip(1) {
+            let fileattr = if dir == Path::new("") {
+                self.presented_attr(1)?
+            } else {
+                let stat = stat_at(&self.root, dir)?;
+                let mut fileattr = stat_to_fileattr(self.inode_numbers.get(&stat), &stat);
+                self.attr_map.apply(&mut fileattr);
+                fileattr
+            };
+            if !permitted(req, &fileattr, libc::X_OK) {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+        }
+        Ok(())
+    }
+
+    // Record a reference to the inode with stat found at path for nfsd, as
+    // lookup does
+    fn export_ref(&mut self, handle: File, stat: &stat, path: PathBuf) -> (FileAttr, u64) {
+        let ino = self.inode_numbers.get(stat);
+        if mode_file_type(stat.st_mode) == Some(FileType::RegularFile) {
+            self.transforms.attach(ino, &path);
+        }
+        let size = self.transforms.size(ino, &handle).ok().flatten();
+        let (mut fileattr, generation) = self.ref_inode(handle, stat, path);
+        if let Some(size) = size {
+            set_size(&mut fileattr, size);
+        }
+        (fileattr, generation)
+    }
+
+    // Remember the file handle of inode, which is leaving inode_map, if nfsd
+    // may look it up again
+    fn keep_file_handle(&mut self, inode: Inode, handle: &File) {
+        if !self.config.nfs_export {
+            return;
+        }
+        match file_handle(handle) {
+            Ok(file_handle) => {
+                let key = self.next_file_handle;
+                self.next_file_handle += 1;
+                if let Some((old, _)) = self.file_handles.insert(inode, (key, file_handle)) {
+                    self.file_handle_lru.remove(&old);
+                }
+                self.file_handle_lru.insert(key, inode);
+            }
+            Err(err) => return warn!("inode={}: unable to get file handle: {}", inode.0, err),
+        }
+        while self.file_handles.len() > MAX_FILE_HANDLES {
+            match self.file_handle_lru.pop_first() {
+                Some((_, oldest)) => self.file_handles.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    // Remove the inodes which the kernel forgot longest ago until inode_map
+    // is within max_inodes, or there are none left which it has forgotten.
+    // Inodes the kernel still refers to are never evicted.
+    fn evict_inodes(&mut self) {
+        let max_inodes = self.config.max_inodes;
+        while self.inode_map.len() > max_inodes {
+            let (_, inode) = match self.inode_lru.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(inode_entry) = self.inode_map.remove(&inode) {
+                debug!(
+                    "evict inode={}: {} of {} inodes in use",
+                    inode.0,
+                    self.inode_map.len(),
+                    max_inodes
+                );
+                self.keep_file_handle(inode, &inode_entry.handle);
+            }
+            self.forget_cached(inode.0);
+        }
+    }
+
+    // Drop everything we have cached about inode ino
+    fn forget_cached(&mut self, ino: u64) {
+        self.fd_cache.forget(ino);
+        self.dir_cache.invalidate(ino);
+        self.name_index.forget(ino);
+        self.transforms.forget(ino);
+        self.checksums.forget(ino);
+    }
+
+    // openat() relative to the branch containing path. Unlike Dir::open_file
+    // this lets us pass arbitrary flags and a creation mode.
+    fn open_at(&self, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+        open_beneath(self.branch(path), path, flags, mode)
+    }
+
+    // Return the branch which path, relative to root, refers to, or root if
+    // it exists nowhere
+    fn branch(&self, path: &Path) -> &Dir {
+        self.find_branch(path).unwrap_or(&self.root)
+    }
+
+    // Return the branch containing path, chosen by search_policy if it is in
+    // several. The upper directory is always chosen if it contains path.
+    fn find_branch(&self, path: &Path) -> Option<&Dir> {
+        if self.branches.is_empty() || path.as_os_str().is_empty() {
+            return Some(&self.root);
+        }
+
+        // Whiteouts only hide what is in lower branches
+        let whited_out = self.whited_out(path);
+        let mut found = std::iter::once(&self.root)
+            .chain(&self.branches)
+            .filter(|branch| {
+                (std::ptr::eq(*branch, &self.root) || !whited_out) && stat_at(branch, path).is_ok()
+            });
+        match self.config.search_policy {
+            // The last maximum is returned, so reverse to prefer earlier
+            // branches
+            BranchPolicy::MostFreeSpace if self.config.upper.is_none() => {
+                found.rev().max_by_key(|branch| free_space(branch))
+            }
+            _ => found.next(),
+        }
+    }
+
+    // Choose the branch in which to create path, which doesn't exist, by
+    // create_policy, and copy the directories leading to it into that branch
+    // if they are only in others. With an upper directory, that is always
+    // where it is created.
+    fn create_branch(&self, path: &Path) -> io::Result<&Dir> {
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut branches = std::iter::once(&self.root).chain(&self.branches);
+        let branch = match self.config.create_policy {
+            _ if self.config.upper.is_some() => &self.root,
+            BranchPolicy::FirstFound => &self.root,
+            BranchPolicy::MostFreeSpace => branches
+                .rev()
+                .max_by_key(|branch| free_space(branch))
+                .unwrap_or(&self.root),
+            BranchPolicy::PathPreserving => branches
+                .find(|branch| parent.as_os_str().is_empty() || stat_at(branch, parent).is_ok())
+                .unwrap_or(&self.root),
+        };
+        self.clone_path(branch, parent)?;
+        Ok(branch)
+    }
+
+    // Copy path and any of its ancestors which are missing from target into
+    // it, from the branches containing them. Nothing is copied if path
+    // doesn't exist, but its ancestors must.
+    fn clone_path(&self, target: &Dir, path: &Path) -> io::Result<()> {
+        let mut partial = PathBuf::new();
+        for component in path.components() {
+            partial.push(component);
+            if stat_at(target, &partial).is_ok() {
+                continue;
+            }
+            let source = match self.find_branch(&partial) {
+                Some(source) if !std::ptr::eq(source, target) => source,
+                _ => break,
+            };
+            debug!("copy {:?} between branches", partial);
+            copy_file(source, target, &partial)?;
+        }
+        Ok(())
+    }
+
+    // Whether path is in a branch below the upper directory, so it must be
+    // copied up before it is modified
+    fn is_lower(&self, path: &Path) -> bool {
+        self.config.upper.is_some() && !std::ptr::eq(self.branch(path), &self.root)
+    }
+
+    // Whether path exists in a lower branch, and isn't hidden by a whiteout
+    fn lower_exists(&self, path: &Path) -> bool {
+        self.config.upper.is_some()
+            && !self.whited_out(path)
+            && self
+                .branches
+                .iter()
+                .any(|branch| stat_at(branch, path).is_ok())
+    }
+
+    // Whether path in lower branches is hidden by a whiteout of it or of any
+    // of its ancestors, or because an ancestor is opaque. Whiteouts are only
+    // used with an upper directory.
+    fn whited_out(&self, path: &Path) -> bool {
+        if self.config.upper.is_none() {
+            return false;
+        }
+
+        let mut parent = PathBuf::new();
+        for component in path.components() {
+            let whiteout = parent.join(whiteout_name(component.as_os_str()));
+            if stat_at(&self.root, &parent.join(OPAQUE_MARKER)).is_ok()
+                || stat_at(&self.root, &whiteout).is_ok()
+            {
+                return true;
+            }
+            parent.push(component);
+        }
+        false
+    }
+
+    // Hide path in lower branches
+    fn whiteout(&self, path: &Path) -> io::Result<()> {
+        debug!("whiteout {:?}", path);
+        self.copy_up_parent(path)?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW;
+        open_beneath(&self.root, &whiteout_path(path), flags, 0o600)?;
+        Ok(())
+    }
+
+    // Called when a new entry has been put at path in the upper directory,
+    // to remove any whiteout of path. A new directory is made opaque if it
+    // replaces a directory in a lower branch, so their entries aren't merged.
+    fn cover_lower(&self, path: &Path, is_dir: bool) {
+        if self.config.upper.is_none() {
+            return;
+        }
+
+        let result = match self.root.remove_file(&whiteout_path(path)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(err) => Err(err),
+        }
+        .and_then(|whited_out| {
+            if is_dir && (whited_out || self.lower_exists(path)) {
+                let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW;
+                open_beneath(&self.root, &path.join(OPAQUE_MARKER), flags, 0o600)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("Unable to cover lower branches at {:?}: {}", path, err);
+        }
+    }
+
+    // Remove the directory at path when we have an upper directory. It must
+    // be empty in every branch, ignoring anything whited out. Whiteouts in it
+    // are removed with it, and it is whited out if it is in a lower branch.
+    fn remove_overlay_dir(&self, path: &Path) -> io::Result<()> {
+        let mut hidden = BTreeSet::new();
+        for dir in self.branch_dirs(path)? {
+            for entry in DirStream::new(dir)? {
+                let name = entry?.name;
+                match whiteout_target(&name) {
+                    Some(target) => {
+                        hidden.insert(target.to_os_string());
+                    }
+                    None if !hidden.contains(&name) => {
+                        return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY))
+                    }
+                    None => (),
+                }
+            }
+        }
+
+        let lower = self.lower_exists(path);
+        if !self.is_lower(path) {
+            let dir = open_beneath(&self.root, path, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+            for entry in DirStream::new(dir)? {
+                self.root.remove_file(&path.join(entry?.name))?;
+            }
+            self.root.remove_dir(path)?;
+        }
+        if lower {
+            self.whiteout(path)?;
+        }
+        Ok(())
+    }
+
+    // Copy path and any of its ancestors which are only in lower branches
+    // into the upper directory. Nothing is copied if path doesn't exist, but
+    // its ancestors must.
+    fn copy_up(&self, path: &Path) -> io::Result<()> {
+        if self.config.upper.is_none() {
+            return Ok(());
+        }
+        self.clone_path(&self.root, path)
+    }
+
+    fn copy_up_parent(&self, path: &Path) -> io::Result<()> {
+        path.parent().map_or(Ok(()), |parent| self.copy_up(parent))
+    }
+
+    // Copy inode up into the upper directory if it is in a lower branch, and
+    // replace its handle with one for the copy, which keeps its inode number
+    fn copy_up_inode(&mut self, inode: Inode) -> io::Result<()> {
+        if self.config.upper.is_none() || inode == Inode(1) {
+            return Ok(());
+        }
+
+        let path = self.dir_path(inode)?;
+        if !self.is_lower(&path) {
+            return Ok(());
+        }
+        self.copy_up(&path)?;
+
+        let (handle, stat) = self.open_handle(&path)?;
+        self.inode_numbers.alias(&stat, inode.0);
+        if let Some(inode_entry) = self.inode_map.get_mut(&inode) {
+            inode_entry.handle = Arc::new(handle);
+        }
+        self.fd_cache.forget(inode.0);
+        Ok(())
+    }
+
+    // Open the directory at path, relative to root, in every branch in which
+    // it exists, in order of priority
+    fn branch_dirs(&self, path: &Path) -> io::Result<Vec<File>> {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+
+        // Lower branches are hidden by a whiteout, or below an opaque
+        // directory
+        let hidden = self.whited_out(path)
+            || (self.config.upper.is_some()
+                && stat_at(&self.root, &path.join(OPAQUE_MARKER)).is_ok());
+        let branches = if hidden { &[] } else { &self.branches[..] };
+
+        let mut dirs = Vec::new();
+        for branch in std::iter::once(&self.root).chain(branches) {
+            match open_beneath(branch, path, libc::O_RDONLY | libc::O_DIRECTORY, 0) {
+                Ok(dir) => dirs.push(dir),
+                Err(err)
+                    if err.raw_os_error() == Some(libc::ENOENT)
+                        || err.raw_os_error() == Some(libc::ENOTDIR) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(dirs)
+    }
+
+    // Update the path of every inode at or below from to the equivalent path
+    // below to. If exchange is set, inodes below to are also moved to from.
+    fn move_paths(&mut self, from: &Path, to: &Path, exchange: bool) {
+        fn reparent(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+            let rest = path.strip_prefix(from).ok()?;
+            if rest.as_os_str().is_empty() {
+                Some(to.to_path_buf())
+            } else {
+                Some(to.join(rest))
+            }
+        }
+
+        for inode_entry in self.inode_map.values_mut() {
+            for known in inode_entry.paths.iter_mut() {
+                let path = reparent(known, from, to).or_else(|| {
+                    if exchange {
+                        reparent(known, to, from)
+                    } else {
+                        None
+                    }
+                });
+                if let Some(path) = path {
+                    debug!("rename: {:?} -> {:?}", known, path);
+                    *known = path;
+                }
+            }
+        }
+    }
+
+    // Read the whole of the directory inode, as readdir would return it
+    fn list_dir(&mut self, inode: Inode) -> io::Result<Vec<ListedEntry>> {
+        let mut listing = Vec::new();
+        if inode == Inode(1) {
+            for (ino, name) in self.virtual_files.entries() {
+                listing.push(ListedEntry {
+                    ino,
+                    kind: FileType::RegularFile,
+                    name: name.to_os_string(),
+                });
+            }
+            if let Some(stats_dir) = &self.stats_dir {
+                listing.push(ListedEntry {
+                    ino: stats_dir.ino(),
+                    kind: FileType::Directory,
+                    name: STATS_DIR.into(),
+                });
+            }
+        }
+
+        if let Some(snapshot) = &self.snapshot {
+            for (name, ino) in snapshot.children(inode.0) {
+                if inode == Inode(1) && self.reserved_name(name) {
+                    continue;
+                }
+                let stat = snapshot.get(ino).map(|node| node.stat);
+                let kind = stat
+                    .and_then(|stat| mode_file_type(stat.st_mode))
+                    .unwrap_or(FileType::RegularFile);
+                listing.push(ListedEntry {
+                    ino,
+                    kind,
+                    name: name.to_os_string(),
+                });
+            }
+            return Ok(listing);
+        }
+
+        let dir_path = self.listing_dir_path(inode)?;
+        if dir_path.as_deref().is_some_and(|path| self.too_deep(path)) {
+            return Ok(listing);
+        }
+
+        // A directory is merged from every branch containing it. An entry
+        // hides any with the same backing name in a later branch.
+        let dirs = if self.branches.is_empty() {
+            let handle = self.handle(inode)?;
+            vec![reopen(&handle, libc::O_RDONLY | libc::O_DIRECTORY)?]
+        } else {
+            let path = self.dir_path(inode)?;
+            self.branch_dirs(&path)?
+        };
+        let mut seen = BTreeSet::new();
+        for dir in dirs {
+            self.list_branch_dir(inode, dir, dir_path.as_deref(), &mut seen, &mut listing)?;
+        }
+        Ok(listing)
+    }
+
+    // Add the entries of dir, the part of directory inode in one branch, to
+    // listing, skipping any whose names are in seen
+    fn list_branch_dir(
+        &mut self,
+        inode: Inode,
+        dir: File,
+        dir_path: Option<&Path>,
+        seen: &mut BTreeSet<OsString>,
+        listing: &mut Vec<ListedEntry>,
+    ) -> io::Result<()> {
+        let dev = fstat(&dir)?.st_dev;
+        let mut stream = DirStream::new(dir)?;
+        let mut entries = stream.by_ref().collect::<io::Result<Vec<_>>>()?;
+        // Whiteouts aren't listed, but hide entries in later branches
+        let upper = self.config.upper.is_some();
+        entries.retain(|entry| match whiteout_target(&entry.name) {
+            Some(target) if upper => {
+                seen.insert(target.to_os_string());
+                false
+            }
+            _ => seen.insert(entry.name.clone()),
+        });
+        let stats = stat_entries(&self.workers, &self.config, stream.fd(), &entries);
+
+        for (entry, stat) in entries.into_iter().zip(stats) {
+            let listed = list_entry(&mut self.inode_numbers, &self.config, dev, &entry, stat)?;
+            if let Some((ino, kind)) = listed {
+                if let Some(dir_path) = dir_path {
+                    let path = dir_path.join(&entry.name);
+                    if !self.filter.visible(&path, kind) {
+                        continue;
+                    }
+                    if self.config.hide_empty_dirs
+                        && kind == FileType::Directory
+                        && is_empty_dir(
+                            &mut self.inode_numbers,
+                            &self.config,
+                            &self.workers,
+                            &self.filter,
+                            stream.fd(),
+                            &entry.name,
+                            &path,
+                        )
+                    {
+                        continue;
+                    }
+                }
+                let name = self.renamer.present(&entry.name).into_owned();
+                if inode == Inode(1) && self.reserved_name(&name) {
+                    continue;
+                }
+                listing.push(ListedEntry { ino, kind, name });
+            }
+        }
+        Ok(())
+    }
+
+    // The path of directory inode if we need it to filter its entries, to
+    // limit depth or to hide empty directories
+    fn listing_dir_path(&mut self, inode: Inode) -> io::Result<Option<PathBuf>> {
+        if self.filter.is_empty() && self.config.max_depth.is_none() && !self.config.hide_empty_dirs
+        {
+            return Ok(None);
+        }
+        self.dir_path(inode).map(Some)
+    }
+
+    // Whether path, relative to root, is deeper than max_depth. A directory
+    // which is too deep appears empty, so nothing below it can be looked up.
+    fn too_deep(&self, path: &Path) -> bool {
+        self.config
+            .max_depth
+            .is_some_and(|max_depth| path.components().count() > max_depth)
+    }
+
+    // With writeback caching the kernel may read from a file opened write-only
+    // in order to fill the page cache, and it tracks the file size itself so
+    // it will send appends with the correct offset.
+    fn writeback_flags(&self, flags: i32) -> i32 {
+        if !self.config.writeback_cache {
+            return flags;
+        }
+
+        let flags = flags & !libc::O_APPEND;
+        if flags & libc::O_ACCMODE == libc::O_WRONLY {
+            (flags & !libc::O_ACCMODE) | libc::O_RDWR
+        } else {
+            flags
+        }
+    }
+}
+
+impl Filesystem for PassFs {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        config: &mut KernelConfig,
+    ) -> std::result::Result<(), libc::c_int> {
+        if self.config.read_write && self.config.writeback_cache {
+            if let Err(unsupported) = config.add_capabilities(consts::FUSE_WRITEBACK_CACHE) {
+                info!(
+                    "Kernel does not support writeback caching ({:x}): using write-through",
+                    unsupported
+                );
+                self.config.writeback_cache = false;
+            }
+        }
+
+        // This asks the kernel to send lookups of . and .. when nfsd has a
+        // file handle for an inode it no longer has in its cache
+        if self.config.nfs_export {
+            if let Err(unsupported) = config.add_capabilities(consts::FUSE_EXPORT_SUPPORT) {
+                warn!(
+                    "Kernel does not support NFS export ({:x}): nfsd may fail with ESTALE",
+                    unsupported
+                );
+            }
+        }
+
+        tune("max_write", self.config.max_write, |value| {
+            config.set_max_write(value)
+        });
+        tune("max_readahead", self.config.max_readahead, |value| {
+            config.set_max_readahead(value)
+        });
+        tune("max_background", self.config.max_background, |value| {
+            config.set_max_background(value)
+        });
+        tune(
+            "congestion_threshold",
+            self.config.congestion_threshold,
+            |value| config.set_congestion_threshold(value),
+        );
+        Ok(())
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let reply = self.meter(req, Op::Getattr, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Getattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows_lookup(req, ino) {
+            return reply.deny(Denial::Policy);
+        }
+        if let Some(mut fileattr) = self.virtual_files.attr(ino) {
+            self.attr_map.apply(&mut fileattr);
+            return reply.attr(&self.config.attr_timeout, &fileattr);
+        }
+        if let Some(fileattr) = self.stats_dir.as_ref().and_then(|dir| dir.attr(ino)) {
+            return reply.attr(&Duration::ZERO, &fileattr);
+        }
+
+        match self.snapshot_attr(Inode(ino)) {
+            Ok(Some(fileattr)) => return reply.attr(&self.config.attr_timeout, &fileattr),
+            Ok(None) => (),
+            Err(err) => return reply.error(errno(&err)),
+        }
+
+        let transformed_size = match self.transformed_size(Inode(ino)) {
+            Ok(size) => size,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        let handle = match self.handle(Inode(ino)) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        let ttl = self.config.attr_timeout;
+        let attr_map = Arc::clone(&self.attr_map);
+        self.workers.run(move || match file_attr(ino, &handle) {
+            Ok(mut fileattr) => {
+                if let Some(size) = transformed_size {
+                    set_size(&mut fileattr, size);
+                }
+                attr_map.apply(&mut fileattr);
+                reply.attr(&ttl, &fileattr)
+            }
+            Err(err) => reply.error(errno(&err)),
+        });
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let reply = self.meter(req, Op::Lookup, parent, Some(name), reply);
+        let reply = self.audit(req, parent, Some(name), reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Lookup, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+
+        // nfsd finds an inode by its number with ., and a directory's parent
+        // with .., which needn't be a directory the caller may search, so
+        // export_lookup checks access itself
+        if self.config.nfs_export && (name == "." || name == "..") {
+            return match self.export_lookup(req, parent, name == "..") {
+                Ok((fileattr, generation)) => {
+                    reply.entry(&self.config.entry_timeout, &fileattr, generation)
+                }
+                Err(err) => reply.error(errno(&err)),
+            };
+        }
+
+        if let Err(err) = self.check_access(req, parent, libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        // Virtual files are never forgotten, so they need no references
+        if let Some(ino) = self.virtual_files.find(name).filter(|_| parent == 1) {
+            let mut fileattr = self.virtual_files.attr(ino).unwrap();
+            self.attr_map.apply(&mut fileattr);
+            let generation = self.inode_numbers.generation(ino);
+            return reply.entry(&self.config.entry_timeout, &fileattr, generation);
+        }
+
+        // Nor is the stats directory
+        if let Some(stats_dir) = &self.stats_dir {
+            let ino = if parent == 1 && name == STATS_DIR {
+                Some(stats_dir.ino())
+            } else if parent == stats_dir.ino() {
+                match stats_dir.find(name) {
+                    Some(ino) => Some(ino),
+                    None => return reply.error(libc::ENOENT),
+                }
+            } else {
+                None
+            };
+            if let Some(fileattr) = ino.and_then(|ino| stats_dir.attr(ino)) {
+                let generation = self.inode_numbers.generation(fileattr.ino);
+                return reply.entry(&self.config.entry_timeout, &fileattr, generation);
+            }
+        }
+
+        // The snapshot is never forgotten either
+        if self.snapshot.is_some() {
+            let key = self.name_key(name);
+            let ino = self
+                .snapshot
+                .as_ref()
+                .and_then(|snapshot| snapshot.child(parent, &key));
+            return match ino.map(|ino| self.snapshot_attr(Inode(ino))) {
+                Some(Ok(Some(fileattr))) => {
+                    let generation = self.inode_numbers.generation(fileattr.ino);
+                    reply.entry(&self.config.entry_timeout, &fileattr, generation)
+                }
+                Some(Err(err)) => reply.error(errno(&err)),
+                _ => self.reply_not_found(reply),
+            };
+        }
+
+        let path = match self.lookup_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        if path.parent().is_some_and(|parent| self.too_deep(parent)) {
+            return self.reply_not_found(reply);
+        }
+
+        match self.open_child(parent, &path) {
+            Ok((handle, stat)) => {
+                if self.config.one_file_system && !self.inode_numbers.same_device(&stat) {
+                    return self.reply_not_found(reply);
+                }
+
+                let kind = mode_file_type(stat.st_mode).unwrap_or(FileType::RegularFile);
+                if !self.filter.visible(&path, kind) {
+                    reply.report(Denial::Filtered);
+                    return self.reply_not_found(reply);
+                }
+
+                if !self.policies.is_empty()
+                    && !self.policies.may_lookup(
+                        &Caller::new(req),
+                        &path,
+                        kind == FileType::Directory,
+                    )
+                {
+                    return reply.deny(Denial::Policy);
+                }
+
+                if self.config.hide_empty_dirs
+                    && kind == FileType::Directory
+                    && is_empty_dir(
+                        &mut self.inode_numbers,
+                        &self.config,
+                        &self.workers,
+                        &self.filter,
+                        handle.as_raw_fd(),
+                        OsStr::new("."),
+                        &path,
+                    )
+                {
+                    return self.reply_not_found(reply);
+                }
+
+                if self.config.oversize == Oversize::Hide && oversized(&self.config, &stat) {
+                    return self.reply_not_found(reply);
+                }
+
+                let ino = self.inode_numbers.get(&stat);
+                if kind == FileType::RegularFile {
+                    self.transforms.attach(ino, &path);
+                }
+                let size = match self.transforms.size(ino, &handle) {
+                    Ok(size) => size,
+                    Err(err) => return reply.error(errno(&err)),
+                };
+
+                let (mut fileattr, generation) = self.ref_inode(handle, &stat, path);
+                if let Some(size) = size {
+                    set_size(&mut fileattr, size);
+                }
+                reply.entry(&self.config.entry_timeout, &fileattr, generation);
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => self.reply_not_found(reply),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    // batch_forget() is not implemented because fuser's default
+    // implementation calls forget() for each node
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        if self.virtual_files.contains(ino) || self.is_stats(ino) {
+            return;
+        }
+
+        let inode = Inode(ino);
+        let remaining = if let Entry::Occupied(mut inode_entry) = self.inode_map.entry(inode) {
+            let inode_entry = inode_entry.get_mut();
+            if nlookup > inode_entry.nlookup {
+                warn!(
+                    "forget inode={}: nlookup={} exceeds {}",
+                    ino, nlookup, inode_entry.nlookup
+                );
+            }
+            inode_entry.nlookup = inode_entry.nlookup.saturating_sub(nlookup);
+            inode_entry.nlookup
+        } else {
+            return debug!("forget inode={}: doesn't exist", ino);
+        };
+
+        debug!("forget inode={}: nlookup={}", ino, remaining);
+
+        // The root inode is never looked up, so it is never forgotten either
+        if remaining == 0 && ino != 1 {
+            // With max_inodes, we keep an inode the kernel has forgotten, so
+            // that it is reused if it is looked up again, until it is evicted
+            if self.config.max_inodes > 0 {
+                if let Some(inode_entry) = self.inode_map.get_mut(&inode) {
+                    self.inode_lru.remove(&inode_entry.lru_key);
+                    inode_entry.lru_key = self.next_lru_key;
+                    self.inode_lru.insert(self.next_lru_key, inode);
+                    self.next_lru_key += 1;
+                }
+                return self.evict_inodes();
+            }
+
+            if let Some(inode_entry) = self.inode_map.remove(&inode) {
+                self.keep_file_handle(inode, &inode_entry.handle);
+            }
+            self.forget_cached(ino);
+        }
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let reply = self.meter(req, Op::Opendir, ino, None, reply);
+        let reply = self.audit(req, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Opendir, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, ino, libc::R_OK) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows(req, ino, PolicyOp::List) {
+            return reply.deny(Denial::Policy);
+        }
+
+        if let Some(stats_dir) = self.stats_dir.as_ref().filter(|dir| dir.ino() == ino) {
+            let listing = stats_dir
+                .entries()
+                .map(|(ino, name)| ListedEntry {
+                    ino,
+                    kind: FileType::RegularFile,
+                    name: name.to_os_string(),
+                })
+                .collect();
+            let fh = self.get_fh();
+            self.dir_listings.insert(fh, Arc::new(listing));
+            return reply.opened(fh.value(), 0);
+        }
+
+        // An unlisted directory can be opened, but appears to be empty
+        let unlisted = self.known_path(ino).is_some_and(|path| {
+            self.unlisted_dirs
+                .iter()
+                .any(|pattern| pattern.matches(&path, true))
+        });
+        if unlisted {
+            let fh = self.get_fh();
+            self.dir_listings.insert(fh, Arc::new(Vec::new()));
+            return reply.opened(fh.value(), 0);
+        }
+
+        // The root is always listed in full if it has virtual files, so they
+        // can be given offsets which don't clash with those of real entries.
+        // Directories merged from several branches are too, so that duplicate
+        // entries can be removed.
+        if self.dir_cache.enabled()
+            || self.snapshot.is_some()
+            || !self.branches.is_empty()
+            || (ino == 1 && (!self.virtual_files.is_empty() || self.stats_dir.is_some()))
+        {
+            let listing = match self.dir_cache.get(ino) {
+                Some(listing) => Ok(listing),
+                None => self.list_dir(Inode(ino)).map(Arc::new),
+            };
+            return match listing {
+                Ok(listing) => {
+                    self.dir_cache.insert(ino, Arc::clone(&listing));
+                    let fh = self.get_fh();
+                    self.dir_listings.insert(fh, listing);
+                    reply.opened(fh.value(), 0)
+                }
+                Err(err) => reply.error(errno(&err)),
+            };
+        }
+
+        let dir = self
+            .handle(Inode(ino))
+            .and_then(|handle| reopen(&handle, libc::O_RDONLY | libc::O_DIRECTORY))
+            .and_then(|dir| Ok((fstat(&dir)?.st_dev, DirStream::new(dir)?)));
+
+        match dir {
+            Ok(dir) => {
+                let fh = self.get_fh();
+                self.open_dirs.insert(fh, dir);
+                reply.opened(fh.value(), 0)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        let reply = self
+            .meter(req, Op::Readdir, ino, None, reply)
+            .arg("offset", offset);
+        let mut reply = self.audit(req, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Readdir, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        reply.record("fh", fh);
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        // Entries we may add before stopping, though there may be more
+        let mut remaining = self.injector.listing_len();
+
+        // The offset of a cached entry is one more than its index
+        if let Some(listing) = self.dir_listings.get(&Fh(fh)) {
+            for (i, entry) in listing.iter().enumerate().skip(offset as usize) {
+                remaining -= 1;
+                if reply.add(entry.ino, i as i64 + 1, entry.kind, &entry.name) || remaining == 0 {
+                    return reply.ok();
+                }
+            }
+            return reply.ok();
+        }
+
+        let dir_path = match self.listing_dir_path(Inode(ino)) {
+            Ok(dir_path) => dir_path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if dir_path.as_deref().is_some_and(|path| self.too_deep(path)) {
+            return reply.ok();
+        }
+
+        let (dev, stream) = match self.open_dirs.get_mut(&Fh(fh)) {
+            None => {
+                reply.error(EBADFD);
+                return;
+            }
+            Some(dir) => dir,
+        };
+
+        // Entries are read in batches so that any which need to be stated can
+        // be stated concurrently. We may read more entries than fit in the
+        // reply, but the stream is seeked back for the next call.
+        stream.seek(offset);
+        loop {
+            let batch = match stream
+                .by_ref()
+                .take(READDIR_BATCH)
+                .collect::<io::Result<Vec<_>>>()
+            {
+                Ok(batch) if batch.is_empty() => return reply.ok(),
+                Ok(batch) => batch,
+                Err(err) => return reply.error(errno(&err)),
+            };
+            let stats = stat_entries(&self.workers, &self.config, stream.fd(), &batch);
+
+            for (entry, stat) in batch.into_iter().zip(stats) {
+                let listed = list_entry(&mut self.inode_numbers, &self.config, *dev, &entry, stat);
+                match listed {
+                    Ok(None) => continue,
+                    Ok(Some((ino, kind))) => {
+                        if let Some(dir_path) = &dir_path {
+                            let path = dir_path.join(&entry.name);
+                            if !self.filter.visible(&path, kind) {
+                                continue;
+                            }
+                            if self.config.hide_empty_dirs
+                                && kind == FileType::Directory
+                                && is_empty_dir(
+                                    &mut self.inode_numbers,
+                                    &self.config,
+                                    &self.workers,
+                                    &self.filter,
+                                    stream.fd(),
+                                    &entry.name,
+                                    &path,
+                                )
+                            {
+                                continue;
+                            }
+                        }
+
+                        let name = self.renamer.present(&entry.name);
+                        remaining -= 1;
+                        if reply.add(ino, entry.offset, kind, &name) || remaining == 0 {
+                            // add returns true if the reply buffer is full.
+                            // The kernel will ask again from the offset of
+                            // the last entry it received, so we'll seek back
+                            // to this one.
+                            return reply.ok();
+                        }
+                    }
+                    Err(err) => return reply.error(errno(&err)),
+                }
+            }
+        }
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let reply = self.meter(req, Op::Releasedir, ino, None, reply);
+        let _request = reply.enter();
+        reply.record("fh", fh);
+        let fh = Fh(fh);
+        if self.open_dirs.remove(&fh).is_some() || self.dir_listings.remove(&fh).is_some() {
+            self.put_fh(fh);
+        } else {
+            warn!("releasedir, but {:?} is not in open_dirs", fh);
+        }
+
+        reply.ok()
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let reply = self
+            .meter(req, Op::Open, ino, None, reply)
+            .arg("flags", flags);
+        let reply = self.audit(req, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Open, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        let access = open_access(flags);
+        if let Err(err) = self.check_access(req, ino, access) {
+            return reply.error(errno(&err));
+        }
+        let refused = [(libc::R_OK, PolicyOp::Read), (libc::W_OK, PolicyOp::Write)]
+            .iter()
+            .any(|(mask, op)| access & mask != 0 && !self.policy_allows(req, ino, *op));
+        if refused {
+            return reply.deny(Denial::Policy);
+        }
+
+        let mask = libc::O_APPEND | libc::O_CREAT | libc::O_TRUNC;
+
+        if let Some(content) = self.virtual_files.content(ino) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & mask != 0 {
+                return reply.error(libc::EACCES);
+            }
+            let content = Arc::clone(content);
+            let fh = self.get_fh();
+            self.virtual_opens.insert(fh, content);
+            return reply.opened(fh.value(), self.open_flags());
+        }
+
+        // The size of a stats file isn't known until it is generated, so it
+        // must be read with direct I/O
+        if let Some(file) = self.stats_dir.as_ref().and_then(|dir| dir.file(ino)) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & mask != 0 {
+                return reply.error(libc::EACCES);
+            }
+            let content = Arc::new(self.stats_content(file));
+            let fh = self.get_fh();
+            self.virtual_opens.insert(fh, content);
+            return reply.opened(fh.value(), consts::FOPEN_DIRECT_IO);
+        }
+
+        let writing = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & mask != 0;
+        if self.config.read_write && writing {
+            if let Err(err) = self.copy_up_inode(Inode(ino)) {
+                return reply.error(errno(&err));
+            }
+        }
+
+        // Transformed content can only be written through a codec, and is
+        // written back when the file is flushed. It is produced on a worker,
+        // and anything using it waits until it has been, so a transform
+        // which fails fails the reads of it. Content truncated by the open is
+        // never produced at all.
+        if self.transforms.applies(ino) {
+            let writable = self.config.read_write && self.transforms.writable(ino);
+            let read_only = flags & libc::O_ACCMODE == libc::O_RDONLY && flags & mask == 0;
+            if !(writable || read_only) {
+                return reply.error(libc::EACCES);
+            }
+            let started = self.handle(Inode(ino)).and_then(|handle| {
+                if flags & libc::O_TRUNC != 0 {
+                    Ok(Some((self.transforms.truncated(ino, &handle)?, None)))
+                } else {
+                    check_size(&self.config, &fstat(&handle)?)?;
+                    self.transforms.start(ino, &handle)
+                }
+            });
+            let (file, job) = match started {
+                Ok(Some(started)) => started,
+                Ok(None) => return reply.error(libc::EIO),
+                Err(err) => return reply.error(errno(&err)),
+            };
+            if let Some(job) = job {
+                self.workers.run(job);
+            }
+
+            // The kernel may have been given the size of the backing file,
+            // as the size of the content isn't known until it is produced,
+            // so it mustn't stop reading at that size
+            let fh = self.get_fh();
+            let flags = if writable {
+                libc::O_RDWR
+            } else {
+                libc::O_RDONLY
+            };
+            self.open_files.insert(fh, (Inode(ino), flags, file));
+            return reply.opened(fh.value(), consts::FOPEN_DIRECT_IO);
+        }
+
+        if !self.config.read_write && flags & mask != 0 {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let flags = if self.config.read_write {
+            // The kernel handles O_CREAT and O_EXCL itself via create()
+            let flags = flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOCTTY);
+            self.writeback_flags(flags)
+        } else {
+            libc::O_RDONLY
+        };
+
+        // A cached file can't be reused for O_TRUNC, but once the file has
+        // been truncated it is equivalent to one opened without it
+        let cached = if flags & libc::O_TRUNC == 0 {
+            self.fd_cache.take(ino, flags)
+        } else {
+            None
+        };
+        let file = match cached {
+            Some(file) => Ok(file),
+            None => self
+                .handle(Inode(ino))
+                .and_then(|handle| reopen(&handle, flags))
+                .map(Arc::new),
+        };
+
+        // Checked on the open file, as the size may have changed since lookup
+        let file = file.and_then(|file| {
+            check_size(&self.config, &fstat(&file)?)?;
+            Ok(file)
+        });
+
+        match file {
+            Ok(file) => {
+                let fh = self.get_fh();
+                if flags & libc::O_ACCMODE == libc::O_RDONLY {
+                    if let Some(mapping) = self.map_file(&file) {
+                        self.mappings.insert(fh, Arc::new(mapping));
+                    }
+                }
+
+                let flags = flags & !libc::O_TRUNC;
+                self.open_files.insert(fh, (Inode(ino), flags, file));
+                reply.opened(fh.value(), self.open_flags())
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let reply = self
+            .meter(req, Op::Read, ino, None, reply)
+            .arg("offset", offset)
+            .arg("size", size);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Read, ino, None) {
+            return reply.error(errno);
+        }
+        reply.record("fh", fh);
+        reply.record("size", size);
+        let delay = match self.throttle(req, size as u64) {
+            Ok(delay) => delay,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        let size = if self.config.cache_mode == CacheMode::Direct {
+            self.injector.read_len(size)
+        } else {
+            size
+        };
+
+        let fh = Fh(fh);
+        if let Some(content) = self.virtual_opens.get(&fh) {
+            let start = content.len().min(offset as usize);
+            let end = content.len().min(start + size as usize);
+            return reply.data(&content[start..end]);
+        }
+
+        // A file may grow past max_file_size after it was opened, but nothing
+        // beyond it is ever read
+        let size = if self.config.max_file_size > 0 {
+            let remaining = self.config.max_file_size.saturating_sub(offset as u64);
+            size.min(remaining.min(u32::MAX as u64) as u32)
+        } else {
+            size
+        };
+        if size == 0 {
+            return reply.data(&[]);
+        }
+
+        // We use pread rather than seeking the shared file offset, so reads
+        // don't depend on what else has been done with the handle
+        let file = match self.open_files.get(&fh) {
+            Some((_, _, file)) => Arc::clone(file),
+            None => return reply.error(EBADFD),
+        };
+
+        let mapping = self.mappings.get(&fh).map(Arc::clone);
+        let prefetch = self.read_ahead(fh, offset as u64, size);
+        let zero_fill_reads = self.config.zero_fill_reads;
+        let loader = self.transforms.loader(ino, &file);
+        self.workers.run_after(delay, move || {
+            if let Some(Err(err)) = loader.map(|loader| loader.load(offset as u64, size as u64)) {
+                return reply.error(errno(&err));
+            }
+
+            // If the whole range is mapped the kernel can copy it straight out
+            // of the mapping. Anything else, including reads near the end of
+            // the file, goes through pread.
+            if let Some(data) = mapping
+                .as_ref()
+                .and_then(|m| m.get(offset as u64, size as usize))
+            {
+                return reply.data(data);
+            }
+
+            READ_BUFFER.with(|buffer| {
+                let mut buffer = buffer.borrow_mut();
+                let size = size as usize;
+                if buffer.len() < size {
+                    buffer.resize(size, 0);
+                }
+
+                let buffer = &mut buffer[..size];
+                match read_full(&*file, buffer, offset as u64) {
+                    Ok(bytesin) if zero_fill_reads => {
+                        buffer[bytesin..].fill(0);
+                        reply.data(buffer)
+                    }
+                    Ok(bytesin) => reply.data(&buffer[..bytesin]),
+                    Err(err) => reply.error(errno(&err)),
+                }
+            });
+
+            // Ask the kernel to start reading the next range into the page
+            // cache, so it's ready by the time the next request arrives
+            if let Some((offset, len)) = prefetch {
+                let ret = unsafe {
+                    posix_fadvise(
+                        file.as_raw_fd(),
+                        offset as i64,
+                        len as i64,
+                        libc::POSIX_FADV_WILLNEED,
+                    )
+                };
+                if ret != 0 {
+                    debug!("readahead: {}", io::Error::from_raw_os_error(ret));
+                }
+            }
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let reply = self
+            .meter(req, Op::Write, ino, None, reply)
+            .arg("offset", offset)
+            .arg("size", data.len() as u64);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Write, ino, None) {
+            return reply.error(errno);
+        }
+        reply.record("fh", fh);
+        reply.record("size", data.len());
+        let delay = match self.throttle(req, data.len() as u64) {
+            Ok(delay) => delay,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        let fh = Fh(fh);
+        let file = match self.open_files.get(&fh) {
+            Some((_, _, file)) => Arc::clone(file),
+            None => return reply.error(EBADFD),
+        };
+        self.transforms.mark_dirty(ino);
+        let loader = self.transforms.loader(ino, &file);
+
+        // data is only borrowed for the duration of this call
+        let data = data.to_vec();
+        self.workers.run_after(delay, move || {
+            let len = data.len() as u64;
+            if let Some(Err(err)) = loader.map(|loader| loader.load(offset as u64, len)) {
+                return reply.error(errno(&err));
+            }
+            let written =
+                trace_span!("pwrite").in_scope(|| file.write_all_at(&data, offset as u64));
+            match written {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let reply = self.meter(req, Op::Flush, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Flush, ino, None) {
+            return reply.error(errno);
+        }
+        reply.record("fh", fh);
+        if self.virtual_opens.contains_key(&Fh(fh)) {
+            return reply.ok();
+        }
+
+        if self.transforms.applies(ino) {
+            return match self.write_back(Inode(ino)) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            };
+        }
+
+        let file = match self.open_files.get(&Fh(fh)) {
+            Some((_, _, file)) => Arc::clone(file),
+            None => return reply.error(EBADFD),
+        };
+
+        // Closing a duplicate of the backing fd reports any deferred write
+        // errors, which matters when the kernel has been batching writes in
+        // its writeback cache, without closing the handle itself.
+        self.workers.run(move || {
+            let ret = unsafe { libc::close(libc::dup(file.as_raw_fd())) };
+            match check_ret(ret) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let reply = self.meter(req, Op::Release, ino, None, reply);
+        let _request = reply.enter();
+        reply.record("fh", fh);
+        let fh = Fh(fh);
+        match self.open_files.remove(&fh) {
+            Some((inode, flags, file)) => {
+                if !self.transforms.applies(inode.0) {
+                    self.fd_cache.insert(inode.0, flags, file);
+                }
+                self.put_fh(fh);
+            }
+            None if self.virtual_opens.remove(&fh).is_some() => self.put_fh(fh),
+            None => warn!("release, but {:?} is not in open_files", fh),
+        }
+        self.read_ahead.remove(&fh);
+        self.mappings.remove(&fh);
+
+        reply.ok()
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let reply = self
+            .meter(req, Op::Fsync, ino, None, reply)
+            .arg("datasync", datasync);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Fsync, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        reply.record("fh", fh);
+        if self.virtual_opens.contains_key(&Fh(fh)) {
+            return reply.ok();
+        }
+
+        // The open file of transformed content isn't the backing file
+        let file = if self.transforms.applies(ino) {
+            self.write_back(Inode(ino))
+                .and_then(|()| self.handle(Inode(ino)))
+                .and_then(|handle| reopen(&handle, libc::O_RDONLY))
+                .map(Arc::new)
+        } else {
+            match self.open_files.get(&Fh(fh)) {
+                Some((_, _, file)) => Ok(Arc::clone(file)),
+                None => Err(io::Error::from_raw_os_error(EBADFD)),
+            }
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        self.workers.run(move || {
+            let result = if datasync {
+                file.sync_data()
+            } else {
+                file.sync_all()
+            };
+            match result {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let reply = self
+            .meter(req, Op::Create, parent, Some(name), reply)
+            .arg("mode", mode & !umask)
+            .arg("flags", flags);
+        let reply = self.audit(req, parent, Some(name), reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Create, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, false, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        // O_EXCL is passed through from the caller if they asked for it. An
+        // existing file is opened in the branch it is in.
+        let flags = self.writeback_flags(flags | libc::O_CREAT);
+        let file = self.copy_up(&path).and_then(|()| {
+            let (branch, existed) = match self.find_branch(&path) {
+                Some(branch) if stat_at(branch, &path).is_ok() => (branch, true),
+                _ => (self.create_branch(&path)?, false),
+            };
+            let file = open_beneath(branch, &path, flags, mode & !umask)?;
+            if !existed {
+                set_created_mode(branch, &path, (mode & !umask) as libc::mode_t)?;
+            }
+            Ok(file)
+        });
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        self.dir_cache.invalidate(parent);
+        self.cover_lower(&path, false);
+
+        let (handle, stat) = match self.open_handle(&path) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        // A new file with a codec is written through its transformed content,
+        // which is encoded even if nothing is written
+        let ino = self.inode_numbers.get(&stat);
+        self.transforms.attach(ino, &path);
+        let (flags, file) = if self.transforms.writable(ino) {
+            match self.transforms.get(ino, &handle) {
+                Ok(Some((file, _))) => {
+                    self.transforms.mark_dirty(ino);
+                    (libc::O_RDWR, file)
+                }
+                Ok(None) => (flags, Arc::new(file)),
+                Err(err) => return reply.error(errno(&err)),
+            }
+        } else {
+            (flags, Arc::new(file))
+        };
+
+        let (fileattr, generation) = self.ref_inode(handle, &stat, path);
+        let fh = self.get_fh();
+        let flags = flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC);
+        self.open_files
+            .insert(fh, (Inode(fileattr.ino), flags, file));
+        reply.created(
+            &self.config.entry_timeout,
+            &fileattr,
+            generation,
+            fh.value(),
+            self.open_flags(),
+        )
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let reply = self
+            .meter(req, Op::Mknod, parent, Some(name), reply)
+            .arg("mode", mode & !umask)
+            .arg("rdev", rdev);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Mknod, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        // We don't create device nodes on behalf of the caller
+        let mode = mode as libc::mode_t;
+        let umask = umask as libc::mode_t;
+        match mode & libc::S_IFMT {
+            libc::S_IFIFO | libc::S_IFSOCK | libc::S_IFREG => (),
+            _ => return reply.error(libc::EPERM),
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, false, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        let (branch, cpath) = match self
+            .create_branch(&path)
+            .and_then(|branch| Ok((branch, cstr(&path)?)))
+        {
+            Ok(args) => args,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        let ret = unsafe {
+            libc::mknodat(
+                branch.as_raw_fd(),
+                cpath.as_ptr(),
+                mode & !umask,
+                rdev as libc::dev_t,
+            )
+        };
+        if let Err(err) =
+            check_ret(ret).and_then(|()| set_created_mode(branch, &path, mode & !umask))
+        {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(parent);
+        self.cover_lower(&path, false);
+
+        match self.open_handle(&path) {
+            Ok((handle, stat)) => {
+                let (fileattr, generation) = self.ref_inode(handle, &stat, path);
+                reply.entry(&self.config.entry_timeout, &fileattr, generation)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let reply = self
+            .meter(req, Op::Setattr, ino, None, reply)
+            .arg("mode", mode)
+            .arg("uid", uid)
+            .arg("gid", gid)
+            .arg("size", size);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Setattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows(req, ino, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+        if self.config.check_permissions == PermissionCheck::Daemon {
+            let truncate = size.is_some() && fh.is_none();
+            if let Err(err) = self.presented_attr(ino).and_then(|fileattr| {
+                check_setattr(
+                    req,
+                    &fileattr,
+                    mode.is_some(),
+                    uid,
+                    gid,
+                    truncate,
+                    [atime, mtime],
+                )
+            }) {
+                return reply.error(errno(&err));
+            }
+        }
+
+        // The size of transformed content is changed in the content, which is
+        // written back straight away. Its other attributes are those of the
+        // backing file, which isn't the file the kernel has open.
+        let mut transformed_size = None;
+        let (size, fh) = if self.transforms.applies(ino) {
+            if !self.transforms.writable(ino) {
+                return reply.error(libc::EACCES);
+            }
+            match self.truncate_transformed(Inode(ino), size) {
+                Ok(size) => transformed_size = size,
+                Err(err) => return reply.error(errno(&err)),
+            }
+            (None, None)
+        } else {
+            (size, fh)
+        };
+
+        if let Err(err) = self.copy_up_inode(Inode(ino)) {
+            return reply.error(errno(&err));
+        }
+
+        // If the kernel gave us a file handle, operate on that rather than
+        // the path
+        let fh = fh.map(Fh);
+        if let Some(fh) = fh {
+            if !self.open_files.contains_key(&fh) {
+                return reply.error(EBADFD);
+            }
+        }
+
+        let file = fh
+            .and_then(|fh| self.open_files.get(&fh))
+            .map(|(_, _, file)| Arc::clone(file));
+        let handle = match self.handle(Inode(ino)) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        // A file opened before it was copied up is still the original
+        let file = file.filter(|file| {
+            self.config.upper.is_none()
+                || matches!((backing_id(file), backing_id(&handle)), (Ok(a), Ok(b)) if a == b)
+        });
+
+        // With writeback caching the kernel keeps mtime itself while it batches
+        // writes, and sends it here when they are flushed. It sends ctime too,
+        // but that can't be set and will be updated by the backing filesystem.
+        let uid = uid.map(|uid| self.attr_map.backing_uid(uid));
+        let gid = gid.map(|gid| self.attr_map.backing_gid(gid));
+        let ttl = self.config.attr_timeout;
+        let attr_map = Arc::clone(&self.attr_map);
+        self.workers.run(move || {
+            let file = file.as_deref();
+            match set_attrs(&handle, file, mode, uid, gid, size, atime, mtime)
+                .and_then(|()| file_attr(ino, file.unwrap_or(&handle)))
+            {
+                Ok(mut fileattr) => {
+                    if let Some(size) = transformed_size {
+                        set_size(&mut fileattr, size);
+                    }
+                    attr_map.apply(&mut fileattr);
+                    reply.attr(&ttl, &fileattr)
+                }
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let reply = self
+            .meter(req, Op::Setxattr, ino, None, reply)
+            .arg("name", &*name.to_string_lossy())
+            .arg("size", value.len() as u64)
+            .arg("flags", flags);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Setxattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows(req, ino, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        if flags & !(XATTR_CREATE | XATTR_REPLACE) != 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        if !xattr_permitted(req, name) {
+            return reply.error(libc::EPERM);
+        }
+        if let Err(err) = self.check_xattr_write(req, ino, name) {
+            return reply.error(errno(&err));
+        }
+
+        let (handle, cname) = match self
+            .copy_up_inode(Inode(ino))
+            .and_then(|()| self.handle(Inode(ino)))
+            .and_then(|handle| Ok((handle, CString::new(name.as_bytes())?)))
+        {
+            Ok(args) => args,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        let value = value.to_vec();
+        self.workers
+            .run(move || match set_xattr(&handle, &cname, &value, flags) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            });
+    }
+
+    fn getxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let reply = self
+            .meter(req, Op::Getxattr, ino, None, reply)
+            .arg("name", &*name.to_string_lossy())
+            .arg("size", size);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Getxattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows_lookup(req, ino) {
+            return reply.deny(Denial::Policy);
+        }
+        reply.record("size", size);
+        if let Some(content) = self.virtual_files.content(ino) {
+            return if name == CHECKSUM_XATTR {
+                reply_xattr(reply, size, hex_digest(content).as_bytes())
+            } else {
+                reply.error(ENODATA)
+            };
+        }
+        if self.is_stats(ino) {
+            return reply.error(ENODATA);
+        }
+
+        let (handle, cname) = match self
+            .handle(Inode(ino))
+            .and_then(|handle| Ok((handle, CString::new(name.as_bytes())?)))
+        {
+            Ok(args) => args,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        // The checksum is of the content as it is presented, so a transformed
+        // file's checksum is of its transformed content
+        if name == CHECKSUM_XATTR {
+            let file = match fstat(&handle) {
+                Ok(stat) if stat.st_mode & libc::S_IFMT != libc::S_IFREG => {
+                    return reply.error(ENODATA)
+                }
+                Ok(_) => match self.transforms.start(ino, &handle) {
+                    Ok(Some(started)) => Ok(started),
+                    Ok(None) => reopen(&handle, libc::O_RDONLY).map(|file| (Arc::new(file), None)),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            };
+            let (file, job) = match file {
+                Ok(file) => file,
+                Err(err) => return reply.error(errno(&err)),
+            };
+
+            let checksums = Arc::clone(&self.checksums);
+            let loader = self.transforms.loader(ino, &file);
+            return self.workers.run(move || {
+                if let Some(job) = job {
+                    job();
+                }
+                let checksum = loader
+                    .map_or(Ok(()), |loader| loader.load(0, u64::MAX))
+                    .and_then(|()| checksums.get(ino, &file));
+                match checksum {
+                    Ok(checksum) => reply_xattr(reply, size, checksum.as_bytes()),
+                    Err(err) => reply.error(errno(&err)),
+                }
+            });
+        }
+
+        self.workers.run(move || match get_xattr(&handle, &cname) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(err) => reply.error(errno(&err)),
+        });
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let reply = self
+            .meter(req, Op::Listxattr, ino, None, reply)
+            .arg("size", size);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Listxattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows_lookup(req, ino) {
+            return reply.deny(Denial::Policy);
+        }
+        reply.record("size", size);
+        let checksum_name = [CHECKSUM_XATTR.as_bytes(), b"\0"].concat();
+        if self.virtual_files.contains(ino) {
+            return reply_xattr(reply, size, &checksum_name);
+        }
+        if self.is_stats(ino) {
+            return reply_xattr(reply, size, &[]);
+        }
+
+        let handle = match self.handle(Inode(ino)) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        self.workers.run(move || {
+            let names = list_xattrs(&handle).and_then(|mut names| {
+                if fstat(&handle)?.st_mode & libc::S_IFMT == libc::S_IFREG {
+                    names.extend_from_slice(&checksum_name);
+                }
+                Ok(names)
+            });
+            match names {
+                Ok(names) => reply_xattr(reply, size, &names),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let reply = self
+            .meter(req, Op::Removexattr, ino, None, reply)
+            .arg("name", &*name.to_string_lossy());
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Removexattr, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows(req, ino, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        if !xattr_permitted(req, name) {
+            return reply.error(libc::EPERM);
+        }
+        if let Err(err) = self.check_xattr_write(req, ino, name) {
+            return reply.error(errno(&err));
+        }
+
+        let (handle, cname) = match self
+            .copy_up_inode(Inode(ino))
+            .and_then(|()| self.handle(Inode(ino)))
+            .and_then(|handle| Ok((handle, CString::new(name.as_bytes())?)))
+        {
+            Ok(args) => args,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        self.workers
+            .run(move || match remove_xattr(&handle, &cname) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            });
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let reply = self
+            .meter(req, Op::Mkdir, parent, Some(name), reply)
+            .arg("mode", mode & !umask);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Mkdir, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, true, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        let mode = (mode & !umask) as libc::mode_t;
+        let result = self.create_branch(&path).and_then(|branch| {
+            branch.create_dir(&path, mode)?;
+            set_created_mode(branch, &path, mode)
+        });
+        if let Err(err) = result {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(parent);
+        self.cover_lower(&path, true);
+
+        match self.open_handle(&path) {
+            Ok((handle, stat)) => {
+                let (fileattr, generation) = self.ref_inode(handle, &stat, path);
+                reply.entry(&self.config.entry_timeout, &fileattr, generation)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let reply = self.meter(req, Op::Rmdir, parent, Some(name), reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Rmdir, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, true, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        // Fetch the inode before removing the directory so we can update it
+        // afterwards
+        let stat = match stat_at(self.branch(&path), &path) {
+            Ok(stat) => stat,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if let Err(err) = self.check_sticky(req, parent, &stat) {
+            return reply.error(errno(&err));
+        }
+        let branch = self.branch(&path);
+
+        // Lower branches are never modified, so a directory in one is hidden
+        // instead. ENOTEMPTY is returned to the caller from here.
+        let removed = !self.is_lower(&path);
+        let result = if self.config.upper.is_some() {
+            self.remove_overlay_dir(&path)
+        } else {
+            branch.remove_dir(&path)
+        };
+        if let Err(err) = result {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(parent);
+        let ino = self.inode_numbers.get(&stat);
+
+        // The inode remains in the inode map until the kernel forgets it
+        if let Some(inode_entry) = self.inode_map.get_mut(&Inode(ino)) {
+            inode_entry.remove_path(&path);
+        }
+        if removed {
+            self.inode_numbers.retire(&stat);
+        }
+        reply.ok()
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let reply = self.meter(req, Op::Unlink, parent, Some(name), reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Unlink, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, false, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        let stat = match stat_at(self.branch(&path), &path) {
+            Ok(stat) => stat,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if let Err(err) = self.check_sticky(req, parent, &stat) {
+            return reply.error(errno(&err));
+        }
+        let branch = self.branch(&path);
+
+        // Lower branches are never modified, so a file in one is hidden
+        // instead
+        let removed = !self.is_lower(&path);
+        let lower = self.lower_exists(&path);
+        let result = if removed {
+            branch.remove_file(&path)
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result.and_then(|()| if lower { self.whiteout(&path) } else { Ok(()) }) {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(parent);
+
+        // The inode remains in the inode map until the kernel forgets it. If
+        // we removed the last link its handle keeps the file alive, so it can
+        // still be used by anything which has it open.
+        let inode = Inode(self.inode_numbers.get(&stat));
+        if let Some(inode_entry) = self.inode_map.get_mut(&inode) {
+            inode_entry.remove_path(&path);
+        }
+        if removed && stat.st_nlink <= 1 {
+            self.inode_numbers.retire(&stat);
+        }
+        reply.ok()
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let reply = self
+            .meter(req, Op::Rename, parent, Some(name), reply)
+            .arg("flags", flags);
+        let reply = self.record_path(reply, "newpath", newparent, Some(newname));
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Rename, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self
+            .check_access(req, parent, libc::W_OK | libc::X_OK)
+            .and_then(|()| self.check_access(req, newparent, libc::W_OK | libc::X_OK))
+        {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        let (from, mut to) = match (
+            self.child_path(parent, name),
+            self.child_path(newparent, newname),
+        ) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(err), _) | (_, Err(err)) => return reply.error(errno(&err)),
+        };
+        let is_dir = !self.policies.is_empty()
+            && stat_at(self.branch(&from), &from)
+                .is_ok_and(|stat| stat.st_mode & libc::S_IFMT == libc::S_IFDIR);
+        if !self.policy_allows_path(req, &from, is_dir, PolicyOp::Write)
+            || !self.policy_allows_path(req, &to, is_dir, PolicyOp::Write)
+        {
+            return reply.deny(Denial::Policy);
+        }
+
+        // A rename which only changes how a name is presented, such as its
+        // case, must not be mapped back to the original name
+        if from == to {
+            to.set_file_name(newname);
+        }
+
+        // The old name is removed from its directory, and so is any file the
+        // new name replaces
+        if self.config.check_permissions == PermissionCheck::Daemon {
+            let from_stat = match stat_at(self.branch(&from), &from) {
+                Ok(stat) => stat,
+                Err(err) => return reply.error(errno(&err)),
+            };
+            let to_stat = stat_at(self.branch(&to), &to).ok();
+            if let Err(err) = self.check_sticky(req, parent, &from_stat).and_then(|()| {
+                to_stat.map_or(Ok(()), |stat| self.check_sticky(req, newparent, &stat))
+            }) {
+                return reply.error(errno(&err));
+            }
+        }
+
+        let exchange = flags & RENAME_EXCHANGE != 0;
+
+        // Files in lower branches can't be removed from their old name, so
+        // the caller must copy them instead
+        if self.is_lower(&from) || (exchange && self.is_lower(&to)) {
+            return reply.error(libc::EXDEV);
+        }
+        let from_lower = !exchange && self.lower_exists(&from);
+
+        // The new name goes in the branch of the file it replaces, as that
+        // is the one presented, or else in the one create_policy chooses.
+        // With an upper directory, both are always the upper directory.
+        let from_branch = self.branch(&from);
+        let to_branch = match self.find_branch(&to) {
+            Some(branch) if !self.is_lower(&to) && stat_at(branch, &to).is_ok() => {
+                let to_parent = to.parent().unwrap_or_else(|| Path::new(""));
+                self.clone_path(branch, to_parent).map(|()| branch)
+            }
+            _ if exchange => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            _ => self.create_branch(&to),
+        };
+        let to_branch = match to_branch {
+            Ok(branch) => branch,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        // A file moving between branches is copied across first, and then
+        // renamed within the new branch. Directories aren't copied, so the
+        // caller must move their contents itself, as across filesystems.
+        let copied = !std::ptr::eq(from_branch, to_branch);
+        if copied {
+            let from_parent = from.parent().unwrap_or_else(|| Path::new(""));
+            let result = match stat_at(from_branch, &from) {
+                Ok(stat) if exchange || stat.st_mode & libc::S_IFMT == libc::S_IFDIR => {
+                    Err(io::Error::from_raw_os_error(libc::EXDEV))
+                }
+                Ok(_) => self
+                    .clone_path(to_branch, from_parent)
+                    .and_then(|()| copy_file(from_branch, to_branch, &from)),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                return reply.error(errno(&err));
+            }
+        }
+
+        // If this rename replaces an existing file we may be deleting it
+        let replaced = if exchange {
+            None
+        } else {
+            stat_at(to_branch, &to).ok()
+        };
+
+        let ret = match (cstr(&from), cstr(&to)) {
+            (Ok(cfrom), Ok(cto)) => unsafe {
+                renameat2(
+                    to_branch.as_raw_fd(),
+                    cfrom.as_ptr(),
+                    to_branch.as_raw_fd(),
+                    cto.as_ptr(),
+                    flags,
+                )
+            },
+            _ => {
+                if copied {
+                    let _ = to_branch.remove_file(&from);
+                }
+                return reply.error(libc::EINVAL);
+            }
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if copied {
+                let _ = to_branch.remove_file(&from);
+            }
+            return reply.error(errno(&err));
+        }
+
+        // Without an upper directory nothing can hide the old name, so it is
+        // removed from every branch, or another copy would take its place
+        if self.config.upper.is_none() && !exchange {
+            for branch in std::iter::once(&self.root).chain(&self.branches) {
+                let other = stat_at(branch, &from)
+                    .is_ok_and(|stat| stat.st_mode & libc::S_IFMT != libc::S_IFDIR);
+                if other && !std::ptr::eq(branch, to_branch) {
+                    if let Err(err) = branch.remove_file(&from) {
+                        warn!("Unable to remove {:?} from a branch: {}", from, err);
+                    }
+                }
+            }
+        }
+        self.dir_cache.invalidate(parent);
+        self.dir_cache.invalidate(newparent);
+
+        // The old name would otherwise reveal what is below it
+        if from_lower {
+            if let Err(err) = self.whiteout(&from) {
+                warn!("Unable to whiteout {:?}: {}", from, err);
+            }
+        }
+        if !exchange {
+            let is_dir = stat_at(&self.root, &to)
+                .is_ok_and(|stat| stat.st_mode & libc::S_IFMT == libc::S_IFDIR);
+            self.cover_lower(&to, is_dir);
+        }
+
+        if let Some(replaced) = replaced {
+            let is_dir = replaced.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            if is_dir || replaced.st_nlink <= 1 {
+                self.inode_numbers.retire(&replaced);
+            }
+        }
+
+        if !exchange {
+            // Anything which was previously at the destination has been
+            // replaced. Its inodes remain valid until they are forgotten, but
+            // they no longer have a path.
+            for inode_entry in self.inode_map.values_mut() {
+                inode_entry.paths.retain(|path| !path.starts_with(&to));
+            }
+        }
+        self.move_paths(&from, &to, exchange);
+        reply.ok()
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let reply = self.meter(req, Op::Readlink, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Readlink, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if !self.policy_allows_lookup(req, ino) {
+            return reply.deny(Denial::Policy);
+        }
+        let node = self
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.get(ino));
+        if let Some(target) = node.and_then(|node| node.target.as_ref()) {
+            return reply.data(target);
+        }
+
+        let handle = match self.handle(Inode(ino)) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        self.workers.run(move || match read_link(&handle) {
+            Ok(target) => reply.data(&target),
+            Err(err) => reply.error(errno(&err)),
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let reply = self
+            .meter(req, Op::Symlink, parent, Some(name), reply)
+            .arg("target", &*link.to_string_lossy());
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Symlink, parent, Some(name)) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &path, false, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        // The link target is stored verbatim: it is interpreted by whoever
+        // follows it, not by us
+        let result = self
+            .create_branch(&path)
+            .and_then(|branch| branch.symlink(&path, link));
+        if let Err(err) = result {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(parent);
+        self.cover_lower(&path, false);
+
+        match self.open_handle(&path) {
+            Ok((handle, stat)) => {
+                let (fileattr, generation) = self.ref_inode(handle, &stat, path);
+                reply.entry(&self.config.entry_timeout, &fileattr, generation)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let reply = self.meter(req, Op::Link, ino, None, reply);
+        let reply = self.record_path(reply, "newpath", newparent, Some(newname));
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Link, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        if let Err(err) = self.check_access(req, newparent, libc::W_OK | libc::X_OK) {
+            return reply.error(errno(&err));
+        }
+
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let newpath = match self.child_path(newparent, newname) {
+            Ok(path) => path,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.policy_allows_path(req, &newpath, false, PolicyOp::Write) {
+            return reply.deny(Denial::Policy);
+        }
+
+        let oldpath = if self.branches.is_empty() {
+            Ok(None)
+        } else {
+            self.copy_up_inode(Inode(ino))
+                .and_then(|()| self.dir_path(Inode(ino)))
+                .map(Some)
+        };
+        let result = oldpath
+            .and_then(|oldpath| {
+                // A hard link must be in the same branch as the file
+                let branch = oldpath.map_or(&self.root, |oldpath| self.branch(&oldpath));
+                self.clone_path(branch, newpath.parent().unwrap_or_else(|| Path::new("")))?;
+                Ok((branch, self.handle(Inode(ino))?))
+            })
+            .and_then(|(branch, handle)| link_handle(&handle, branch, &newpath));
+        if let Err(err) = result {
+            return reply.error(errno(&err));
+        }
+        self.dir_cache.invalidate(newparent);
+        self.cover_lower(&newpath, false);
+
+        // Stat the new link so the reply carries the updated nlink. This is
+        // the same inode, so ref_inode only bumps its reference count and
+        // records the new path.
+        match self.open_handle(&newpath) {
+            Ok((handle, stat)) => {
+                let (fileattr, generation) = self.ref_inode(handle, &stat, newpath);
+                reply.entry(&self.config.entry_timeout, &fileattr, generation)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let reply = self
+            .meter(req, Op::Fallocate, ino, None, reply)
+            .arg("offset", offset)
+            .arg("length", length)
+            .arg("mode", mode);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Fallocate, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        reply.record("fh", fh);
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        let file = match self.open_files.get(&Fh(fh)) {
+            Some((_, _, file)) => Arc::clone(file),
+            None => return reply.error(EBADFD),
+        };
+        self.transforms.mark_dirty(ino);
+        let loader = self.transforms.loader(ino, &file);
+
+        // The mode flags are passed straight through. The backing filesystem
+        // will return EOPNOTSUPP for anything it doesn't support.
+        self.workers.run(move || {
+            if let Some(Err(err)) = loader.map(|loader| loader.load(0, u64::MAX)) {
+                return reply.error(errno(&err));
+            }
+            let ret = unsafe { fallocate(file.as_raw_fd(), mode, offset, length) };
+            match check_ret(ret) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(errno(&err)),
+            }
+        });
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let reply = self
+            .meter(req, Op::CopyFileRange, ino_out, None, reply)
+            .arg("offset_in", offset_in)
+            .arg("offset_out", offset_out)
+            .arg("len", len)
+            .arg("flags", flags);
+        let reply = self.record_path(reply, "source", ino_in, None);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::CopyFileRange, ino_out, None) {
+            return reply.error(errno);
+        }
+        reply.record("fh", fh_in);
+        reply.record("size", len);
+        let delay = match self.throttle(req, len) {
+            Ok(delay) => delay,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        if !self.config.read_write {
+            return reply.deny(Denial::ReadOnly);
+        }
+
+        if offset_in < 0 || offset_out < 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        let (file_in, file_out) = match (
+            self.open_files.get(&Fh(fh_in)),
+            self.open_files.get(&Fh(fh_out)),
+        ) {
+            (Some((_, _, file_in)), Some((_, _, file_out))) => {
+                (Arc::clone(file_in), Arc::clone(file_out))
+            }
+            _ => return reply.error(EBADFD),
+        };
+        self.transforms.mark_dirty(ino_out);
+        let loader_in = self.transforms.loader(ino_in, &file_in);
+        let loader_out = self.transforms.loader(ino_out, &file_out);
+
+        // FUSE can only report a u32 worth of bytes copied
+        let len = len.min(u32::MAX as u64) as usize;
+
+        // Let the kernel do the copy between the backing files, which allows
+        // it to reflink or do a server-side copy where supported
+        self.workers.run_after(delay, move || {
+            let loaded = loader_in
+                .map_or(Ok(()), |loader| loader.load(offset_in as u64, len as u64))
+                .and_then(|()| {
+                    loader_out.map_or(Ok(()), |loader| loader.load(offset_out as u64, len as u64))
+                });
+            if let Err(err) = loaded {
+                return reply.error(errno(&err));
+            }
+            let mut offset_in = offset_in;
+            let mut offset_out = offset_out;
+            let ret = unsafe {
+                libc::copy_file_range(
+                    file_in.as_raw_fd(),
+                    &mut offset_in,
+                    file_out.as_raw_fd(),
+                    &mut offset_out,
+                    len,
+                    flags,
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                return reply.error(errno(&err));
+            }
+
+            reply.written(ret as u32)
+        });
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let reply = self.meter(req, Op::Statfs, ino, None, reply);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Statfs, ino, None) {
+            return reply.error(errno);
+        }
+        reply.error(libc::EPERM)
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let reply = self
+            .meter(req, Op::Access, ino, None, reply)
+            .arg("mask", mask);
+        let _request = reply.enter();
+        if let Some(errno) = self.inject(Op::Access, ino, None) {
+            return reply.error(errno);
+        }
+        if let Err(err) = self.throttle(req, 0) {
+            return reply.error(errno(&err));
+        }
+        // Otherwise the kernel need not ask again: access is decided by the
+        // backing tree as each request is made
+        if self.config.check_permissions != PermissionCheck::Daemon {
+            return reply.error(libc::ENOSYS);
+        }
+        match self.check_access(req, ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+}
+
+// Apply a connection setting with set, if it was given. If the kernel won't
+// accept the value we use the nearest one it will.
+fn tune<T, F>(name: &str, value: Option<T>, mut set: F)
+where
+    T: Copy + std::fmt::Display,
+    F: FnMut(T) -> std::result::Result<T, T>,
+{
+    if let Some(value) = value {
+        if let Err(nearest) = set(value) {
+            warn!("{} {} is not supported: using {}", name, value, nearest);
+            let _ = set(nearest);
+        }
+    }
+}
+
+// The number of directory entries readdir reads from the backing directory at a
+// time
+const READDIR_BATCH: usize = 64;
+
+// We only stat a directory entry if the dirent doesn't tell us its type, if we
+// need its device to hide mount points, or if we need its size to hide large
+// files
+fn needs_stat(entry: &DirEntry, config: &Config) -> bool {
+    let hide_oversized = config.max_file_size > 0 && config.oversize == Oversize::Hide;
+    entry.file_type == libc::DT_UNKNOWN
+        || config.one_file_system
+        || (hide_oversized && entry.file_type == libc::DT_REG)
+}
+
+// Whether stat is a regular file larger than max_file_size
+fn oversized(config: &Config, stat: &stat) -> bool {
+    config.max_file_size > 0
+        && stat.st_mode & libc::S_IFMT == libc::S_IFREG
+        && stat.st_size as u64 > config.max_file_size
+}
+
+// Fail to open a file which has grown larger than max_file_size since it was
+// looked up: with ENOENT if it should have been hidden, or EFBIG
+fn check_size(config: &Config, stat: &stat) -> io::Result<()> {
+    if !oversized(config, stat) {
+        return Ok(());
+    }
+    let errno = match config.oversize {
+        Oversize::Hide => libc::ENOENT,
+        Oversize::Deny => libc::EFBIG,
+    };
+    Err(io::Error::from_raw_os_error(errno))
+}
+
+// Stat the entries of dirfd which need it, concurrently on the worker pool.
+// This waits for every stat to complete, so dirfd only needs to remain open
+// until it returns.
+fn stat_entries(
+    workers: &Workers,
+    config: &Config,
+    dirfd: libc::c_int,
+    entries: &[DirEntry],
+) -> Vec<Option<io::Result<stat>>> {
+    let (sender, receiver) = mpsc::channel();
+    for (i, entry) in entries.iter().enumerate() {
+        if needs_stat(entry, config) {
+            let sender = sender.clone();
+            let name = entry.name.clone();
+            workers.run(move || {
+                let _ = sender.send((i, fstatat(dirfd, &name)));
+            });
+        }
+    }
+    // The receiver is exhausted once every job has dropped its sender
+    drop(sender);
+
+    let mut stats: Vec<_> = entries.iter().map(|_| None).collect();
+    for (i, stat) in receiver {
+        stats[i] = Some(stat);
+    }
+    stats
+}
+
+// Whether the directory name in dirfd, at path relative to root, would be
+// listed with no entries. A subdirectory only counts as an entry if it isn't
+// empty itself. A directory which can't be read is assumed not to be empty.
+fn is_empty_dir(
+    inode_numbers: &mut InodeNumbers,
+    config: &Config,
+    workers: &Workers,
+    filter: &Filter,
+    dirfd: libc::c_int,
+    name: &OsStr,
+    path: &Path,
+) -> bool {
+    if config
+        .max_depth
+        .is_some_and(|max_depth| path.components().count() > max_depth)
+    {
+        return true;
+    }
+
+    let mut scan = || -> io::Result<bool> {
+        let dir = open_subdir(dirfd, name)?;
+        let dev = fstat(&dir)?.st_dev;
+        let mut stream = DirStream::new(dir)?;
+        let entries = stream.by_ref().collect::<io::Result<Vec<_>>>()?;
+        let stats = stat_entries(workers, config, stream.fd(), &entries);
+
+        for (entry, stat) in entries.into_iter().zip(stats) {
+            let kind = match list_entry(inode_numbers, config, dev, &entry, stat)? {
+                Some((_, kind)) => kind,
+                None => continue,
+            };
+            let path = path.join(&entry.name);
+            if filter.visible(&path, kind)
+                && (kind != FileType::Directory
+                    || !is_empty_dir(
+                        inode_numbers,
+                        config,
+                        workers,
+                        filter,
+                        stream.fd(),
+                        &entry.name,
+                        &path,
+                    ))
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+    scan().unwrap_or(false)
+}
+
+// Return the inode number and type of a directory entry on dev, or None if it
+// should be hidden. stat is the result of stat_entries for this entry.
+fn list_entry(
+    inode_numbers: &mut InodeNumbers,
+    config: &Config,
+    dev: u64,
+    entry: &DirEntry,
+    stat: Option<io::Result<stat>>,
+) -> io::Result<Option<(u64, FileType)>> {
+    let mut file_type = entry.file_type;
+    let mut id = BackingId {
+        dev,
+        ino: entry.ino,
+    };
+
+    if let Some(stat) = stat {
+        let stat = stat?;
+        if config.one_file_system && !inode_numbers.same_device(&stat) {
+            return Ok(None);
+        }
+        if config.oversize == Oversize::Hide && oversized(config, &stat) {
+            return Ok(None);
+        }
+
+        // DT_* is the file type from st_mode shifted down
+        file_type = ((stat.st_mode & libc::S_IFMT) >> 12) as u8;
+        id = BackingId::from_stat(&stat);
+    }
+
+    let kind = match file_type {
+        libc::DT_SOCK => FileType::Socket,
+        libc::DT_LNK => FileType::Symlink,
+        libc::DT_BLK => FileType::BlockDevice,
+        libc::DT_DIR => FileType::Directory,
+        libc::DT_CHR => FileType::CharDevice,
+        libc::DT_FIFO => FileType::NamedPipe,
+        _ => FileType::RegularFile,
+    };
+    Ok(Some((inode_numbers.get_id(id), kind)))
+}
+
+// Attributes of a file which, if they are unchanged, mean that it hasn't been
+// modified
+// The times are narrower on 32-bit targets
+#[allow(clippy::unnecessary_cast)]
+fn file_version(stat: &stat) -> [i64; 5] {
+    [
+        stat.st_size,
+        stat.st_mtime as i64,
+        stat.st_mtime_nsec as i64,
+        stat.st_ctime as i64,
+        stat.st_ctime_nsec as i64,
+    ]
+}
+
+// Present fileattr with size bytes of content
+fn set_size(fileattr: &mut FileAttr, size: u64) {
+    fileattr.size = size;
+    fileattr.blocks = size.div_ceil(512);
+}
+
+// The type of file with st_mode mode, if we recognise it
+fn mode_file_type(mode: libc::mode_t) -> Option<FileType> {
+    match mode & libc::S_IFMT {
+        libc::S_IFSOCK => Some(FileType::Socket),
+        libc::S_IFLNK => Some(FileType::Symlink),
+        libc::S_IFREG => Some(FileType::RegularFile),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        libc::S_IFDIR => Some(FileType::Directory),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFIFO => Some(FileType::NamedPipe),
+        _ => None,
+    }
+}
+
+// st_mode is a u16 on FreeBSD, and the times are narrower on 32-bit targets
+#[allow(clippy::unnecessary_cast)]
+fn stat_to_fileattr(ino: u64, stat: &stat) -> FileAttr {
+    let kind = mode_file_type(stat.st_mode).unwrap_or_else(|| {
+        warn! {"Unrecognised file type {:o} for inode {:x}", stat.st_mode, stat.st_ino};
+        FileType::RegularFile
+    });
+
+    FileAttr {
+        ino,
+        size: stat.st_size as u64,
+        blocks: stat.st_blocks as u64,
+        atime: get_system_time(stat.st_atime as i64, stat.st_atime_nsec as i64),
+        mtime: get_system_time(stat.st_mtime as i64, stat.st_mtime_nsec as i64),
+        ctime: get_system_time(stat.st_ctime as i64, stat.st_ctime_nsec as i64),
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: (stat.st_mode & 0o777) as u16,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: stat.st_rdev as u32,
+        blksize: stat.st_blksize as u32,
+        padding: 0,
+        flags: 0,
+    }
+}
+
+fn get_system_time(time: i64, nsec: i64) -> SystemTime {
+    let nsec = nsec as u32;
+    if time >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(time as u64, nsec))
+    } else {
+        // tv_nsec is always positive, even for times before the epoch
         UNIX_EPOCH
-            .checked_add(Duration::new(time as u64, 0))
-            .unwrap_or(UNIX_EPOCH)
+            .checked_sub(Duration::new(time.unsigned_abs(), 0))
+            .and_then(|time| time.checked_add(Duration::new(0, nsec)))
+    }
+    .unwrap_or(UNIX_EPOCH)
+}
+
+fn time_or_now_to_timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    match time {
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        Some(TimeOrNow::Now) => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        Some(TimeOrNow::SpecificTime(time)) => {
+            let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            libc::timespec {
+                tv_sec: time.as_secs() as libc::time_t,
+                tv_nsec: time.subsec_nanos() as libc::c_long,
+            }
+        }
+    }
+}
+
+// The trusted and security namespaces require CAP_SYS_ADMIN. We may well have
+// that, but the caller must not be able to use it unless they are root too.
+// The checksum attribute is computed by us, so nobody can set it.
+fn xattr_permitted(req: &Request<'_>, name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    if name == CHECKSUM_XATTR.as_bytes() {
+        return false;
+    }
+    req.uid() == 0 || !(name.starts_with(b"trusted.") || name.starts_with(b"security."))
+}
+
+// Check that the caller of req may change the attributes of a file with
+// fileattr as setattr asks: its mode if chmod is set, its owner and group,
+// its size if truncate is set and its times. As the kernel decides it, only
+// the owner may change the mode or set a time, only root may give a file
+// away, the owner may only change its group to one of their own, and
+// truncating or touching needs write access. A file truncated through a file
+// handle was opened for writing already.
+fn check_setattr(
+    req: &Request<'_>,
+    fileattr: &FileAttr,
+    chmod: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    truncate: bool,
+    times: [Option<TimeOrNow>; 2],
+) -> io::Result<()> {
+    if req.uid() == 0 {
+        return Ok(());
+    }
+    let owner = req.uid() == fileattr.uid;
+    let chown = uid.is_some_and(|uid| !owner || uid != fileattr.uid);
+    let chgrp = gid.is_some_and(|gid| {
+        !owner
+            || (gid != fileattr.gid && gid != req.gid() && !caller_groups(req.pid()).contains(&gid))
+    });
+    let set_time = times
+        .iter()
+        .any(|time| matches!(time, Some(TimeOrNow::SpecificTime(_))));
+    if ((chmod || set_time) && !owner) || chown || chgrp {
+        return Err(io::Error::from_raw_os_error(libc::EPERM));
+    }
+
+    let touch = times
+        .iter()
+        .any(|time| matches!(time, Some(TimeOrNow::Now)));
+    if (truncate || (touch && !owner)) && !permitted(req, fileattr, libc::W_OK) {
+        return Err(io::Error::from_raw_os_error(libc::EACCES));
     }
+    Ok(())
+}
 
-    FileAttr {
-        ino: stat.st_ino,
-        size: stat.st_size as u64,
-        blocks: stat.st_blocks as u64,
-        atime: get_system_time(stat.st_atime),
-        mtime: get_system_time(stat.st_mtime),
-        ctime: get_system_time(stat.st_ctime),
-        crtime: UNIX_EPOCH,
-        kind,
-        perm: (stat.st_mode & 0o777) as u16,
-        nlink: stat.st_nlink as u32,
-        uid: stat.st_uid,
-        gid: stat.st_gid,
-        rdev: stat.st_rdev as u32,
-        blksize: stat.st_blksize as u32,
-        padding: 0,
-        flags: 0,
+// Whether the caller of req may access a file with fileattr as mask, made of
+// R_OK, W_OK and X_OK, asks. This is decided from the file's mode and owner as
+// the kernel would, except that root may read and write anything.
+fn permitted(req: &Request<'_>, fileattr: &FileAttr, mask: i32) -> bool {
+    if req.uid() == 0 {
+        let executable = fileattr.kind == FileType::Directory || fileattr.perm & 0o111 != 0;
+        return mask & libc::X_OK == 0 || executable;
+    }
+    let perm = if req.uid() == fileattr.uid {
+        fileattr.perm >> 6
+    } else if req.gid() == fileattr.gid || caller_groups(req.pid()).contains(&fileattr.gid) {
+        fileattr.perm >> 3
+    } else {
+        fileattr.perm
+    };
+    i32::from(perm & 0o7) & mask == mask
+}
+
+// The supplementary groups of process pid, or none if it has exited
+#[cfg(target_os = "linux")]
+fn caller_groups(pid: u32) -> Vec<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map_or_else(Vec::new, |groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+}
+
+// The access open with flags asks for, as a mask for permitted
+fn open_access(flags: i32) -> i32 {
+    let access = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => libc::R_OK,
+        libc::O_WRONLY => libc::W_OK,
+        _ => libc::R_OK | libc::W_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        access | libc::W_OK
+    } else {
+        access
+    }
+}
+
+// The extended attribute presenting the SHA-256 of a regular file's content
+const CHECKSUM_XATTR: &str = "user.passfs.sha256";
+
+// Reply to getxattr or listxattr with value, or with its size if the kernel
+// asked for the size by passing 0
+fn reply_xattr(reply: Metered<ReplyXattr>, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32)
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE)
+    } else {
+        reply.data(value)
+    }
+}
+
+// There are no *xattrat() syscalls, and the f*xattr() syscalls don't accept
+// an O_PATH fd, so the xattr functions go through the handle's path in /proc
+// instead
+
+// Set the extended attribute name of the file referred to by handle
+#[cfg(target_os = "linux")]
+fn set_xattr(handle: &File, name: &CString, value: &[u8], flags: i32) -> io::Result<()> {
+    let path = cstr(&fd_path(handle))?;
+    check_ret(unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            flags,
+        )
+    })
+}
+
+// Remove the extended attribute name of the file referred to by handle
+#[cfg(target_os = "linux")]
+fn remove_xattr(handle: &File, name: &CString) -> io::Result<()> {
+    let path = cstr(&fd_path(handle))?;
+    check_ret(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) })
+}
+
+// Return the value of the extended attribute name of the file referred to by
+// handle
+#[cfg(target_os = "linux")]
+fn get_xattr(handle: &File, name: &CString) -> io::Result<Vec<u8>> {
+    let path = cstr(&fd_path(handle))?;
+    loop {
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut value = vec![0u8; size as usize];
+        let ret = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        match ret {
+            // The value grew between the calls
+            ret if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => {
+                continue
+            }
+            ret if ret < 0 => return Err(io::Error::last_os_error()),
+            ret => {
+                value.truncate(ret as usize);
+                return Ok(value);
+            }
+        }
+    }
+}
+
+// Return the names of the extended attributes of the file referred to by
+// handle, each terminated by a nul
+#[cfg(target_os = "linux")]
+fn list_xattrs(handle: &File) -> io::Result<Vec<u8>> {
+    let path = cstr(&fd_path(handle))?;
+    loop {
+        let size = unsafe { libc::listxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut names = vec![0u8; size as usize];
+        let ret = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                names.as_mut_ptr() as *mut libc::c_char,
+                names.len(),
+            )
+        };
+        match ret {
+            // The list grew between the calls
+            ret if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => {
+                continue
+            }
+            ret if ret < 0 => return Err(io::Error::last_os_error()),
+            ret => {
+                names.truncate(ret as usize);
+                return Ok(names);
+            }
+        }
+    }
+}
+
+// Return the errno to reply with for err. Errors from the backing filesystem
+// carry their own errno, but errors which originate in std or in passfs itself
+// may only have an ErrorKind.
+fn errno(err: &io::Error) -> libc::c_int {
+    use io::ErrorKind::*;
+
+    if let Some(errno) = err.raw_os_error() {
+        return errno;
+    }
+
+    match err.kind() {
+        NotFound => libc::ENOENT,
+        PermissionDenied => libc::EACCES,
+        AlreadyExists => libc::EEXIST,
+        NotADirectory => libc::ENOTDIR,
+        IsADirectory => libc::EISDIR,
+        DirectoryNotEmpty => libc::ENOTEMPTY,
+        ReadOnlyFilesystem => libc::EROFS,
+        StaleNetworkFileHandle => libc::ESTALE,
+        StorageFull => libc::ENOSPC,
+        QuotaExceeded => libc::EDQUOT,
+        FileTooLarge => libc::EFBIG,
+        NotSeekable => libc::ESPIPE,
+        ResourceBusy => libc::EBUSY,
+        ExecutableFileBusy => libc::ETXTBSY,
+        CrossesDevices => libc::EXDEV,
+        TooManyLinks => libc::EMLINK,
+        // std maps ENAMETOOLONG to InvalidFilename
+        InvalidFilename => libc::ENAMETOOLONG,
+        // This includes a path containing a NUL, which can't be passed to the
+        // kernel
+        InvalidInput | InvalidData => libc::EINVAL,
+        WouldBlock => libc::EAGAIN,
+        Interrupted => libc::EINTR,
+        TimedOut => libc::ETIMEDOUT,
+        BrokenPipe => libc::EPIPE,
+        Unsupported => libc::ENOTSUP,
+        OutOfMemory => libc::ENOMEM,
+        _ => {
+            warn!("No errno for {}: returning EIO", err);
+            libc::EIO
+        }
+    }
+}
+
+// Convert the return value of a libc call to an io::Result
+fn check_ret(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn cstr(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+// openat() relative to branch. Where the kernel supports it we use openat2()
+// with RESOLVE_BENEATH, so that a symlink or .. in the path can never take us
+// outside branch, even if the tree is modified underneath us.
+fn open_beneath(branch: &Dir, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+    let path = cstr(path)?;
+    let flags = flags | libc::O_CLOEXEC;
+    // Without O_LARGEFILE, 32-bit Linux refuses to open a file of 2GiB or more
+    #[cfg(target_os = "linux")]
+    let flags = flags | libc::O_LARGEFILE;
+
+    if !OPENAT2_UNSUPPORTED.load(Ordering::Relaxed) {
+        match openat2_beneath(branch.as_raw_fd(), &path, flags, mode) {
+            Err(err) if openat2_unavailable(branch.as_raw_fd(), &err) => {
+                warn!(
+                    "Unable to use openat2 ({}): opens are not confined to root",
+                    err
+                );
+                OPENAT2_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            result => return result,
+        }
+    }
+
+    let fd = unsafe { libc::openat(branch.as_raw_fd(), path.as_ptr(), flags, mode) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Whether err, returned by openat2_beneath(dirfd, ...), means we can't use
+// openat2() at all: the kernel predates it (ENOSYS) or our struct open_how
+// (E2BIG), or a seccomp filter, as container runtimes install, refuses it
+// (EPERM). An open can also fail with EPERM for other reasons, so that only
+// counts if opening dirfd itself fails the same way.
+fn openat2_unavailable(dirfd: libc::c_int, err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::E2BIG) => true,
+        Some(libc::EPERM) => {
+            let dot = CString::new(".").unwrap();
+            let probe = openat2_beneath(dirfd, &dot, libc::O_PATH | libc::O_CLOEXEC, 0);
+            matches!(probe, Err(err) if err.raw_os_error() == Some(libc::EPERM))
+        }
+        _ => false,
+    }
+}
+
+// Set the permissions of the file we just created at path in dir for a
+// caller to those in mode, which the caller's umask has already been applied
+// to. It was created with our own umask in effect too, which could have taken
+// away more. A directory keeps any setgid bit it inherited from its parent.
+fn set_created_mode(dir: &Dir, path: &Path, mode: libc::mode_t) -> io::Result<()> {
+    let stat = stat_at(dir, path)?;
+    let mut mode = mode & 0o7777;
+    if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        mode |= stat.st_mode & libc::S_ISGID;
+    }
+    if stat.st_mode & 0o7777 == mode {
+        return Ok(());
+    }
+    let cpath = cstr(path)?;
+    check_ret(unsafe { libc::fchmodat(dir.as_raw_fd(), cpath.as_ptr(), mode, 0) })
+}
+
+// Copy the file at path in from to the same path in to, whose parent must
+// exist. Its content, or target if it is a symlink, is copied along with its
+// mode, timestamps and, if we are permitted, its owner.
+fn copy_file(from: &Dir, to: &Dir, path: &Path) -> io::Result<()> {
+    let stat = stat_at(from, path)?;
+    let mode = stat.st_mode & 0o7777;
+    match stat.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => to.create_dir(path, mode)?,
+        libc::S_IFLNK => to.symlink(path, &from.read_link(path)?)?,
+        libc::S_IFREG => {
+            let mut input = open_beneath(from, path, libc::O_RDONLY | libc::O_NOFOLLOW, 0)?;
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+            let mut output = open_beneath(to, path, flags, mode as libc::c_uint)?;
+            // Don't leave a partial copy to hide the original
+            if let Err(err) = io::copy(&mut input, &mut output) {
+                let _ = to.remove_file(path);
+                return Err(err);
+            }
+        }
+        _ => {
+            let cpath = cstr(path)?;
+            check_ret(unsafe {
+                libc::mknodat(to.as_raw_fd(), cpath.as_ptr(), stat.st_mode, stat.st_rdev)
+            })?;
+        }
+    }
+
+    let cpath = cstr(path)?;
+    let fd = to.as_raw_fd();
+    let nofollow = libc::AT_SYMLINK_NOFOLLOW;
+    match check_ret(unsafe {
+        libc::fchownat(fd, cpath.as_ptr(), stat.st_uid, stat.st_gid, nofollow)
+    }) {
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => (),
+        result => result?,
+    }
+    // The mode of a symlink can't be changed, and is never used
+    if stat.st_mode & libc::S_IFMT != libc::S_IFLNK {
+        check_ret(unsafe { libc::fchmodat(fd, cpath.as_ptr(), mode, 0) })?;
+    }
+    let times = [
+        libc::timespec {
+            tv_sec: stat.st_atime,
+            tv_nsec: stat.st_atime_nsec,
+        },
+        libc::timespec {
+            tv_sec: stat.st_mtime,
+            tv_nsec: stat.st_mtime_nsec,
+        },
+    ];
+    check_ret(unsafe { libc::utimensat(fd, cpath.as_ptr(), times.as_ptr(), nofollow) })
+}
+
+// With an upper directory, an entry in a lower branch is removed by creating a
+// whiteout beside it in the upper directory, and a directory which replaces
+// one in a lower branch contains an opaque marker, as done by aufs
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    whiteout
+}
+
+fn whiteout_path(path: &Path) -> PathBuf {
+    path.with_file_name(whiteout_name(path.file_name().unwrap_or_default()))
+}
+
+// The name hidden by the whiteout name, or None if it isn't a whiteout
+fn whiteout_target(name: &OsStr) -> Option<&OsStr> {
+    name.as_bytes()
+        .strip_prefix(WHITEOUT_PREFIX.as_bytes())
+        .map(OsStr::from_bytes)
+}
+
+// The space available to unprivileged users in the filesystem containing dir,
+// in bytes, or 0 if it can't be found
+fn free_space(dir: &Dir) -> u64 {
+    let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) } < 0 {
+        return 0;
+    }
+    stat.f_bavail as u64 * stat.f_frsize as u64
+}
+
+// Set once we have found that we can't use openat2(), so we don't keep trying
+// it
+static OPENAT2_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// struct open_how and its resolve flags from linux/openat2.h, which libc
+// doesn't provide
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+#[cfg(target_os = "linux")]
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(target_os = "linux")]
+const RESOLVE_BENEATH: u64 = 0x08;
+
+// How many times we retry an openat2() which raced with a rename, before
+// giving up with EAGAIN
+#[cfg(target_os = "linux")]
+const OPENAT2_RETRIES: usize = 32;
+
+// openat2() which fails rather than resolve path to anywhere outside dirfd,
+// including via /proc magic links
+#[cfg(target_os = "linux")]
+fn openat2_beneath(dirfd: libc::c_int, path: &CString, flags: i32, mode: u32) -> io::Result<File> {
+    let _syscall = trace_span!("openat2").entered();
+    let how = OpenHow {
+        flags: flags as u64,
+        // mode must be 0 unless we're creating a file
+        mode: if flags & libc::O_CREAT != 0 {
+            mode as u64
+        } else {
+            0
+        },
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+    };
+
+    let mut retries = 0;
+    loop {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dirfd,
+                path.as_ptr(),
+                &how as *const OpenHow,
+                std::mem::size_of::<OpenHow>(),
+            )
+        };
+        if fd >= 0 {
+            return Ok(unsafe { File::from_raw_fd(fd as libc::c_int) });
+        }
+
+        let err = io::Error::last_os_error();
+        // EAGAIN means a concurrent rename raced with the lookup
+        if err.raw_os_error() != Some(libc::EAGAIN) || retries == OPENAT2_RETRIES {
+            return Err(err);
+        }
+        retries += 1;
+    }
+}
+
+// Apply the given attribute changes to the file referred to by handle, or
+// to file if given
+#[allow(clippy::too_many_arguments)]
+fn set_attrs(
+    handle: &File,
+    file: Option<&File>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+) -> io::Result<()> {
+    if let Some(mode) = mode {
+        let mode = (mode & 0o7777) as libc::mode_t;
+        match file {
+            Some(file) => check_ret(unsafe { libc::fchmod(file.as_raw_fd(), mode) })?,
+            None => chmod_handle(handle, mode)?,
+        }
+    }
+
+    if uid.is_some() || gid.is_some() {
+        // -1 leaves the id unchanged
+        let uid = uid.unwrap_or(u32::MAX);
+        let gid = gid.unwrap_or(u32::MAX);
+        check_ret(match file {
+            Some(file) => unsafe { libc::fchown(file.as_raw_fd(), uid, gid) },
+            None => unsafe {
+                libc::fchownat(
+                    handle.as_raw_fd(),
+                    EMPTY_PATH.as_ptr() as *const libc::c_char,
+                    uid,
+                    gid,
+                    libc::AT_EMPTY_PATH,
+                )
+            },
+        })?;
+    }
+
+    if let Some(size) = size {
+        match file {
+            Some(file) => file.set_len(size)?,
+            None => reopen(handle, libc::O_WRONLY)?.set_len(size)?,
+        }
+    }
+
+    if atime.is_some() || mtime.is_some() {
+        let times = [
+            time_or_now_to_timespec(atime),
+            time_or_now_to_timespec(mtime),
+        ];
+        match file {
+            Some(file) => check_ret(unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) })?,
+            None => utimens_handle(handle, &times)?,
+        }
+    }
+
+    Ok(())
+}
+
+// fchmod() and futimens() don't accept an O_PATH fd, and fchmodat() doesn't
+// accept AT_EMPTY_PATH, but the handle's path in /proc refers to the same
+// file
+
+#[cfg(target_os = "linux")]
+fn chmod_handle(handle: &File, mode: libc::mode_t) -> io::Result<()> {
+    let path = cstr(&fd_path(handle))?;
+    check_ret(unsafe { libc::chmod(path.as_ptr(), mode) })
+}
+
+#[cfg(target_os = "linux")]
+fn utimens_handle(handle: &File, times: &[libc::timespec; 2]) -> io::Result<()> {
+    let path = cstr(&fd_path(handle))?;
+    check_ret(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })
+}
+
+// Create a hard link at path in dir to the file referred to by handle.
+// linkat() with AT_EMPTY_PATH requires CAP_DAC_READ_SEARCH, but following the
+// handle's path in /proc does not.
+#[cfg(target_os = "linux")]
+fn link_handle(handle: &File, dir: &Dir, path: &Path) -> io::Result<()> {
+    let oldpath = cstr(&fd_path(handle))?;
+    let newpath = cstr(path)?;
+    check_ret(unsafe {
+        libc::linkat(
+            libc::AT_FDCWD,
+            oldpath.as_ptr(),
+            dir.as_raw_fd(),
+            newpath.as_ptr(),
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    })
+}
+
+thread_local! {
+    // fuser doesn't give us access to the FUSE device, so we can't splice
+    // data from the backing file to the kernel. Instead it is read into this
+    // buffer, which is reused for every read on the thread rather than
+    // allocated and zeroed each time, and the kernel copies it from there
+    // when we reply.
+    static READ_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// Read from file at offset until buffer is full or we reach the end of the
+// file, and return the number of bytes read. The backing file may return
+// fewer bytes than we asked for without being at the end of the file, so only
+// a zero-length read is treated as EOF. If we fail after reading some data we
+// return what we have, as read() would; the caller will get the error when it
+// retries from there.
+fn read_full(file: &impl FileExt, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    let _syscall = trace_span!("pread").entered();
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match file.read_at(&mut buffer[pos..], offset + pos as u64) {
+            Ok(0) => break,
+            Ok(bytesin) => pos += bytesin,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) if pos > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(pos)
+}
+
+// An empty path for *at() syscalls with AT_EMPTY_PATH
+const EMPTY_PATH: &[u8] = b"\0";
+
+// The path of file's fd in /proc. Opening it opens the file the fd refers to,
+// which lets us reopen an O_PATH handle with real access flags.
+#[cfg(target_os = "linux")]
+fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+// Open the file referred to by an O_PATH handle with flags
+#[cfg(target_os = "linux")]
+fn reopen(handle: &File, flags: i32) -> io::Result<File> {
+    let path = cstr(&fd_path(handle))?;
+    let flags = flags | libc::O_LARGEFILE | libc::O_CLOEXEC;
+    let fd = unsafe { libc::open(path.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// Return the file handle of the file referred to by an O_PATH handle, as a
+// struct file_handle: its size in bytes, its type and then the handle itself.
+// It is kept in u32s so that it is aligned like the struct.
+#[cfg(target_os = "linux")]
+fn file_handle(handle: &File) -> io::Result<Vec<u32>> {
+    let mut file_handle = vec![0u32; 2 + libc::MAX_HANDLE_SZ as usize / 4];
+    file_handle[0] = libc::MAX_HANDLE_SZ as u32;
+    let mut mount_id = 0;
+    check_ret(unsafe {
+        libc::name_to_handle_at(
+            handle.as_raw_fd(),
+            EMPTY_PATH.as_ptr() as *const libc::c_char,
+            file_handle.as_mut_ptr() as *mut libc::file_handle,
+            &mut mount_id,
+            libc::AT_EMPTY_PATH,
+        )
+    })?;
+    let size = file_handle[0] as usize;
+    file_handle.truncate(2 + size.div_ceil(4));
+    Ok(file_handle)
+}
+
+// Open an O_PATH handle for the file with file_handle on the same filesystem
+// as root. This fails with ESTALE if the file has been deleted.
+#[cfg(target_os = "linux")]
+fn open_by_handle(root: &Dir, file_handle: &[u32]) -> io::Result<File> {
+    let mut file_handle = file_handle.to_vec();
+    let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe {
+        libc::open_by_handle_at(
+            root.as_raw_fd(),
+            file_handle.as_mut_ptr() as *mut libc::file_handle,
+            flags,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// The path relative to root of the file referred to by handle, as the kernel
+// last knew it. It may be stale, or not below root at all.
+#[cfg(target_os = "linux")]
+fn handle_path(root: &Dir, handle: &File) -> io::Result<PathBuf> {
+    let root_path = std::fs::read_link(format!("/proc/self/fd/{}", root.as_raw_fd()))?;
+    let path = std::fs::read_link(fd_path(handle))?;
+    path.strip_prefix(&root_path)
+        .map(Path::to_path_buf)
+        .map_err(|_| io::Error::from_raw_os_error(libc::ESTALE))
+}
+
+// Read the target of the symlink referred to by an O_PATH handle
+fn read_link(handle: &File) -> io::Result<Vec<u8>> {
+    let mut target = vec![0u8; libc::PATH_MAX as usize];
+    let ret = unsafe {
+        libc::readlinkat(
+            handle.as_raw_fd(),
+            EMPTY_PATH.as_ptr() as *const libc::c_char,
+            target.as_mut_ptr() as *mut libc::c_char,
+            target.len(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    target.truncate(ret as usize);
+    Ok(target)
+}
+
+fn fstatat(dirfd: libc::c_int, name: &OsStr) -> io::Result<stat> {
+    let _syscall = trace_span!("fstatat").entered();
+    let name = CString::new(name.as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<stat>::uninit();
+    let ret = unsafe {
+        fstatat64(
+            dirfd,
+            name.as_ptr(),
+            stat.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+// Stat path in dir without following a symlink. This is used rather than
+// Dir::metadata, which uses libc's stat.
+fn stat_at(dir: &Dir, path: &Path) -> io::Result<stat> {
+    fstatat(dir.as_raw_fd(), path.as_os_str())
+}
+
+// Set once statx() has returned ENOSYS, so we don't keep trying it
+#[cfg(target_os = "linux")]
+static STATX_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Open the subdirectory name of dirfd for reading, without following a
+// symlink
+fn open_subdir(dirfd: libc::c_int, name: &OsStr) -> io::Result<File> {
+    let _syscall = trace_span!("openat").entered();
+    let name = CString::new(name.as_bytes())?;
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dirfd, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// statx() file, requesting only the fields in mask. This allows the backing
+// filesystem to skip fetching anything we don't need. Returns None if the
+// kernel doesn't support statx, in which case the caller should use fstat.
+#[cfg(target_os = "linux")]
+fn statx(file: &File, mask: libc::c_uint) -> io::Result<Option<libc::statx>> {
+    let _syscall = trace_span!("statx").entered();
+    if STATX_UNSUPPORTED.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+
+    let mut statx = std::mem::MaybeUninit::<libc::statx>::uninit();
+    let ret = unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            EMPTY_PATH.as_ptr() as *const libc::c_char,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            mask,
+            statx.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            warn!("Kernel does not support statx: using fstat");
+            STATX_UNSUPPORTED.store(true, Ordering::Relaxed);
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(unsafe { statx.assume_init() }))
+}
+
+// libc::makedev is only unsafe in older releases of libc
+#[cfg(target_os = "linux")]
+#[allow(unused_unsafe)]
+fn makedev(major: u32, minor: u32) -> libc::dev_t {
+    unsafe { libc::makedev(major, minor) }
+}
+
+// The identity of file in the backing tree
+#[cfg(target_os = "linux")]
+fn backing_id(file: &File) -> io::Result<BackingId> {
+    match statx(file, libc::STATX_INO)? {
+        Some(statx) => Ok(BackingId {
+            dev: makedev(statx.stx_dev_major, statx.stx_dev_minor),
+            ino: statx.stx_ino,
+        }),
+        None => Ok(BackingId::from_stat(&fstat(file)?)),
+    }
+}
+
+// Whether handle refers to a file which has since been deleted and replaced
+// by the file with stat, which has the same backing id. A file's ctime changes
+// whenever anything else about it does, so a different ctime only means a
+// different file if handle's has also been deleted, which stat's can't have
+// been if it was just found.
+fn replaced(handle: &File, stat: &stat) -> bool {
+    matches!(fstat(handle), Ok(old) if old.st_nlink == 0
+        && (old.st_ctime, old.st_ctime_nsec) != (stat.st_ctime, stat.st_ctime_nsec))
+}
+
+// The attributes of file to return to the kernel, including its birth time
+// if the backing filesystem records it
+#[cfg(target_os = "linux")]
+fn file_attr(ino: u64, file: &File) -> io::Result<FileAttr> {
+    let statx = match statx(file, libc::STATX_BASIC_STATS | libc::STATX_BTIME)? {
+        Some(statx) => statx,
+        None => return Ok(stat_to_fileattr(ino, &fstat(file)?)),
+    };
+
+    let mut stat: stat = unsafe { std::mem::zeroed() };
+    stat.st_dev = makedev(statx.stx_dev_major, statx.stx_dev_minor);
+    stat.st_ino = statx.stx_ino;
+    stat.st_mode = statx.stx_mode as libc::mode_t;
+    stat.st_nlink = statx.stx_nlink as libc::nlink_t;
+    stat.st_uid = statx.stx_uid;
+    stat.st_gid = statx.stx_gid;
+    stat.st_rdev = makedev(statx.stx_rdev_major, statx.stx_rdev_minor);
+    stat.st_size = statx.stx_size as libc::off64_t;
+    stat.st_blksize = statx.stx_blksize as libc::blksize_t;
+    stat.st_blocks = statx.stx_blocks as libc::blkcnt64_t;
+
+    // The times are taken straight from statx, as time_t is only 32 bits on
+    // 32-bit targets
+    let time = |t: libc::statx_timestamp| get_system_time(t.tv_sec, t.tv_nsec as i64);
+    let mut fileattr = stat_to_fileattr(ino, &stat);
+    fileattr.atime = time(statx.stx_atime);
+    fileattr.mtime = time(statx.stx_mtime);
+    fileattr.ctime = time(statx.stx_ctime);
+    if statx.stx_mask & libc::STATX_BTIME != 0 {
+        fileattr.crtime = time(statx.stx_btime);
+    }
+    Ok(fileattr)
+}
+
+fn fstat(file: &File) -> io::Result<stat> {
+    let _syscall = trace_span!("fstat").entered();
+    let mut stat = std::mem::MaybeUninit::<stat>::uninit();
+    if unsafe { fstat64(file.as_raw_fd(), stat.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+// Every inode the kernel holds a reference to keeps an fd open, so raise our
+// fd limit as far as we're allowed
+fn raise_nofile_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return warn!("getrlimit: {}", io::Error::last_os_error());
+    }
+
+    limit.rlim_cur = limit.rlim_max;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } < 0 {
+        return warn!("setrlimit: {}", io::Error::last_os_error());
+    }
+    debug!("fd limit: {}", limit.rlim_cur);
+}
+
+// Switch every thread to uid and gid, with no supplementary groups. The group
+// must be changed first, as we can't once we aren't root.
+fn drop_privileges(uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::setgroups(0, std::ptr::null()) } < 0
+        || unsafe { libc::setgid(gid) } < 0
+        || unsafe { libc::setuid(uid) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Restrict the threads serving requests with Landlock
+#[cfg(target_os = "linux")]
+fn restrict_access(passfs: &PassFs, root: &Path) -> Result<()> {
+    let ruleset = match Ruleset::new(root, &passfs.config) {
+        Ok(Some(ruleset)) => Arc::new(ruleset),
+        Ok(None) => {
+            warn!("Landlock is not supported by the kernel: access is not restricted");
+            return Ok(());
+        }
+        Err(err) => return Err(err).chain_err(|| "Error creating Landlock ruleset"),
+    };
+    passfs
+        .workers
+        .run_on_all(move || ruleset.restrict_self())
+        .chain_err(|| "Error restricting access with Landlock")
+}
+
+// The options to mount with for config
+fn mount_options(config: &Config) -> Vec<&'static OsStr> {
+    let mut mountopts = Vec::new();
+    // FreeBSD only permits root to mount with allow_other, where Linux lets
+    // /etc/fuse.conf permit other users to
+    if config.allow_other {
+        mountopts.extend([OsStr::new("-o"), OsStr::new("allow_other")]);
+    }
+    if config.check_permissions == PermissionCheck::Kernel {
+        mountopts.extend([OsStr::new("-o"), OsStr::new("default_permissions")]);
     }
+    if config.mount_noexec {
+        mountopts.extend([OsStr::new("-o"), OsStr::new("noexec")]);
+    }
+    mountopts
 }
 
-pub fn run(mountpoint: &str, root_path: &str) -> Result<()> {
+pub fn run(mountpoint: &str, root_path: &str, config: Config) -> Result<()> {
     let path = Path::new(mountpoint);
-    let mountopts: &[&OsStr] = &[];
-    let passfs = PassFs::new(root_path)?;
+    let mountopts = mount_options(&config);
+    let runs_commands = config.transforms.iter().any(|rule| {
+        matches!(
+            rule.transform,
+            Transform::Command(_) | Transform::Codec { .. } | Transform::Compress
+        )
+    });
+    if (config.seccomp || config.landlock) && (runs_commands || config.denial_hook.is_some()) {
+        bail!(
+            "Unable to run transform commands or a denial hook with a seccomp filter or Landlock"
+        );
+    }
+    raise_nofile_limit();
+    let mut passfs = PassFs::new(root_path, config)?;
+    if let Some(address) = &passfs.config.metrics_address {
+        #[cfg(feature = "metrics")]
+        metrics::serve(address, Arc::clone(&passfs.metrics))
+            .chain_err(|| format!("Error serving metrics on {}", address))?;
+        #[cfg(not(feature = "metrics"))]
+        bail!(
+            "Unable to serve metrics on {}: built without metrics",
+            address
+        );
+    }
+
+    let control_socket = passfs.config.control_socket.clone();
+    if let Some(socket) = &control_socket {
+        let introspection = Arc::new(Introspection::new());
+        passfs.introspection = Some(Arc::clone(&introspection));
+        control::serve(
+            socket,
+            path.to_path_buf(),
+            passfs.config.clone(),
+            Arc::clone(&passfs.metrics),
+            introspection,
+        )
+        .chain_err(|| format!("Error serving control socket {}", socket.display()))?;
+    }
 
-    fuser::mount(passfs, &path, mountopts)
+    let run_as = passfs.config.run_as;
+    let landlock = passfs.config.landlock;
+    let seccomp = passfs.config.seccomp;
+    let result = Session::new(passfs, path, &mountopts)
         .chain_err(|| format!("Error mounting passfs on {}", mountpoint))
+        .and_then(|mut session| {
+            if let Some((uid, gid)) = run_as {
+                drop_privileges(uid, gid)
+                    .chain_err(|| format!("Error switching to {}:{}", uid, gid))?;
+                info!("Running as {}:{}", uid, gid);
+            }
+            if landlock {
+                #[cfg(target_os = "linux")]
+                restrict_access(&session.filesystem, Path::new(root_path))?;
+                #[cfg(not(target_os = "linux"))]
+                bail!("Unable to restrict access: Landlock is only available on Linux");
+            }
+            if seccomp {
+                #[cfg(all(
+                    target_os = "linux",
+                    any(target_arch = "x86_64", target_arch = "aarch64")
+                ))]
+                seccomp::restrict().chain_err(|| "Error installing seccomp filter")?;
+                #[cfg(not(target_os = "linux"))]
+                bail!("Unable to install seccomp filter: seccomp is only available on Linux");
+                #[cfg(all(
+                    target_os = "linux",
+                    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+                ))]
+                bail!("Unable to install seccomp filter: unsupported architecture");
+            }
+            session
+                .run()
+                .chain_err(|| format!("Error serving passfs on {}", mountpoint))
+        });
+    if let Some(socket) = &control_socket {
+        if let Err(err) = std::fs::remove_file(socket) {
+            warn!("Error removing {}: {}", socket.display(), err);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // A new directory to use as the root of a PassFs, which the caller
+    // removes
+    fn temp_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("passfs-unit-{}-{}", std::process::id(), name));
+        std::fs::create_dir(&root).unwrap();
+        root
+    }
+
+    fn passfs(root: &Path, config: Config) -> PassFs {
+        PassFs::new(root.to_str().unwrap(), config).unwrap()
+    }
+
+    #[test]
+    fn child_path_rejects_escapes() {
+        let root = temp_root("child_path");
+        let mut passfs = passfs(&root, Config::default());
+        for name in ["", ".", "..", "/", "/etc", "../etc", "a/b", "a\0b", "\0"] {
+            let err = passfs.child_path(1, OsStr::new(name)).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL), "{:?}", name);
+        }
+        assert_eq!(
+            passfs.child_path(1, OsStr::new("..a")).unwrap(),
+            Path::new("..a")
+        );
+        drop(passfs);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn nanosecond_timestamps() {
+        let mut stat: stat = unsafe { std::mem::zeroed() };
+        stat.st_mode = libc::S_IFREG | 0o644;
+        stat.st_atime = 1;
+        stat.st_atime_nsec = 1;
+        stat.st_mtime = 1_600_000_000;
+        stat.st_mtime_nsec = 123_456_789;
+        stat.st_ctime = -2;
+        stat.st_ctime_nsec = 250_000_000;
+
+        let fileattr = stat_to_fileattr(2, &stat);
+        assert_eq!(fileattr.atime, UNIX_EPOCH + Duration::new(1, 1));
+        assert_eq!(
+            fileattr.mtime,
+            UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789)
+        );
+        // Before the epoch, the nanoseconds still count forwards
+        assert_eq!(fileattr.ctime, UNIX_EPOCH - Duration::new(1, 750_000_000));
+    }
+
+    // The timestamps of a backing file survive being set through
+    // time_or_now_to_timespec and read back through stat_to_fileattr
+    #[test]
+    fn nanosecond_timestamps_round_trip() {
+        let root = temp_root("timestamps");
+        let path = root.join("file");
+        let file = File::create(&path).unwrap();
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 987_654_321);
+        let times = [
+            time_or_now_to_timespec(Some(TimeOrNow::SpecificTime(mtime))),
+            time_or_now_to_timespec(Some(TimeOrNow::SpecificTime(mtime))),
+        ];
+        assert_eq!(
+            unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) },
+            0
+        );
+
+        let fileattr = stat_to_fileattr(2, &fstat(&file).unwrap());
+        assert_eq!(fileattr.atime, mtime);
+        assert_eq!(fileattr.mtime, mtime);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    // A backing file with content, which returns at most chunk bytes from
+    // each read, and fails each read in turn with the errors in errors
+    struct FakeFile {
+        content: Vec<u8>,
+        chunk: usize,
+        errors: RefCell<Vec<i32>>,
+        reads: Cell<usize>,
+    }
+
+    impl FakeFile {
+        fn new(content: &[u8], chunk: usize, errors: &[i32]) -> FakeFile {
+            FakeFile {
+                content: content.to_vec(),
+                chunk,
+                errors: RefCell::new(errors.iter().rev().copied().collect()),
+                reads: Cell::new(0),
+            }
+        }
+    }
+
+    impl FileExt for FakeFile {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            if let Some(errno) = self.errors.borrow_mut().pop().filter(|errno| *errno != 0) {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            let start = self.content.len().min(offset as usize);
+            let end = self.content.len().min(start + buf.len().min(self.chunk));
+            buf[..end - start].copy_from_slice(&self.content[start..end]);
+            Ok(end - start)
+        }
+
+        // Only reads are tested
+        fn write_at(&self, _buf: &[u8], _offset: u64) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    // Short reads before the end of the file are followed by more reads
+    #[test]
+    fn read_full_short_reads() {
+        let file = FakeFile::new(b"0123456789", 3, &[]);
+        let mut buffer = [0; 8];
+        assert_eq!(read_full(&file, &mut buffer, 1).unwrap(), 8);
+        assert_eq!(&buffer, b"12345678");
+        assert_eq!(file.reads.get(), 3);
+    }
+
+    // Only a read of nothing is the end of the file
+    #[test]
+    fn read_full_eof() {
+        let file = FakeFile::new(b"0123456789", 4, &[]);
+        let mut buffer = [0; 8];
+        assert_eq!(read_full(&file, &mut buffer, 4).unwrap(), 6);
+        assert_eq!(&buffer[..6], b"456789");
+        assert_eq!(file.reads.get(), 3);
+        assert_eq!(read_full(&file, &mut buffer, 10).unwrap(), 0);
+        assert_eq!(read_full(&file, &mut buffer, 20).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_full_retries_eintr() {
+        let file = FakeFile::new(b"0123456789", 4, &[libc::EINTR, 0, libc::EINTR]);
+        let mut buffer = [0; 6];
+        assert_eq!(read_full(&file, &mut buffer, 0).unwrap(), 6);
+        assert_eq!(&buffer, b"012345");
+        assert_eq!(file.reads.get(), 4);
+    }
+
+    // An error is returned if nothing was read, and otherwise what was read
+    // is, as read() would
+    #[test]
+    fn read_full_errors() {
+        let file = FakeFile::new(b"0123456789", 4, &[libc::EIO]);
+        let mut buffer = [0; 6];
+        let err = read_full(&file, &mut buffer, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+        let file = FakeFile::new(b"0123456789", 4, &[0, libc::EIO]);
+        assert_eq!(read_full(&file, &mut buffer, 0).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"0123");
+    }
+
+    // Released handles are reused before new ones are allocated, and a
+    // handle is never given out twice while it is in use
+    #[test]
+    fn fh_reuse() {
+        let root = temp_root("fh_reuse");
+        let mut passfs = passfs(&root, Config::default());
+        let fhs: Vec<_> = (0..4).map(|_| passfs.get_fh()).collect();
+        let mut values: Vec<_> = fhs.iter().map(|fh| fh.value()).collect();
+        values.sort();
+        values.dedup();
+        assert_eq!(values.len(), 4);
+
+        passfs.put_fh(fhs[1]);
+        passfs.put_fh(fhs[3]);
+        let mut reused = [passfs.get_fh().value(), passfs.get_fh().value()];
+        reused.sort();
+        assert_eq!(reused, [fhs[1].value(), fhs[3].value()]);
+
+        let new = passfs.get_fh();
+        assert!(!fhs.contains(&new), "{:?}", new);
+        drop(passfs);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -1,16 +1,1525 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use error_chain::ChainedError;
+use passfs::{
+    AccessPolicy, BranchPolicy, CacheMode, FaultRule, FilterRule, IdMapping, LatencyRule,
+    ModeOverride, Oversize, PermissionCheck, PolicyOp, Principal, RateLimit, RateLimitKey,
+    Redaction, RenameRule, Timestamps, Transform, TransformRule, VirtualContent, VirtualFile,
+};
 
-use simple_logger::SimpleLogger;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Component, Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod bench;
+#[cfg(feature = "otel")]
+mod otel;
+mod replay;
+
+// The entry timeout with --nfs-export, unless another is given
+const NFS_ENTRY_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Parse a timeout given in seconds, which may be fractional
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("{} is not a valid number of seconds", value)),
+    }
+}
+
+// Parse a path below the root, which must not leave it
+fn parse_subpath(value: &str) -> Result<PathBuf, String> {
+    let path = Path::new(value);
+    let mut subpath = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => subpath.push(name),
+            Component::CurDir => (),
+            _ => return Err(format!("{} is not a path below the root", value)),
+        }
+    }
+    if subpath.as_os_str().is_empty() {
+        return Err(format!("{} is not a path below the root", value));
+    }
+    Ok(subpath)
+}
+
+// Parse BACKING:PRESENTED[:COUNT]
+fn parse_id_mapping(value: &str) -> Result<IdMapping, String> {
+    let invalid = || format!("{} is not a valid id mapping", value);
+    let fields = value
+        .split(':')
+        .map(|field| field.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    let (backing, presented, count) = match fields[..] {
+        [backing, presented] => (backing, presented, 1),
+        [backing, presented, count] if count > 0 => (backing, presented, count),
+        _ => return Err(invalid()),
+    };
+    if backing.checked_add(count - 1).is_none() || presented.checked_add(count - 1).is_none() {
+        return Err(invalid());
+    }
+    Ok(IdMapping {
+        backing,
+        presented,
+        count,
+    })
+}
+
+// Parse a time given in seconds since the epoch, like SOURCE_DATE_EPOCH
+fn parse_epoch(value: &str) -> Result<SystemTime, String> {
+    match value.parse::<u64>() {
+        Ok(secs) => UNIX_EPOCH
+            .checked_add(Duration::from_secs(secs))
+            .ok_or_else(|| format!("{} is out of range", value)),
+        Err(_) => Err(format!("{} is not a valid number of seconds", value)),
+    }
+}
+
+// Parse UID:GID
+fn parse_owner(value: &str) -> Result<(u32, u32), String> {
+    let mut fields = value.splitn(2, ':').map(|field| field.parse::<u32>());
+    match (fields.next(), fields.next()) {
+        (Some(Ok(uid)), Some(Ok(gid))) => Ok((uid, gid)),
+        _ => Err(format!("{} is not a valid UID:GID", value)),
+    }
+}
+
+// Parse an octal mode, which replaces the permission bits, or a sequence of
+// &MASK and |BITS terms applied to them in order
+fn parse_mode(value: &str) -> Result<ModeOverride, String> {
+    let invalid = || format!("{} is not a valid mode", value);
+    let octal = |digits: &str| match u16::from_str_radix(digits, 8) {
+        Ok(bits) if bits <= 0o7777 && !digits.is_empty() => Ok(bits),
+        _ => Err(invalid()),
+    };
+
+    if !value.starts_with(['&', '|']) {
+        return Ok(ModeOverride {
+            and: 0,
+            or: octal(value)?,
+        });
+    }
+
+    let mut mode = ModeOverride { and: 0o7777, or: 0 };
+    let mut rest = value;
+    while let Some(op) = rest.chars().next() {
+        let end = rest[1..].find(['&', '|']).map_or(rest.len(), |i| i + 1);
+        let bits = octal(&rest[1..end])?;
+        match op {
+            '&' => {
+                mode.and &= bits;
+                mode.or &= bits;
+            }
+            '|' => mode.or |= bits,
+            _ => return Err(invalid()),
+        }
+        rest = &rest[end..];
+    }
+    Ok(mode)
+}
+
+fn id_map(matches: &ArgMatches, name: &str) -> Vec<IdMapping> {
+    matches.values_of(name).map_or_else(Vec::new, |values| {
+        values
+            .map(|value| parse_id_mapping(value).unwrap())
+            .collect()
+    })
+}
+
+// Parse FROM/TO. Neither can contain a / as it can't appear in a name.
+fn parse_rename_rule(value: &str) -> Result<RenameRule, String> {
+    let mut fields = value.split('/');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(from), Some(to), None) if !from.is_empty() => Ok(RenameRule {
+            from: from.into(),
+            to: to.into(),
+        }),
+        _ => Err(format!("{} is not a valid FROM/TO rename rule", value)),
+    }
+}
+
+// Parse WHO:OPS:GLOB, where WHO is uid=UID or gid=GID and OPS is a possibly
+// empty list of read, write and list separated by commas
+fn parse_access_policy(value: &str) -> Result<AccessPolicy, String> {
+    let invalid = || format!("{} is not a valid WHO:OPS:GLOB policy", value);
+    let mut fields = value.splitn(3, ':');
+    let (who, ops, pattern) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(who), Some(ops), Some(pattern)) if !pattern.is_empty() => (who, ops, pattern),
+        _ => return Err(invalid()),
+    };
+    let principal = match who.split_once('=') {
+        Some(("uid", uid)) => Principal::Uid(uid.parse().map_err(|_| invalid())?),
+        Some(("gid", gid)) => Principal::Gid(gid.parse().map_err(|_| invalid())?),
+        _ => return Err(invalid()),
+    };
+    let operations = ops
+        .split(',')
+        .filter(|op| !op.is_empty())
+        .map(|op| match op {
+            "read" => Ok(PolicyOp::Read),
+            "write" => Ok(PolicyOp::Write),
+            "list" => Ok(PolicyOp::List),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(AccessPolicy {
+        principal,
+        patterns: vec![pattern.into()],
+        operations,
+    })
+}
+
+// Parse OPS:ERRNO:PROBABILITY[:GLOB], where OPS is * or a list of operations
+// separated by commas
+fn parse_fault_rule(value: &str) -> Result<FaultRule, String> {
+    let invalid = || format!("{} is not a valid OPS:ERRNO:PROBABILITY[:GLOB]", value);
+    let mut fields = value.splitn(4, ':');
+    let (ops, errno, probability) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(ops), Some(errno), Some(probability)) => (ops, errno, probability),
+        _ => return Err(invalid()),
+    };
+    let errno = match errno {
+        "EIO" => libc::EIO,
+        "ENOSPC" => libc::ENOSPC,
+        "EACCES" => libc::EACCES,
+        "EPERM" => libc::EPERM,
+        "ENOENT" => libc::ENOENT,
+        "EROFS" => libc::EROFS,
+        "EDQUOT" => libc::EDQUOT,
+        "EAGAIN" => libc::EAGAIN,
+        "EINTR" => libc::EINTR,
+        "ETIMEDOUT" => libc::ETIMEDOUT,
+        // An errno of 0 would be a reply of success without its data, and the
+        // kernel rejects a reply with a negative one
+        errno => match errno.parse() {
+            Ok(errno) if errno > 0 => errno,
+            _ => return Err(invalid()),
+        },
+    };
+    let probability = match probability.parse::<f64>() {
+        Ok(probability) if (0.0..=1.0).contains(&probability) => probability,
+        _ => return Err(invalid()),
+    };
+    let ops = match ops {
+        "*" => Vec::new(),
+        ops => ops.split(',').map(String::from).collect(),
+    };
+    Ok(FaultRule {
+        ops,
+        pattern: fields.next().map(String::from),
+        errno,
+        probability,
+    })
+}
+
+// Parse OPS:MILLISECONDS[:JITTER], where OPS is * or a list of operations
+// separated by commas, and JITTER is also in milliseconds
+fn parse_latency_rule(value: &str) -> Result<LatencyRule, String> {
+    let invalid = || format!("{} is not a valid OPS:MILLISECONDS[:JITTER]", value);
+    let mut fields = value.split(':');
+    let (ops, delay, jitter) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(ops), Some(delay), jitter, None) => (ops, delay, jitter.unwrap_or("0")),
+        _ => return Err(invalid()),
+    };
+    let millis = |value: &str| {
+        value
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid())
+    };
+    let ops = match ops {
+        "*" => Vec::new(),
+        ops => ops.split(',').map(String::from).collect(),
+    };
+    Ok(LatencyRule {
+        ops,
+        delay: millis(delay)?,
+        jitter: millis(jitter)?,
+    })
+}
+
+// Parse GLOB=COMMAND
+fn parse_transform_rule(value: &str) -> Result<TransformRule, String> {
+    match value.split_once('=') {
+        Some((pattern, command)) if !pattern.is_empty() && !command.is_empty() => {
+            Ok(TransformRule {
+                pattern: pattern.into(),
+                transform: Transform::Command(command.into()),
+            })
+        }
+        _ => Err(format!("{} is not a valid GLOB=COMMAND", value)),
+    }
+}
+
+// Parse GLOB=bytes:OFFSET+LEN, GLOB=text:TEXT or GLOB=value:MARKER
+fn parse_redact_rule(value: &str) -> Result<TransformRule, String> {
+    let invalid = || format!("{} is not a valid GLOB=KIND:SPEC redaction", value);
+    let (pattern, spec) = value.split_once('=').ok_or_else(invalid)?;
+    let redaction = match spec.split_once(':') {
+        Some(("bytes", range)) => {
+            let (offset, len) = range.split_once('+').ok_or_else(invalid)?;
+            Redaction::Bytes {
+                offset: offset.parse().map_err(|_| invalid())?,
+                len: len.parse().map_err(|_| invalid())?,
+            }
+        }
+        Some(("text", text)) if !text.is_empty() => Redaction::Text(text.into()),
+        Some(("value", marker)) if !marker.is_empty() => Redaction::Value(marker.into()),
+        _ => return Err(invalid()),
+    };
+    if pattern.is_empty() {
+        return Err(invalid());
+    }
+    Ok(TransformRule {
+        pattern: pattern.into(),
+        transform: Transform::Redact(vec![redaction]),
+    })
+}
+
+// Parse NAME=VALUE, where NAME is the name of a virtual file in the root
+fn parse_virtual_file(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !matches!(name, "" | "." | "..") && !name.contains('/') => {
+            Ok((name.into(), value.into()))
+        }
+        _ => Err(format!("{} is not a valid NAME=VALUE", value)),
+    }
+}
+
+// Return the virtual files given literally or by command, in the order they
+// were given
+fn virtual_files(matches: &ArgMatches) -> Vec<VirtualFile> {
+    let mut files = Vec::new();
+    for (arg, literal) in [("virtual-file", true), ("virtual-command", false)] {
+        if let (Some(indices), Some(values)) = (matches.indices_of(arg), matches.values_of(arg)) {
+            files.extend(indices.zip(values.map(|value| {
+                let (name, value) = parse_virtual_file(value).unwrap();
+                let content = if literal {
+                    VirtualContent::Literal(value.into_bytes())
+                } else {
+                    VirtualContent::Command(value)
+                };
+                VirtualFile { name, content }
+            })));
+        }
+    }
+    files.sort_by_key(|(index, _)| *index);
+    files.into_iter().map(|(_, file)| file).collect()
+}
+
+// Quote value for use as a single word in a shell command
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn validate_number<T: FromStr>(value: String) -> Result<(), String> {
+    match value.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{} is not a valid number", value)),
+    }
+}
 
-const MOUNTPOINT: &str = "/tmp/passfs";
-const ROOT_PATH: &str = "/home/mbooth/tmp";
+// Return the include and exclude rules in the order they were given
+fn filter_rules(matches: &ArgMatches) -> Vec<FilterRule> {
+    let mut rules = Vec::new();
+    if let (Some(indices), Some(values)) =
+        (matches.indices_of("include"), matches.values_of("include"))
+    {
+        rules.extend(indices.zip(values.map(|value| FilterRule::Include(value.into()))));
+    }
+    if let (Some(indices), Some(values)) =
+        (matches.indices_of("exclude"), matches.values_of("exclude"))
+    {
+        rules.extend(indices.zip(values.map(|value| FilterRule::Exclude(value.into()))));
+    }
+    rules.sort_by_key(|(index, _)| *index);
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
+// Read the values substituted for ${NAME} placeholders: the environment,
+// overridden by any NAME=VALUE lines in file. Blank lines and lines starting
+// with # are ignored.
+fn substitutions(file: Option<&str>) -> Result<BTreeMap<String, String>, String> {
+    let mut values: BTreeMap<_, _> = env::vars().collect();
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(values),
+    };
+
+    let content =
+        fs::read_to_string(file).map_err(|err| format!("Unable to read {}: {}", file, err))?;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                values.insert(name.trim_end().into(), value.into());
+            }
+            _ => return Err(format!("{}:{}: expected NAME=VALUE", file, i + 1)),
+        }
+    }
+    Ok(values)
+}
+
+// Return the transform rules given by GLOB=COMMAND or by GLOB alone, in the
+// order they were given
+fn transform_rules(
+    matches: &ArgMatches,
+    substitutions: &BTreeMap<String, String>,
+) -> Vec<TransformRule> {
+    let mut rules = Vec::new();
+    let args = [
+        "transform",
+        "compress",
+        "to-lf",
+        "to-crlf",
+        "substitute",
+        "redact",
+    ];
+    for arg in args {
+        if let (Some(indices), Some(values)) = (matches.indices_of(arg), matches.values_of(arg)) {
+            rules.extend(indices.zip(values.map(|value| {
+                let pattern = value.into();
+                match arg {
+                    "transform" => parse_transform_rule(value).unwrap(),
+                    // Files are stored compressed in the backing tree under
+                    // their own names
+                    "compress" => TransformRule {
+                        pattern,
+                        transform: Transform::Compress,
+                    },
+                    "to-lf" => TransformRule {
+                        pattern,
+                        transform: Transform::ToLf,
+                    },
+                    "to-crlf" => TransformRule {
+                        pattern,
+                        transform: Transform::ToCrlf,
+                    },
+                    "substitute" => TransformRule {
+                        pattern,
+                        transform: Transform::Substitute(substitutions.clone()),
+                    },
+                    _ => parse_redact_rule(value).unwrap(),
+                }
+            })));
+        }
+    }
+    rules.sort_by_key(|(index, _)| *index);
+
+    // Only one rule applies to a file, so consecutive redactions of the same
+    // glob are combined
+    let mut combined: Vec<TransformRule> = Vec::new();
+    for (_, rule) in rules {
+        if let Some(last) = combined.last_mut() {
+            if let (Transform::Redact(redactions), Transform::Redact(more)) =
+                (&mut last.transform, &rule.transform)
+            {
+                if last.pattern == rule.pattern {
+                    redactions.extend(more.iter().cloned());
+                    continue;
+                }
+            }
+        }
+        combined.push(rule);
+    }
+    combined
+}
+
+// Spans are exported until this is taken and dropped by exit()
+#[cfg(feature = "otel")]
+static EXPORTER: std::sync::Mutex<Option<otel::Exporter>> = std::sync::Mutex::new(None);
+
+// Exit with code, once any spans still waiting to be exported have been.
+// process::exit doesn't run destructors, so they would otherwise be lost.
+fn exit(code: i32) -> ! {
+    #[cfg(feature = "otel")]
+    drop(EXPORTER.lock().unwrap().take());
+    process::exit(code)
+}
+
+// Log everything, including records from fuser's log macros, and export spans
+// with OTLP if asked to
+#[cfg(feature = "otel")]
+fn init_tracing(matches: &ArgMatches) {
+    let exported = match matches.value_of("otlp").map(otel::layer).transpose() {
+        Ok(exported) => exported,
+        Err(err) => {
+            eprintln!("Error exporting spans: {}", err);
+            exit(1)
+        }
+    };
+    let (layer, exporter) = exported.unzip();
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(LevelFilter::TRACE))
+        .with(layer)
+        .init();
+    *EXPORTER.lock().unwrap() = exporter;
+}
+
+#[cfg(not(feature = "otel"))]
+fn init_tracing(matches: &ArgMatches) {
+    if matches.is_present("otlp") {
+        eprintln!("Unable to export spans: passfs was built without the otel feature");
+        exit(1)
+    }
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(LevelFilter::TRACE))
+        .init();
+}
 
 fn main() {
-    SimpleLogger::new().init().unwrap();
+    let matches = App::new("passfs")
+        .about("A FUSE filesystem which passes through to a local directory")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(
+            Arg::with_name("rw")
+                .long("rw")
+                .help("Allow modification of the backing directory"),
+        )
+        .arg(
+            Arg::with_name("no-writeback-cache")
+                .long("no-writeback-cache")
+                .help("Pass every write straight through instead of letting the kernel batch them"),
+        )
+        .arg(
+            Arg::with_name("allow-other")
+                .long("allow-other")
+                .help("Let other users use the mount"),
+        )
+        .arg(
+            Arg::with_name("check-permissions")
+                .long("check-permissions")
+                .value_name("BY")
+                .possible_values(&["off", "kernel", "daemon"])
+                .default_value("off")
+                .help("Who checks callers against the mode and owner of each file"),
+        )
+        .arg(
+            Arg::with_name("policy")
+                .long("policy")
+                .value_name("WHO:OPS:GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_access_policy(&value).map(|_| ()))
+                .help("Only let uid=UID or gid=GID reach GLOB, and read, write or list it"),
+        )
+        .arg(
+            Arg::with_name("rate-limit-ops")
+                .long("rate-limit-ops")
+                .value_name("OPS")
+                .validator(validate_number::<NonZeroU32>)
+                .help("Fail requests beyond OPS a second from each caller with EAGAIN"),
+        )
+        .arg(
+            Arg::with_name("rate-limit-bytes")
+                .long("rate-limit-bytes")
+                .value_name("BYTES")
+                .validator(validate_number::<NonZeroU64>)
+                .help("Fail reads and writes beyond BYTES a second from each caller"),
+        )
+        .arg(
+            Arg::with_name("rate-limit-per")
+                .long("rate-limit-per")
+                .value_name("CALLER")
+                .possible_values(&["uid", "pid"])
+                .default_value("uid")
+                .help("Whether rate limits apply to each user or each process"),
+        )
+        .arg(
+            Arg::with_name("rate-limit-delay")
+                .long("rate-limit-delay")
+                .help("Delay reads and writes beyond the rate limits rather than failing them"),
+        )
+        .arg(
+            Arg::with_name("fault")
+                .long("fault")
+                .value_name("OPS:ERRNO:PROBABILITY[:GLOB]")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_fault_rule(&value).map(|_| ()))
+                .help("Fail requests for OPS, or * for all, with ERRNO, such as EIO"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .value_name("OPS:MILLISECONDS[:JITTER]")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_latency_rule(&value).map(|_| ()))
+                .help("Delay replies to requests for OPS, or * for all, by up to JITTER ms more"),
+        )
+        .arg(
+            Arg::with_name("chaos")
+                .long("chaos")
+                .help("Return short reads and directory reads at random, to test applications"),
+        )
+        .arg(
+            Arg::with_name("fault-seed")
+                .long("fault-seed")
+                .value_name("SEED")
+                .validator(validate_number::<u64>)
+                .help("Seed the random choices of --fault, --latency and --chaos"),
+        )
+        .arg(
+            Arg::with_name("run-as")
+                .long("run-as")
+                .value_name("UID:GID")
+                .validator(|value| parse_owner(&value).map(|_| ()))
+                .help("Once mounted, switch to UID:GID with no supplementary groups"),
+        )
+        .arg(
+            Arg::with_name("landlock")
+                .long("landlock")
+                .help("Once mounted, only allow access to files beneath the root"),
+        )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .help("Once mounted, only allow the syscalls passfs needs"),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .value_name("DIR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Merge DIR below ROOT and any earlier branches, like a union mount"),
+        )
+        .arg(
+            Arg::with_name("search-policy")
+                .long("search-policy")
+                .value_name("POLICY")
+                .possible_values(&["ff", "mfs", "ep"])
+                .default_value("ff")
+                .help("Which branch a file in several is used from: first, or most free space"),
+        )
+        .arg(
+            Arg::with_name("create-policy")
+                .long("create-policy")
+                .value_name("POLICY")
+                .possible_values(&["ff", "mfs", "ep"])
+                .default_value("ff")
+                .help("Which branch new files go in: first, most free space, or first with their directory"),
+        )
+        .arg(
+            Arg::with_name("upper")
+                .long("upper")
+                .value_name("DIR")
+                .requires("rw")
+                .help("Write changes to DIR, copying files up from ROOT, which is left unchanged"),
+        )
+        .arg(
+            Arg::with_name("scratch")
+                .long("scratch")
+                .conflicts_with("upper")
+                .help("Allow writes, which go to a temporary directory deleted on unmount"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .conflicts_with_all(&["rw", "scratch"])
+                .help("Present the tree as it was when mounted, ignoring later changes"),
+        )
+        .arg(
+            Arg::with_name("pin-snapshot")
+                .long("pin-snapshot")
+                .requires("snapshot")
+                .help("Keep every file in the snapshot open, so it can still be read if replaced or deleted"),
+        )
+        .arg(
+            Arg::with_name("xdev")
+                .long("xdev")
+                .help("Don't cross filesystem boundaries below the root"),
+        )
+        .arg(
+            Arg::with_name("nfs-export")
+                .long("nfs-export")
+                .conflicts_with_all(&["branch", "upper", "scratch", "snapshot", "run-as"])
+                .help("Allow the mount to be re-exported by the kernel NFS server"),
+        )
+        .arg(
+            Arg::with_name("case-insensitive")
+                .long("case-insensitive")
+                .help("Look up names ignoring case if they don't exist as given"),
+        )
+        .arg(
+            Arg::with_name("rename")
+                .long("rename")
+                .value_name("FROM/TO")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_rename_rule(&value).map(|_| ()))
+                .help("Present names with FROM replaced by TO. FROM may be anchored with ^ or $"),
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only show files matching GLOB, and directories"),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Hide files and directories matching GLOB"),
+        )
+        .arg(
+            Arg::with_name("hide-hidden")
+                .long("hide-hidden")
+                .help("Hide files and directories whose names start with a dot"),
+        )
+        .arg(
+            Arg::with_name("hide-devices")
+                .long("hide-devices")
+                .help("Hide block and character devices"),
+        )
+        .arg(
+            Arg::with_name("hide-sockets")
+                .long("hide-sockets")
+                .help("Hide sockets"),
+        )
+        .arg(
+            Arg::with_name("hide-fifos")
+                .long("hide-fifos")
+                .help("Hide FIFOs"),
+        )
+        .arg(
+            Arg::with_name("hide-empty-dirs")
+                .long("hide-empty-dirs")
+                .help("Hide directories with no visible entries"),
+        )
+        .arg(
+            Arg::with_name("unlisted")
+                .long("unlisted")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("List directories matching GLOB as empty, though their entries exist"),
+        )
+        .arg(
+            Arg::with_name("export")
+                .long("export")
+                .value_name("SUBPATH")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_subpath(&value).map(|_| ()))
+                .help("Only show SUBPATH of the root, and anything below it"),
+        )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("N")
+                .validator(validate_number::<usize>)
+                .help("Show directories more than N levels below the root as empty"),
+        )
+        .arg(
+            Arg::with_name("transform")
+                .long("transform")
+                .value_name("GLOB=COMMAND")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_transform_rule(&value).map(|_| ()))
+                .help("Present files matching GLOB as their content piped through COMMAND"),
+        )
+        .arg(
+            Arg::with_name("decrypt-gpg")
+                .long("decrypt-gpg")
+                .help("Present *.gpg files decrypted by gpg, without the .gpg extension"),
+        )
+        .arg(
+            Arg::with_name("decompress")
+                .long("decompress")
+                .help("Present *.gz and *.zst files decompressed, without the extension"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Store files matching GLOB compressed with zstd"),
+        )
+        .arg(
+            Arg::with_name("to-lf")
+                .long("to-lf")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Present files matching GLOB with CRLF line endings converted to LF"),
+        )
+        .arg(
+            Arg::with_name("to-crlf")
+                .long("to-crlf")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Present files matching GLOB with LF line endings converted to CRLF"),
+        )
+        .arg(
+            Arg::with_name("substitute")
+                .long("substitute")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Present files matching GLOB with ${NAME} replaced by the value of NAME"),
+        )
+        .arg(
+            Arg::with_name("substitutions")
+                .long("substitutions")
+                .value_name("FILE")
+                .help("Substitute values from NAME=VALUE lines in FILE as well as the environment"),
+        )
+        .arg(
+            Arg::with_name("redact")
+                .long("redact")
+                .value_name("GLOB=KIND:SPEC")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_redact_rule(&value).map(|_| ()))
+                .help(
+                    "Mask bytes of files matching GLOB with *: bytes:OFFSET+LEN, \
+                     text:TEXT, or value:MARKER for the rest of the line after MARKER",
+                ),
+        )
+        .arg(
+            Arg::with_name("age-identity")
+                .long("age-identity")
+                .value_name("FILE")
+                .conflicts_with_all(&[
+                    "transform",
+                    "compress",
+                    "to-lf",
+                    "to-crlf",
+                    "substitute",
+                    "redact",
+                    "decrypt-gpg",
+                    "decompress",
+                ])
+                .help("Present every file decrypted by age with the identity in FILE"),
+        )
+        .arg(
+            Arg::with_name("virtual-file")
+                .long("virtual-file")
+                .value_name("NAME=TEXT")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_virtual_file(&value).map(|_| ()))
+                .help("Show a read-only file NAME containing TEXT in the root"),
+        )
+        .arg(
+            Arg::with_name("virtual-command")
+                .long("virtual-command")
+                .value_name("NAME=COMMAND")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_virtual_file(&value).map(|_| ()))
+                .help("Show a read-only file NAME in the root containing the output of COMMAND"),
+        )
+        .arg(
+            Arg::with_name("stats-dir")
+                .long("stats-dir")
+                .help("Show live statistics and configuration in .passfs in the root"),
+        )
+        .arg(
+            Arg::with_name("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .default_value("0")
+                .validator(validate_number::<u64>)
+                .help("Don't serve files larger than this"),
+        )
+        .arg(
+            Arg::with_name("oversize")
+                .long("oversize")
+                .value_name("ACTION")
+                .possible_values(&["hide", "deny"])
+                .default_value("hide")
+                .help("Whether to hide files larger than --max-file-size, or deny opening them"),
+        )
+        .arg(
+            Arg::with_name("map-uid")
+                .long("map-uid")
+                .value_name("BACKING:PRESENTED[:COUNT]")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_id_mapping(&value).map(|_| ()))
+                .help("Present files owned by uid BACKING as owned by PRESENTED"),
+        )
+        .arg(
+            Arg::with_name("map-gid")
+                .long("map-gid")
+                .value_name("BACKING:PRESENTED[:COUNT]")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|value| parse_id_mapping(&value).map(|_| ()))
+                .help("Present files with gid BACKING as having PRESENTED"),
+        )
+        .arg(
+            Arg::with_name("squash-owner")
+                .long("squash-owner")
+                .value_name("UID:GID")
+                .min_values(0)
+                .require_equals(true)
+                .validator(|value| parse_owner(&value).map(|_| ()))
+                .help("Present every file as owned by UID:GID [default: the mounting user]"),
+        )
+        .arg(
+            Arg::with_name("chmod-file")
+                .long("chmod-file")
+                .value_name("MODE")
+                .validator(|value| parse_mode(&value).map(|_| ()))
+                .help("Present files with MODE, or with their mode changed by &MASK and |BITS"),
+        )
+        .arg(
+            Arg::with_name("chmod-dir")
+                .long("chmod-dir")
+                .value_name("MODE")
+                .validator(|value| parse_mode(&value).map(|_| ()))
+                .help(
+                    "Present directories with MODE, or with their mode changed by &MASK and |BITS",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-exec")
+                .long("no-exec")
+                .help("Present files other than directories without execute bits"),
+        )
+        .arg(
+            Arg::with_name("mount-noexec")
+                .long("mount-noexec")
+                .help("Mount with noexec so nothing in the mount can be executed"),
+        )
+        .arg(
+            Arg::with_name("clamp-time")
+                .long("clamp-time")
+                .value_name("EPOCH")
+                .conflicts_with("fix-time")
+                .validator(|value| parse_epoch(&value).map(|_| ()))
+                .help("Present timestamps later than EPOCH seconds as EPOCH"),
+        )
+        .arg(
+            Arg::with_name("fix-time")
+                .long("fix-time")
+                .value_name("EPOCH")
+                .validator(|value| parse_epoch(&value).map(|_| ()))
+                .help("Present every timestamp as EPOCH seconds"),
+        )
+        .arg(
+            Arg::with_name("zero-fill-reads")
+                .long("zero-fill-reads")
+                .help("Pad reads past the end of a file with zeros instead of truncating them"),
+        )
+        .arg(
+            Arg::with_name("attr-timeout")
+                .long("attr-timeout")
+                .value_name("SECS")
+                .default_value("0")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("How long the kernel may cache file attributes"),
+        )
+        .arg(
+            Arg::with_name("entry-timeout")
+                .long("entry-timeout")
+                .value_name("SECS")
+                .default_value("0")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("How long the kernel may cache name lookups"),
+        )
+        .arg(
+            Arg::with_name("negative-timeout")
+                .long("negative-timeout")
+                .value_name("SECS")
+                .default_value("0")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("How long the kernel may cache that a name doesn't exist"),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .value_name("MODE")
+                .possible_values(&["auto", "keep", "direct"])
+                .default_value("auto")
+                .help("How the kernel may cache file contents"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .validator(validate_number::<usize>)
+                .help("Number of worker threads for file I/O [default: number of CPUs]"),
+        )
+        .arg(
+            Arg::with_name("fd-cache")
+                .long("fd-cache")
+                .value_name("N")
+                .default_value("0")
+                .validator(validate_number::<usize>)
+                .help("Keep up to N released files open for reuse"),
+        )
+        .arg(
+            Arg::with_name("max-inodes")
+                .long("max-inodes")
+                .value_name("N")
+                .default_value("0")
+                .validator(validate_number::<usize>)
+                .help("Keep inodes the kernel has forgotten, up to N inodes in all"),
+        )
+        .arg(
+            Arg::with_name("dir-cache-timeout")
+                .long("dir-cache-timeout")
+                .value_name("SECS")
+                .default_value("0")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("How long a directory listing may be reused by later opens"),
+        )
+        .arg(
+            Arg::with_name("read-ahead")
+                .long("read-ahead")
+                .value_name("BYTES")
+                .default_value("0")
+                .validator(validate_number::<usize>)
+                .help("Prefetch this many bytes ahead of sequential reads"),
+        )
+        .arg(
+            Arg::with_name("mmap-threshold")
+                .long("mmap-threshold")
+                .value_name("BYTES")
+                .default_value("0")
+                .validator(validate_number::<usize>)
+                .help("Serve reads from a mapping for files of at least this size, without --rw"),
+        )
+        .arg(
+            Arg::with_name("max-write")
+                .long("max-write")
+                .value_name("BYTES")
+                .validator(validate_number::<u32>)
+                .help("Largest write the kernel may send in one request"),
+        )
+        .arg(
+            Arg::with_name("max-readahead")
+                .long("max-readahead")
+                .value_name("BYTES")
+                .validator(validate_number::<u32>)
+                .help("Most the kernel may read ahead of a reader"),
+        )
+        .arg(
+            Arg::with_name("max-background")
+                .long("max-background")
+                .value_name("N")
+                .validator(validate_number::<u16>)
+                .help("Most background requests, such as readahead, the kernel may have pending"),
+        )
+        .arg(
+            Arg::with_name("congestion-threshold")
+                .long("congestion-threshold")
+                .value_name("N")
+                .validator(validate_number::<u16>)
+                .help("Pending background requests at which the kernel considers us congested"),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .value_name("ADDRESS")
+                .help("Serve Prometheus metrics over HTTP on ADDRESS, e.g. localhost:9100"),
+        )
+        .arg(
+            Arg::with_name("slow-op")
+                .long("slow-op")
+                .value_name("SECS")
+                .default_value("0")
+                .validator(|value| parse_timeout(&value).map(|_| ()))
+                .help("Log requests which take at least this long"),
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("Append a JSON line to FILE for every lookup, open and directory read"),
+        )
+        .arg(
+            Arg::with_name("denial-log")
+                .long("denial-log")
+                .value_name("FILE")
+                .help("Append a JSON line to FILE for every request which is refused"),
+        )
+        .arg(
+            Arg::with_name("denial-hook")
+                .long("denial-hook")
+                .value_name("COMMAND")
+                .help("Run COMMAND with each refused request as JSON on its stdin"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Record every request to FILE, for passfs replay"),
+        )
+        .arg(
+            Arg::with_name("control")
+                .long("control")
+                .value_name("SOCKET")
+                .help("Serve commands from passfs ctl on the unix socket SOCKET"),
+        )
+        .arg(
+            Arg::with_name("otlp")
+                .long("otlp")
+                .value_name("URL")
+                .help("Export request spans to the OTLP/HTTP collector at URL"),
+        )
+        .arg(
+            Arg::with_name("log-callers")
+                .long("log-callers")
+                .help("Log every request with the process which made it"),
+        )
+        .arg(
+            Arg::with_name("ROOT")
+                .help("Directory to export")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("MOUNTPOINT")
+                .help("Where to mount passfs")
+                .required(true),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Compare the performance of passfs with direct access to a generated tree")
+                .arg(
+                    Arg::with_name("files")
+                        .long("files")
+                        .value_name("N")
+                        .default_value("1000")
+                        .validator(validate_number::<usize>)
+                        .help("Number of small files to generate"),
+                )
+                .arg(
+                    Arg::with_name("file-size")
+                        .long("file-size")
+                        .value_name("BYTES")
+                        .default_value("67108864")
+                        .validator(validate_number::<u64>)
+                        .help("Size of the file used to measure read throughput"),
+                )
+                .arg(
+                    Arg::with_name("OPTIONS")
+                        .multiple(true)
+                        .last(true)
+                        .help("Options for the mounted passfs, after --"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("Perform the requests in a recording made with --record on a directory")
+                .arg(
+                    Arg::with_name("no-wait")
+                        .long("no-wait")
+                        .help("Perform requests one after another, not when they were made"),
+                )
+                .arg(
+                    Arg::with_name("RECORDING")
+                        .help("The recording to replay")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("A copy of the tree as it was when recording began")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ctl")
+                .about("Query a mounted passfs through its control socket")
+                .arg(
+                    Arg::with_name("SOCKET")
+                        .help("The control socket of the mount")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("COMMAND")
+                        .possible_values(&["stats", "handles", "inodes", "config"])
+                        .help("What to ask for")
+                        .required(true),
+                ),
+        )
+        .get_matches();
+
+    // Before any other thread is started, so none of them receives the
+    // signals instead
+    let signals = matches.subcommand_name().is_none().then(block_signals);
 
-    if let Err(err) = passfs::run(MOUNTPOINT, ROOT_PATH) {
-        eprintln!("{}", err.display_chain().to_string());
-        process::exit(1)
+    init_tracing(&matches);
+
+    if let Some(matches) = matches.subcommand_matches("ctl") {
+        let socket = matches.value_of("SOCKET").unwrap();
+        match passfs::control_request(Path::new(socket), matches.value_of("COMMAND").unwrap()) {
+            Ok(reply) => print!("{}", reply),
+            Err(err) => {
+                eprintln!("Error querying {}: {}", socket, err);
+                exit(1)
+            }
+        }
+        exit(0)
+    }
+
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let options = bench::Options {
+            files: matches.value_of("files").unwrap().parse().unwrap(),
+            file_size: matches.value_of("file-size").unwrap().parse().unwrap(),
+            args: matches
+                .values_of("OPTIONS")
+                .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+        };
+        if let Err(err) = bench::run(&options) {
+            eprintln!("{}", err.display_chain());
+            exit(1)
+        }
+        exit(0)
     }
+
+    if let Some(matches) = matches.subcommand_matches("replay") {
+        let options = replay::Options {
+            recording: PathBuf::from(matches.value_of("RECORDING").unwrap()),
+            dir: PathBuf::from(matches.value_of("DIR").unwrap()),
+            no_wait: matches.is_present("no-wait"),
+        };
+        if let Err(err) = replay::run(&options) {
+            eprintln!("{}", err.display_chain());
+            exit(1)
+        }
+        exit(0)
+    }
+
+    let substitutions = match substitutions(matches.value_of("substitutions")) {
+        Ok(substitutions) => substitutions,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1)
+        }
+    };
+
+    let mut config = passfs::Config {
+        read_write: matches.is_present("rw") || matches.is_present("scratch"),
+        writeback_cache: !matches.is_present("no-writeback-cache"),
+        allow_other: matches.is_present("allow-other"),
+        check_permissions: match matches.value_of("check-permissions").unwrap() {
+            "kernel" => PermissionCheck::Kernel,
+            "daemon" => PermissionCheck::Daemon,
+            _ => PermissionCheck::Off,
+        },
+        access_policies: matches.values_of("policy").map_or_else(Vec::new, |values| {
+            values
+                .map(|value| parse_access_policy(value).unwrap())
+                .collect()
+        }),
+        rate_limit: match (
+            matches.value_of("rate-limit-ops"),
+            matches.value_of("rate-limit-bytes"),
+        ) {
+            (None, None) => None,
+            (ops, bytes) => Some(RateLimit {
+                ops_per_sec: ops.map(|ops| ops.parse().unwrap()),
+                bytes_per_sec: bytes.map(|bytes| bytes.parse().unwrap()),
+                per: match matches.value_of("rate-limit-per").unwrap() {
+                    "pid" => RateLimitKey::Pid,
+                    _ => RateLimitKey::Uid,
+                },
+                delay: matches.is_present("rate-limit-delay"),
+            }),
+        },
+        faults: matches.values_of("fault").map_or_else(Vec::new, |values| {
+            values
+                .map(|value| parse_fault_rule(value).unwrap())
+                .collect()
+        }),
+        latencies: matches
+            .values_of("latency")
+            .map_or_else(Vec::new, |values| {
+                values
+                    .map(|value| parse_latency_rule(value).unwrap())
+                    .collect()
+            }),
+        chaos: matches.is_present("chaos"),
+        fault_seed: matches
+            .value_of("fault-seed")
+            .map(|seed| seed.parse().unwrap()),
+        run_as: matches
+            .value_of("run-as")
+            .map(|owner| parse_owner(owner).unwrap()),
+        landlock: matches.is_present("landlock"),
+        seccomp: matches.is_present("seccomp"),
+        branches: matches
+            .values_of("branch")
+            .map_or_else(Vec::new, |values| values.map(PathBuf::from).collect()),
+        search_policy: branch_policy(matches.value_of("search-policy").unwrap()),
+        create_policy: branch_policy(matches.value_of("create-policy").unwrap()),
+        upper: matches.value_of("upper").map(PathBuf::from),
+        snapshot: matches.is_present("snapshot"),
+        pin_snapshot: matches.is_present("pin-snapshot"),
+        one_file_system: matches.is_present("xdev"),
+        nfs_export: matches.is_present("nfs-export"),
+        case_insensitive: matches.is_present("case-insensitive"),
+        rename_rules: matches.values_of("rename").map_or_else(Vec::new, |values| {
+            values
+                .map(|value| parse_rename_rule(value).unwrap())
+                .collect()
+        }),
+        filter_rules: filter_rules(&matches),
+        hide_hidden: matches.is_present("hide-hidden"),
+        hide_devices: matches.is_present("hide-devices"),
+        hide_sockets: matches.is_present("hide-sockets"),
+        hide_fifos: matches.is_present("hide-fifos"),
+        hide_empty_dirs: matches.is_present("hide-empty-dirs"),
+        unlisted_dirs: matches
+            .values_of("unlisted")
+            .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+        exports: matches.values_of("export").map_or_else(Vec::new, |values| {
+            values.map(|value| parse_subpath(value).unwrap()).collect()
+        }),
+        max_depth: matches
+            .value_of("max-depth")
+            .map(|depth| depth.parse().unwrap()),
+        transforms: transform_rules(&matches, &substitutions),
+        virtual_files: virtual_files(&matches),
+        stats_dir: matches.is_present("stats-dir"),
+        max_file_size: matches.value_of("max-file-size").unwrap().parse().unwrap(),
+        oversize: match matches.value_of("oversize").unwrap() {
+            "deny" => Oversize::Deny,
+            _ => Oversize::Hide,
+        },
+        uid_map: id_map(&matches, "map-uid"),
+        gid_map: id_map(&matches, "map-gid"),
+        squash_owner: if matches.is_present("squash-owner") {
+            Some(match matches.value_of("squash-owner") {
+                Some(owner) => parse_owner(owner).unwrap(),
+                None => unsafe { (libc::getuid(), libc::getgid()) },
+            })
+        } else {
+            None
+        },
+        file_mode: matches
+            .value_of("chmod-file")
+            .map(|mode| parse_mode(mode).unwrap()),
+        dir_mode: matches
+            .value_of("chmod-dir")
+            .map(|mode| parse_mode(mode).unwrap()),
+        no_exec: matches.is_present("no-exec"),
+        mount_noexec: matches.is_present("mount-noexec"),
+        timestamps: match (matches.value_of("clamp-time"), matches.value_of("fix-time")) {
+            (Some(limit), _) => Timestamps::Clamp(parse_epoch(limit).unwrap()),
+            (_, Some(fixed)) => Timestamps::Fixed(parse_epoch(fixed).unwrap()),
+            _ => Timestamps::Unchanged,
+        },
+        zero_fill_reads: matches.is_present("zero-fill-reads"),
+        attr_timeout: parse_timeout(matches.value_of("attr-timeout").unwrap()).unwrap(),
+        entry_timeout: parse_timeout(matches.value_of("entry-timeout").unwrap()).unwrap(),
+        negative_timeout: parse_timeout(matches.value_of("negative-timeout").unwrap()).unwrap(),
+        cache_mode: match matches.value_of("cache").unwrap() {
+            "keep" => CacheMode::Keep,
+            "direct" => CacheMode::Direct,
+            _ => CacheMode::Auto,
+        },
+        threads: match matches.value_of("threads") {
+            Some(threads) => threads.parse().unwrap(),
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        },
+        fd_cache_size: matches.value_of("fd-cache").unwrap().parse().unwrap(),
+        max_inodes: matches.value_of("max-inodes").unwrap().parse().unwrap(),
+        dir_cache_timeout: parse_timeout(matches.value_of("dir-cache-timeout").unwrap()).unwrap(),
+        read_ahead: matches.value_of("read-ahead").unwrap().parse().unwrap(),
+        mmap_threshold: matches.value_of("mmap-threshold").unwrap().parse().unwrap(),
+        max_write: matches.value_of("max-write").map(|v| v.parse().unwrap()),
+        max_readahead: matches
+            .value_of("max-readahead")
+            .map(|v| v.parse().unwrap()),
+        max_background: matches
+            .value_of("max-background")
+            .map(|v| v.parse().unwrap()),
+        congestion_threshold: matches
+            .value_of("congestion-threshold")
+            .map(|v| v.parse().unwrap()),
+        metrics_address: matches.value_of("metrics").map(String::from),
+        slow_op_threshold: parse_timeout(matches.value_of("slow-op").unwrap()).unwrap(),
+        audit_log: matches.value_of("audit-log").map(PathBuf::from),
+        denial_log: matches.value_of("denial-log").map(PathBuf::from),
+        record: matches.value_of("record").map(PathBuf::from),
+        denial_hook: matches.value_of("denial-hook").map(String::from),
+        control_socket: matches.value_of("control").map(PathBuf::from),
+        access_events: None,
+        log_callers: matches.is_present("log-callers"),
+    };
+
+    // A pass(1) store has a NAME.gpg file for each secret. gpg must be able to
+    // decrypt without prompting, for instance from a running gpg-agent.
+    if matches.is_present("decrypt-gpg") {
+        config.transforms.push(TransformRule {
+            pattern: "*.gpg".into(),
+            transform: Transform::Command("gpg --quiet --batch --decrypt".into()),
+        });
+        config.rename_rules.push(RenameRule {
+            from: ".gpg$".into(),
+            to: "".into(),
+        });
+    }
+
+    // Every file in the backing tree is encrypted, and only one transform
+    // applies to a file, so this can't be combined with other transforms.
+    // With --rw, written files are encrypted to the recipient of the same
+    // identity.
+    if let Some(identity) = matches.value_of("age-identity") {
+        let identity = shell_quote(identity);
+        config.transforms.push(TransformRule {
+            pattern: "*".into(),
+            transform: Transform::Codec {
+                decode: format!("age --decrypt --identity {}", identity),
+                encode: format!("age --encrypt --identity {}", identity),
+            },
+        });
+    }
+
+    // The size of the decompressed content is only known once it has all been
+    // decompressed, so each file is decompressed in full when it is opened
+    if matches.is_present("decompress") {
+        for (extension, command) in [("gz", "gzip -dc"), ("zst", "zstd -dcq")] {
+            config.transforms.push(TransformRule {
+                pattern: format!("*.{}", extension),
+                transform: Transform::Command(command.into()),
+            });
+            config.rename_rules.push(RenameRule {
+                from: format!(".{}$", extension),
+                to: "".into(),
+            });
+        }
+    }
+
+    // Every lookup nfsd makes to revalidate a name would otherwise come to us,
+    // so unless a timeout is given names are cached for a long time
+    if config.nfs_export && matches.occurrences_of("entry-timeout") == 0 {
+        config.entry_timeout = NFS_ENTRY_TIMEOUT;
+    }
+
+    let scratch = if matches.is_present("scratch") {
+        match scratch_dir() {
+            Ok(scratch) => {
+                config.upper = Some(scratch.clone());
+                Some(scratch)
+            }
+            Err(err) => {
+                eprintln!("Error creating scratch directory: {}", err);
+                exit(1)
+            }
+        }
+    } else {
+        None
+    };
+
+    let mountpoint = matches.value_of("MOUNTPOINT").unwrap();
+    let root_path = matches.value_of("ROOT").unwrap();
+
+    if let Some(signals) = signals {
+        unmount_on_signal(PathBuf::from(mountpoint), signals);
+    }
+    let result = passfs::run(mountpoint, root_path, config);
+    if let Some(scratch) = scratch {
+        if let Err(err) = fs::remove_dir_all(&scratch) {
+            eprintln!("Error removing {}: {}", scratch.display(), err);
+        }
+    }
+    if let Err(err) = result {
+        eprintln!("{}", err.display_chain());
+        exit(1)
+    }
+    exit(0)
+}
+
+// Parse the name of a branch policy, as used by mergerfs
+fn branch_policy(name: &str) -> BranchPolicy {
+    match name {
+        "mfs" => BranchPolicy::MostFreeSpace,
+        "ep" => BranchPolicy::PathPreserving,
+        _ => BranchPolicy::FirstFound,
+    }
+}
+
+// Block the signals which should end the mount, in this thread and every
+// thread it starts, so they are only received by unmount_on_signal
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut signals, signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+// Unmount mountpoint when one of signals is received, from a new thread, so
+// that the session ends and we clean up as if it had been unmounted by the
+// user. If it can't be unmounted, for instance because we no longer have the
+// privileges to, we exit without cleaning up.
+fn unmount_on_signal(mountpoint: PathBuf, signals: libc::sigset_t) {
+    thread::spawn(move || {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            return;
+        }
+        eprintln!(
+            "Received signal {}: unmounting {}",
+            signal,
+            mountpoint.display()
+        );
+
+        // Lazily, so a busy mount doesn't stop us ending
+        #[cfg(target_os = "linux")]
+        let mut command = process::Command::new("fusermount");
+        #[cfg(target_os = "linux")]
+        command.args(["-u", "-z"]);
+        #[cfg(target_os = "freebsd")]
+        let mut command = process::Command::new("umount");
+        let unmounted = command
+            .arg(&mountpoint)
+            .status()
+            .is_ok_and(|status| status.success());
+        if !unmounted {
+            eprintln!("Error unmounting {}", mountpoint.display());
+            exit(128 + signal)
+        }
+    });
+}
+
+// Create an empty directory, private to us, to hold changes made to a scratch
+// mount. It is in memory if /dev/shm is available, and otherwise in the
+// temporary directory.
+fn scratch_dir() -> io::Result<PathBuf> {
+    let shm = Path::new("/dev/shm");
+    let base = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        env::temp_dir()
+    };
+    let scratch = base.join(format!("passfs-scratch-{}", process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&scratch)?;
+    Ok(scratch)
 }
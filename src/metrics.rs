@@ -0,0 +1,722 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::field::Value;
+use tracing::span::EnteredSpan;
+use tracing::{info, warn, Span};
+
+use fuser::{
+    FileAttr, FileType, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+};
+
+use crate::audit::{AuditLog, Caller};
+use crate::config::AccessEvent;
+use crate::denial::{Denial, DenialLog};
+use crate::histogram::Histogram;
+use crate::record::{Record, RecordValue, Recorder};
+use crate::workers::Timer;
+
+// The FUSE operations which are measured: every one we reply to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Getattr,
+    Lookup,
+    Opendir,
+    Readdir,
+    Releasedir,
+    Open,
+    Read,
+    Write,
+    Flush,
+    Release,
+    Fsync,
+    Create,
+    Mknod,
+    Setattr,
+    Setxattr,
+    Getxattr,
+    Listxattr,
+    Removexattr,
+    Mkdir,
+    Rmdir,
+    Unlink,
+    Rename,
+    Readlink,
+    Symlink,
+    Link,
+    Fallocate,
+    CopyFileRange,
+    Statfs,
+    Access,
+}
+
+const OPS: [Op; 29] = [
+    Op::Getattr,
+    Op::Lookup,
+    Op::Opendir,
+    Op::Readdir,
+    Op::Releasedir,
+    Op::Open,
+    Op::Read,
+    Op::Write,
+    Op::Flush,
+    Op::Release,
+    Op::Fsync,
+    Op::Create,
+    Op::Mknod,
+    Op::Setattr,
+    Op::Setxattr,
+    Op::Getxattr,
+    Op::Listxattr,
+    Op::Removexattr,
+    Op::Mkdir,
+    Op::Rmdir,
+    Op::Unlink,
+    Op::Rename,
+    Op::Readlink,
+    Op::Symlink,
+    Op::Link,
+    Op::Fallocate,
+    Op::CopyFileRange,
+    Op::Statfs,
+    Op::Access,
+];
+
+impl Op {
+    pub fn from_name(name: &str) -> Option<Op> {
+        OPS.iter().copied().find(|op| op.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Getattr => "getattr",
+            Op::Lookup => "lookup",
+            Op::Opendir => "opendir",
+            Op::Readdir => "readdir",
+            Op::Releasedir => "releasedir",
+            Op::Open => "open",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Flush => "flush",
+            Op::Release => "release",
+            Op::Fsync => "fsync",
+            Op::Create => "create",
+            Op::Mknod => "mknod",
+            Op::Setattr => "setattr",
+            Op::Setxattr => "setxattr",
+            Op::Getxattr => "getxattr",
+            Op::Listxattr => "listxattr",
+            Op::Removexattr => "removexattr",
+            Op::Mkdir => "mkdir",
+            Op::Rmdir => "rmdir",
+            Op::Unlink => "unlink",
+            Op::Rename => "rename",
+            Op::Readlink => "readlink",
+            Op::Symlink => "symlink",
+            Op::Link => "link",
+            Op::Fallocate => "fallocate",
+            Op::CopyFileRange => "copy_file_range",
+            Op::Statfs => "statfs",
+            Op::Access => "access",
+        }
+    }
+}
+
+// Upper bounds of the exported latency histogram buckets, in seconds
+const BUCKETS: [f64; 14] = [
+    0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0,
+];
+
+// Quantiles of latency which are exported
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+// Latencies are recorded in nanoseconds
+struct OpMetrics {
+    count: AtomicU64,
+    total_latency: AtomicU64,
+    latency: Histogram,
+}
+
+// Counters and gauges describing everything we have done since we were
+// mounted. They are updated by whichever thread replies to a request.
+pub struct Metrics {
+    ops: Vec<OpMetrics>,
+    // Requests taking at least this long are logged, unless it is zero
+    slow_op_threshold: Duration,
+    errors: Mutex<BTreeMap<(usize, i32), u64>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    inodes: AtomicU64,
+    open_handles: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(slow_op_threshold: Duration) -> Metrics {
+        Metrics {
+            ops: OPS
+                .iter()
+                .map(|_| OpMetrics {
+                    count: AtomicU64::new(0),
+                    total_latency: AtomicU64::new(0),
+                    latency: Histogram::new(),
+                })
+                .collect(),
+            slow_op_threshold,
+            errors: Mutex::new(BTreeMap::new()),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            inodes: AtomicU64::new(0),
+            open_handles: AtomicU64::new(0),
+        }
+    }
+
+    // Whether requests may be slow enough to log, and so need describing
+    pub fn logs_slow_ops(&self) -> bool {
+        !self.slow_op_threshold.is_zero()
+    }
+
+    // Record the number of inodes we hold and the number of open files and
+    // directories
+    pub fn set_gauges(&self, inodes: usize, open_handles: usize) {
+        self.inodes.store(inodes as u64, Ordering::Relaxed);
+        self.open_handles
+            .store(open_handles as u64, Ordering::Relaxed);
+    }
+
+    fn record(&self, op: Op, latency: Duration, errno: Option<i32>) {
+        let index = op as usize;
+        let op_metrics = &self.ops[index];
+        op_metrics.count.fetch_add(1, Ordering::Relaxed);
+        let nanos = latency.as_nanos() as u64;
+        op_metrics.total_latency.fetch_add(nanos, Ordering::Relaxed);
+        op_metrics.latency.record(nanos);
+
+        if let Some(errno) = errno {
+            *self
+                .errors
+                .lock()
+                .unwrap()
+                .entry((index, errno))
+                .or_insert(0) += 1;
+        }
+    }
+
+    // Return the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP passfs_requests_total FUSE requests handled.\n");
+        out.push_str("# TYPE passfs_requests_total counter\n");
+        for (op, op_metrics) in OPS.iter().zip(&self.ops) {
+            let count = op_metrics.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "passfs_requests_total{{op=\"{}\"}} {}",
+                op.name(),
+                count
+            );
+        }
+
+        out.push_str("# HELP passfs_errors_total FUSE requests which failed, by errno.\n");
+        out.push_str("# TYPE passfs_errors_total counter\n");
+        for ((index, errno), count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "passfs_errors_total{{op=\"{}\",errno=\"{}\"}} {}",
+                OPS[*index].name(),
+                errno,
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP passfs_request_duration_seconds Time taken to reply to FUSE requests.\n",
+        );
+        out.push_str("# TYPE passfs_request_duration_seconds histogram\n");
+        for (op, op_metrics) in OPS.iter().zip(&self.ops) {
+            for bound in BUCKETS.iter() {
+                let cumulative = op_metrics.latency.count_to((bound * 1e9) as u64);
+                let _ = writeln!(
+                    out,
+                    "passfs_request_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    bound,
+                    cumulative
+                );
+            }
+            let count = op_metrics.count.load(Ordering::Relaxed);
+            let total = op_metrics.total_latency.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "passfs_request_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                op.name(),
+                count
+            );
+            let _ = writeln!(
+                out,
+                "passfs_request_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                total as f64 / 1e9
+            );
+            let _ = writeln!(
+                out,
+                "passfs_request_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                count
+            );
+        }
+
+        out.push_str("# HELP passfs_request_latency_seconds Quantiles of time taken to reply.\n");
+        out.push_str("# TYPE passfs_request_latency_seconds summary\n");
+        for (op, op_metrics) in OPS.iter().zip(&self.ops) {
+            for quantile in QUANTILES.iter() {
+                let _ = writeln!(
+                    out,
+                    "passfs_request_latency_seconds{{op=\"{}\",quantile=\"{}\"}} {}",
+                    op.name(),
+                    quantile,
+                    op_metrics.latency.quantile(*quantile) as f64 / 1e9
+                );
+            }
+            let count = op_metrics.count.load(Ordering::Relaxed);
+            let total = op_metrics.total_latency.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "passfs_request_latency_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                total as f64 / 1e9
+            );
+            let _ = writeln!(
+                out,
+                "passfs_request_latency_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                count
+            );
+        }
+
+        let values = [
+            (
+                "read_bytes_total",
+                "counter",
+                "Bytes read from files.",
+                &self.bytes_read,
+            ),
+            (
+                "written_bytes_total",
+                "counter",
+                "Bytes written to files.",
+                &self.bytes_written,
+            ),
+            (
+                "inodes",
+                "gauge",
+                "Inodes referenced by the kernel.",
+                &self.inodes,
+            ),
+            (
+                "open_handles",
+                "gauge",
+                "Open files and directories.",
+                &self.open_handles,
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            let _ = writeln!(out, "# HELP passfs_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE passfs_{} {}", name, kind);
+            let _ = writeln!(out, "passfs_{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+// The failure of any reply
+pub trait ReplyError {
+    fn error(self, err: libc::c_int);
+}
+
+macro_rules! reply_error {
+    ($($reply:ty),*) => {
+        $(impl ReplyError for $reply {
+            fn error(self, err: libc::c_int) {
+                <$reply>::error(self, err)
+            }
+        })*
+    };
+}
+
+reply_error!(
+    ReplyAttr,
+    ReplyCreate,
+    ReplyData,
+    ReplyDirectory,
+    ReplyEmpty,
+    ReplyEntry,
+    ReplyOpen,
+    ReplyStatfs,
+    ReplyWrite,
+    ReplyXattr
+);
+
+// Who made a request, and who wants to know about it
+struct Observer {
+    caller: Caller,
+    audit_log: Option<Arc<AuditLog>>,
+    events: Option<Sender<AccessEvent>>,
+}
+
+// A reply which records the outcome of the request in metrics when it is
+// sent. It is used exactly as the reply it wraps. subject describes the file
+// the request is about, if it might be logged as slow or be observed.
+pub struct Metered<R> {
+    reply: R,
+    op: Op,
+    subject: Option<String>,
+    observer: Option<Observer>,
+    // The caller and their command, if every request is logged
+    logged_caller: Option<(Caller, String)>,
+    // Where denials are reported, and the caller they are reported for
+    denials: Option<(Arc<DenialLog>, Caller)>,
+    // Where the request is recorded, and its record so far
+    recording: Option<(Arc<Recorder>, Record)>,
+    // How long to hold the reply back, and the timer which sends it then
+    delay: Option<(Duration, Timer)>,
+    span: Span,
+    start: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl<R> Metered<R> {
+    pub fn new(
+        reply: R,
+        op: Op,
+        subject: Option<String>,
+        span: Span,
+        metrics: &Arc<Metrics>,
+    ) -> Metered<R> {
+        Metered {
+            reply,
+            op,
+            subject,
+            observer: None,
+            logged_caller: None,
+            denials: None,
+            recording: None,
+            delay: None,
+            span,
+            start: Instant::now(),
+            metrics: Arc::clone(metrics),
+        }
+    }
+
+    // Also record the outcome as a request by caller about subject in
+    // audit_log and send it to events, if given
+    pub fn observe(
+        self,
+        caller: Caller,
+        subject: String,
+        audit_log: Option<Arc<AuditLog>>,
+        events: Option<Sender<AccessEvent>>,
+    ) -> Metered<R> {
+        Metered {
+            subject: Some(subject),
+            observer: Some(Observer {
+                caller,
+                audit_log,
+                events,
+            }),
+            ..self
+        }
+    }
+
+    // Also log the outcome as a request by caller, which is running command
+    pub fn log_caller(self, caller: Caller, command: String) -> Metered<R> {
+        Metered {
+            logged_caller: Some((caller, command)),
+            ..self
+        }
+    }
+
+    // Also report denials of the request by caller to denials
+    pub fn report_denials(self, denials: Arc<DenialLog>, caller: Caller) -> Metered<R> {
+        Metered {
+            denials: Some((denials, caller)),
+            ..self
+        }
+    }
+
+    // Also record the request in recorder, beginning with record
+    pub fn record_to(self, recorder: Arc<Recorder>, record: Record) -> Metered<R> {
+        Metered {
+            recording: Some((recorder, record)),
+            ..self
+        }
+    }
+
+    // Hold the reply back until latency has passed since it was ready, with
+    // timer, without holding up other requests
+    pub fn delay(self, latency: Duration, timer: Timer) -> Metered<R> {
+        Metered {
+            delay: Some((latency, timer)),
+            ..self
+        }
+    }
+
+    // Whether the request is being recorded
+    pub fn recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Add an argument of the request to its record, if it is being recorded
+    pub fn arg<V: RecordValue>(mut self, key: &str, value: V) -> Metered<R> {
+        if let Some((_, record)) = &mut self.recording {
+            record.arg(key, value);
+        }
+        self
+    }
+
+    // Report that the request was refused, without replying
+    pub fn report(&self, denial: Denial) {
+        if let Some((denials, caller)) = &self.denials {
+            let subject = self.subject.as_deref().unwrap_or("?");
+            denials.record(denial, self.op.name(), caller, subject);
+        }
+    }
+
+    // Enter the span of the request, until the guard returned is dropped
+    pub fn enter(&self) -> EnteredSpan {
+        self.span.clone().entered()
+    }
+
+    // Set a field of the span of the request
+    pub fn record<V: Value>(&self, field: &str, value: V) {
+        self.span.record(field, value);
+    }
+
+    fn done(&self, errno: Option<i32>) {
+        let latency = self.start.elapsed();
+        self.metrics.record(self.op, latency, errno);
+
+        if let Some((recorder, record)) = &self.recording {
+            recorder.finish(record, latency, errno);
+        }
+
+        if let (Some(observer), Some(subject)) = (&self.observer, &self.subject) {
+            let caller = &observer.caller;
+            if let Some(audit_log) = &observer.audit_log {
+                audit_log.record(self.op.name(), caller, subject, errno);
+            }
+            if let Some(events) = &observer.events {
+                let _ = events.send(AccessEvent {
+                    op: self.op.name(),
+                    path: subject.clone(),
+                    uid: caller.uid,
+                    gid: caller.gid,
+                    pid: caller.pid,
+                    errno,
+                });
+            }
+        }
+
+        if let Some((caller, command)) = &self.logged_caller {
+            let result = match errno {
+                Some(errno) => std::io::Error::from_raw_os_error(errno).to_string(),
+                None => "ok".into(),
+            };
+            info!(
+                "{} {} by pid {} ({}) uid {} gid {}: {}",
+                self.op.name(),
+                self.subject.as_deref().unwrap_or("?"),
+                caller.pid,
+                command,
+                caller.uid,
+                caller.gid,
+                result
+            );
+        }
+
+        let threshold = self.metrics.slow_op_threshold;
+        if !threshold.is_zero() && latency >= threshold {
+            let subject = self.subject.as_deref().unwrap_or("?");
+            match errno {
+                Some(errno) => warn!(
+                    "slow {} of {} took {:?}: {}",
+                    self.op.name(),
+                    subject,
+                    latency,
+                    std::io::Error::from_raw_os_error(errno)
+                ),
+                None => warn!("slow {} of {} took {:?}", self.op.name(), subject, latency),
+            }
+        }
+    }
+}
+
+impl<R: Send + 'static> Metered<R> {
+    // Record the outcome of the request, which failed with errno if set, and
+    // reply with send, once any delay has passed
+    fn finish<F>(mut self, errno: Option<i32>, send: F)
+    where
+        F: FnOnce(R) + Send + 'static,
+    {
+        match self.delay.take() {
+            Some((latency, timer)) => timer.run_after(latency, move || {
+                self.done(errno);
+                send(self.reply)
+            }),
+            None => {
+                self.done(errno);
+                send(self.reply)
+            }
+        }
+    }
+}
+
+impl<R: ReplyError + Send + 'static> Metered<R> {
+    pub fn error(self, err: libc::c_int) {
+        self.finish(Some(err), move |reply| reply.error(err))
+    }
+
+    // Refuse the request, reporting why
+    pub fn deny(self, denial: Denial) {
+        self.report(denial);
+        self.error(denial.errno())
+    }
+}
+
+impl Metered<ReplyAttr> {
+    pub fn attr(self, ttl: &Duration, attr: &FileAttr) {
+        let (ttl, attr) = (*ttl, *attr);
+        self.finish(None, move |reply| reply.attr(&ttl, &attr))
+    }
+}
+
+impl Metered<ReplyEntry> {
+    pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
+        let (ttl, attr) = (*ttl, *attr);
+        self.finish(None, move |reply| reply.entry(&ttl, &attr, generation))
+    }
+}
+
+impl Metered<ReplyCreate> {
+    pub fn created(self, ttl: &Duration, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        let (ttl, attr) = (*ttl, *attr);
+        self.finish(None, move |reply| {
+            reply.created(&ttl, &attr, generation, fh, flags)
+        })
+    }
+}
+
+impl Metered<ReplyOpen> {
+    pub fn opened(self, fh: u64, flags: u32) {
+        self.finish(None, move |reply| reply.opened(fh, flags))
+    }
+}
+
+impl Metered<ReplyEmpty> {
+    pub fn ok(self) {
+        self.finish(None, ReplyEmpty::ok)
+    }
+}
+
+impl Metered<ReplyData> {
+    pub fn data(self, data: &[u8]) {
+        if self.op == Op::Read {
+            let bytes_read = &self.metrics.bytes_read;
+            bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        // The data is only borrowed, so it must be copied to be held back
+        if self.delay.is_some() {
+            let data = data.to_vec();
+            return self.finish(None, move |reply| reply.data(&data));
+        }
+        self.done(None);
+        self.reply.data(data)
+    }
+}
+
+impl Metered<ReplyWrite> {
+    pub fn written(self, size: u32) {
+        let bytes_written = &self.metrics.bytes_written;
+        bytes_written.fetch_add(size as u64, Ordering::Relaxed);
+        self.finish(None, move |reply| reply.written(size))
+    }
+}
+
+impl Metered<ReplyXattr> {
+    pub fn size(self, size: u32) {
+        self.finish(None, move |reply| reply.size(size))
+    }
+
+    pub fn data(self, data: &[u8]) {
+        let data = data.to_vec();
+        self.finish(None, move |reply| reply.data(&data))
+    }
+}
+
+impl Metered<ReplyDirectory> {
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
+        self.reply.add(ino, offset, kind, name)
+    }
+
+    pub fn ok(self) {
+        self.finish(None, ReplyDirectory::ok)
+    }
+}
+
+// Serve metrics over HTTP at address, from a new thread
+#[cfg(feature = "metrics")]
+pub fn serve(address: &str, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind(address)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("metrics: {}", err);
+                    continue;
+                }
+            };
+
+            // A client which never sends its request or reads the response
+            // mustn't stop others being served
+            let timeout = Some(std::time::Duration::from_secs(5));
+            if let Err(err) = stream
+                .set_read_timeout(timeout)
+                .and_then(|()| stream.set_write_timeout(timeout))
+            {
+                warn!("metrics: {}", err);
+                continue;
+            }
+
+            // Only the request line matters: every path other than /metrics
+            // is not found
+            let mut request = String::new();
+            if BufReader::new(&stream).read_line(&mut request).is_err() {
+                continue;
+            }
+            let found = request.split_whitespace().nth(1) == Some("/metrics");
+            let (status, body) = if found {
+                ("200 OK", metrics.render())
+            } else {
+                ("404 Not Found", String::new())
+            };
+            let response = format!(
+                "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = std::io::Write::write_all(&mut stream, response.as_bytes());
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+// A read-only shared mapping of the whole of a file, as it was when the
+// mapping was created
+pub struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is never written, so it can be read from any thread
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn new(file: &File, len: usize, advice: libc::c_int) -> io::Result<Mapping> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mapping = Mapping { addr, len };
+        if unsafe { libc::madvise(addr, len, advice) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(mapping)
+    }
+
+    // Return the len bytes at offset, if they are all within the mapping
+    pub fn get(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.len as u64 {
+            return None;
+        }
+
+        let data = unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) };
+        Some(&data[offset as usize..end as usize])
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+// The names in a directory, keyed by the form in which they are presented,
+// as they were when the directory had mtime
+struct IndexedDir {
+    mtime: (i64, i64),
+    names: BTreeMap<Vec<u8>, OsString>,
+}
+
+// Maps presented names back to backing names, for directories in which we
+// have looked up a name which isn't necessarily presented as itself. A
+// directory's names are only used while its mtime is unchanged, so they are
+// never stale.
+pub struct NameIndex {
+    dirs: BTreeMap<u64, IndexedDir>,
+}
+
+impl NameIndex {
+    pub fn new() -> NameIndex {
+        NameIndex {
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    // Whether we have the names of directory ino as they are at mtime
+    pub fn is_current(&self, ino: u64, mtime: (i64, i64)) -> bool {
+        self.dirs.get(&ino).is_some_and(|dir| dir.mtime == mtime)
+    }
+
+    // Record the names of directory ino, each with its key. If several names
+    // have the same key the first is kept.
+    pub fn insert<I>(&mut self, ino: u64, mtime: (i64, i64), names: I)
+    where
+        I: IntoIterator<Item = (Vec<u8>, OsString)>,
+    {
+        let mut keyed = BTreeMap::new();
+        for (key, name) in names {
+            keyed.entry(key).or_insert(name);
+        }
+        self.dirs.insert(
+            ino,
+            IndexedDir {
+                mtime,
+                names: keyed,
+            },
+        );
+    }
+
+    // Return the backing name in directory ino with key
+    pub fn find(&self, ino: u64, key: &[u8]) -> Option<OsString> {
+        self.dirs.get(&ino)?.names.get(key).cloned()
+    }
+
+    pub fn forget(&mut self, ino: u64) {
+        self.dirs.remove(&ino);
+    }
+}
+
+// Fold the case of name for comparison. Names which aren't valid UTF-8 are
+// only folded for ASCII.
+pub fn fold_case(name: &OsStr) -> Vec<u8> {
+    match name.to_str() {
+        Some(name) => name.to_lowercase().into_bytes(),
+        None => name.as_bytes().to_ascii_lowercase(),
+    }
+}
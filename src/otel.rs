@@ -0,0 +1,43 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// Exports spans until it is dropped, when the last of them are flushed
+pub struct Exporter(SdkTracerProvider);
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.shutdown() {
+            eprintln!("Error exporting spans: {}", err);
+        }
+    }
+}
+
+// A layer exporting the spans of requests, and of the syscalls made for them,
+// to the OTLP/HTTP collector at endpoint, such as
+// http://localhost:4318/v1/traces. Spans are exported in batches by a thread
+// of their own, so requests don't wait for the collector.
+pub fn layer<S>(endpoint: &str) -> Result<(impl Layer<S>, Exporter), String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|err| err.to_string())?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    let tracer = provider.tracer("passfs");
+
+    // Only our own spans are exported: not those of the exporter itself
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target("passfs", Level::TRACE));
+    Ok((layer, Exporter(provider)))
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use crate::audit::Caller;
+use crate::config::{AccessPolicy, Config, PolicyOp, Principal};
+use crate::filter::Pattern;
+
+struct Policy {
+    principal: Principal,
+    patterns: Vec<Pattern>,
+    operations: Vec<PolicyOp>,
+}
+
+impl Policy {
+    fn new(policy: &AccessPolicy) -> Policy {
+        Policy {
+            principal: policy.principal,
+            patterns: policy
+                .patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect(),
+            operations: policy.operations.clone(),
+        }
+    }
+
+    // Whether a pattern matches path, relative to the root, or a directory
+    // above it
+    fn covers(&self, path: &Path, is_dir: bool) -> bool {
+        self.patterns.iter().any(|pattern| {
+            pattern.matches(path, is_dir)
+                || path
+                    .ancestors()
+                    .skip(1)
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .any(|dir| pattern.matches(dir, true))
+        })
+    }
+}
+
+// Decides which files each caller may access, from Config::access_policies
+pub struct Policies {
+    policies: Vec<Policy>,
+}
+
+impl Policies {
+    pub fn new(config: &Config) -> Policies {
+        Policies {
+            policies: config.access_policies.iter().map(Policy::new).collect(),
+        }
+    }
+
+    // Whether every caller may access every file
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    // The policies applying to caller. Their groups are only read if a policy
+    // applies to a group.
+    fn applying<'a>(&'a self, caller: &Caller) -> impl Iterator<Item = &'a Policy> {
+        let caller = *caller;
+        let mut groups = None;
+        self.policies
+            .iter()
+            .filter(move |policy| match policy.principal {
+                Principal::Uid(uid) => caller.uid == uid,
+                Principal::Gid(gid) => {
+                    caller.gid == gid
+                        || groups
+                            .get_or_insert_with(|| crate::caller_groups(caller.pid))
+                            .contains(&gid)
+                }
+            })
+    }
+
+    // Whether caller may look up the file at path, relative to the root. They
+    // may look up the files a policy covers, and directories below which a
+    // pattern may match, so that they can reach them.
+    pub fn may_lookup(&self, caller: &Caller, path: &Path, is_dir: bool) -> bool {
+        self.applying(caller).any(|policy| {
+            policy.covers(path, is_dir)
+                || (is_dir
+                    && policy
+                        .patterns
+                        .iter()
+                        .any(|pattern| pattern.may_match_below(path)))
+        })
+    }
+
+    // Whether caller may do op to the file at path, relative to the root
+    pub fn allows(&self, caller: &Caller, path: &Path, is_dir: bool, op: PolicyOp) -> bool {
+        self.applying(caller)
+            .any(|policy| policy.operations.contains(&op) && policy.covers(path, is_dir))
+    }
+}
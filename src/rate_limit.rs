@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::audit::Caller;
+use crate::config::{RateLimit, RateLimitKey};
+
+// Buckets are dropped once there are this many and they are full again, so
+// the map doesn't grow with every pid which has ever made a request
+const MAX_IDLE_BUCKETS: usize = 4096;
+
+// What to do with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Admit,
+    // Admit it once this has passed
+    Delay(Duration),
+    Refuse,
+}
+
+// A token bucket holding up to a second's worth of tokens. A request may be
+// admitted while any tokens remain, and may leave the bucket in debt.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Bucket {
+        Bucket { rate, tokens: rate }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+    }
+
+    fn full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+
+    fn empty(&self) -> bool {
+        self.tokens <= 0.0
+    }
+
+    // Take cost tokens, returning how long it will be until the bucket is
+    // out of debt
+    fn take(&mut self, cost: f64) -> Duration {
+        self.tokens -= cost;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+struct Buckets {
+    last: Instant,
+    ops: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Bucket> {
+        self.ops.iter_mut().chain(self.bytes.iter_mut())
+    }
+}
+
+// Limits the rate of requests, and of bytes read and written, of each caller
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<u32, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // Charge caller for a request reading or writing bytes. Only reads and
+    // writes are delayed rather than refused.
+    pub fn check(&mut self, caller: &Caller, bytes: u64) -> Verdict {
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_IDLE_BUCKETS {
+            self.buckets.retain(|_, buckets| {
+                let elapsed = now.duration_since(buckets.last);
+                buckets.iter_mut().any(|bucket| {
+                    bucket.refill(elapsed);
+                    !bucket.full()
+                })
+            });
+        }
+
+        let key = match self.limit.per {
+            RateLimitKey::Uid => caller.uid,
+            RateLimitKey::Pid => caller.pid,
+        };
+        let limit = &self.limit;
+        let buckets = self.buckets.entry(key).or_insert_with(|| Buckets {
+            last: now,
+            ops: limit.ops_per_sec.map(|rate| Bucket::new(rate.get() as f64)),
+            bytes: limit
+                .bytes_per_sec
+                .map(|rate| Bucket::new(rate.get() as f64)),
+        });
+        let elapsed = now.duration_since(buckets.last);
+        buckets.last = now;
+        for bucket in buckets.iter_mut() {
+            bucket.refill(elapsed);
+        }
+
+        let delay = self.limit.delay && bytes > 0;
+        if !delay && buckets.iter_mut().any(|bucket| bucket.empty()) {
+            return Verdict::Refuse;
+        }
+        let wait = buckets
+            .ops
+            .as_mut()
+            .map(|bucket| bucket.take(1.0))
+            .max(
+                buckets
+                    .bytes
+                    .as_mut()
+                    .map(|bucket| bucket.take(bytes as f64)),
+            )
+            .unwrap_or(Duration::ZERO);
+        if delay && wait > Duration::ZERO {
+            Verdict::Delay(wait)
+        } else {
+            Verdict::Admit
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::audit::{json_string, Caller};
+
+// A value of an argument of a recorded request
+pub trait RecordValue {
+    fn append(&self, out: &mut String);
+
+    // Whether the argument is recorded at all
+    fn present(&self) -> bool {
+        true
+    }
+}
+
+macro_rules! number_value {
+    ($($t:ty),*) => {
+        $(impl RecordValue for $t {
+            fn append(&self, out: &mut String) {
+                let _ = write!(out, "{}", self);
+            }
+        })*
+    };
+}
+
+number_value!(i32, i64, u32, u64, bool);
+
+impl RecordValue for &str {
+    fn append(&self, out: &mut String) {
+        json_string(out, self);
+    }
+}
+
+impl<T: RecordValue> RecordValue for Option<T> {
+    fn append(&self, out: &mut String) {
+        if let Some(value) = self {
+            value.append(out);
+        }
+    }
+
+    fn present(&self) -> bool {
+        self.is_some()
+    }
+}
+
+// The request so far, as an unterminated JSON object
+pub struct Record {
+    line: String,
+}
+
+impl Record {
+    // Add an argument of the request, unless it is None
+    pub fn arg<V: RecordValue>(&mut self, key: &str, value: V) {
+        if value.present() {
+            let _ = write!(self.line, ",\"{}\":", key);
+            value.append(&mut self.line);
+        }
+    }
+}
+
+// A file to which every request is written as a JSON object on a line of its
+// own, with its arguments, when it started relative to the start of the
+// recording, how long it took and how it failed, if it did. Paths are
+// relative to the root. Written data and xattr values aren't recorded, only
+// their size. `passfs replay` performs a recording on a directory.
+pub struct Recorder {
+    file: Mutex<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> io::Result<Recorder> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    // Begin a record of an op request by caller about path
+    pub fn begin(&self, op: &str, caller: &Caller, path: &Path) -> Record {
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            "{{\"start\":{:.6},\"op\":\"{}\",\"uid\":{},\"gid\":{},\"pid\":{},\"path\":",
+            self.start.elapsed().as_secs_f64(),
+            op,
+            caller.uid,
+            caller.gid,
+            caller.pid
+        );
+        json_string(&mut line, &path.to_string_lossy());
+        Record { line }
+    }
+
+    // Write record, for a request which took duration and failed with errno
+    // if set
+    pub fn finish(&self, record: &Record, duration: Duration, errno: Option<i32>) {
+        let mut line = record.line.clone();
+        let _ = write!(line, ",\"duration\":{:.6}", duration.as_secs_f64());
+        if let Some(errno) = errno {
+            let _ = write!(line, ",\"errno\":{}", errno);
+        }
+        line.push_str("}\n");
+
+        // Requests are finished on several threads, so a single write keeps
+        // lines whole
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("recording: {}", err);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use crate::config::RenameRule;
+
+// A rule's pattern is literal, but may be anchored to the start of a name
+// with ^ or to the end with $
+struct Rule {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    start: bool,
+    end: bool,
+}
+
+impl Rule {
+    fn new(rule: &RenameRule) -> Rule {
+        let mut from = rule.from.as_bytes();
+        let start = from.starts_with(b"^");
+        if start {
+            from = &from[1..];
+        }
+        let end = from.ends_with(b"$");
+        if end {
+            from = &from[..from.len() - 1];
+        }
+
+        Rule {
+            from: from.to_vec(),
+            to: rule.to.as_bytes().to_vec(),
+            start,
+            end,
+        }
+    }
+
+    fn apply(&self, name: &[u8]) -> Vec<u8> {
+        match (self.start, self.end) {
+            (true, true) if name == &self.from[..] => self.to.clone(),
+            (true, false) if name.starts_with(&self.from) => {
+                [&self.to[..], &name[self.from.len()..]].concat()
+            }
+            (false, true) if name.ends_with(&self.from) => {
+                [&name[..name.len() - self.from.len()], &self.to[..]].concat()
+            }
+            (false, false) if !self.from.is_empty() => {
+                let mut renamed = Vec::with_capacity(name.len());
+                let mut rest = name;
+                while !rest.is_empty() {
+                    if rest.starts_with(&self.from) {
+                        renamed.extend_from_slice(&self.to);
+                        rest = &rest[self.from.len()..];
+                    } else {
+                        renamed.push(rest[0]);
+                        rest = &rest[1..];
+                    }
+                }
+                renamed
+            }
+            _ => name.to_vec(),
+        }
+    }
+}
+
+// Rewrites the names of backing files into the names they are presented with
+pub struct Renamer {
+    rules: Vec<Rule>,
+}
+
+impl Renamer {
+    pub fn new(rules: &[RenameRule]) -> Renamer {
+        Renamer {
+            rules: rules.iter().map(Rule::new).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Return the presented name of the backing file name. Every rule is
+    // applied in order. A name which would be rewritten to something which
+    // isn't a valid name is presented unchanged.
+    pub fn present<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        if self.rules.is_empty() {
+            return Cow::Borrowed(name);
+        }
+
+        let renamed = self
+            .rules
+            .iter()
+            .fold(name.as_bytes().to_vec(), |renamed, rule| {
+                rule.apply(&renamed)
+            });
+        match &renamed[..] {
+            b"" | b"." | b".." => Cow::Borrowed(name),
+            _ => Cow::Owned(OsString::from_vec(renamed)),
+        }
+    }
+}
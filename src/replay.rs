@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error_chain::bail;
+use passfs::errors::*;
+
+// How many requests whose outcome differs from the recording are listed
+const MAX_REPORTED: usize = 20;
+
+pub struct Options {
+    // A recording made with --record
+    pub recording: PathBuf,
+    // The directory requests are performed on
+    pub dir: PathBuf,
+    // Perform requests one after another, rather than when they were made
+    pub no_wait: bool,
+}
+
+// A request read from a recording. Values are kept as they were written,
+// without the quotes around strings.
+struct Request {
+    fields: HashMap<String, String>,
+}
+
+impl Request {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+
+    fn number<T: std::str::FromStr>(&self, key: &str) -> io::Result<T> {
+        self.get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no valid {}", key)))
+    }
+}
+
+// The time taken by the requests for one operation
+#[derive(Default)]
+struct Totals {
+    count: u64,
+    recorded: Duration,
+    replayed: Duration,
+}
+
+// Perform the requests in a recording on a directory, as the same user, and
+// compare the time they take with the time they took when recorded. The
+// directory should hold a copy of the tree as it was when recording began.
+// Requests are performed one at a time, and written files are filled with
+// zeros, as their data isn't recorded.
+pub fn run(options: &Options) -> Result<()> {
+    let file = File::open(&options.recording)
+        .chain_err(|| format!("Error opening {}", options.recording.display()))?;
+    let mut replayer = Replayer {
+        dir: options.dir.clone(),
+        files: HashMap::new(),
+    };
+    let mut totals = BTreeMap::<String, Totals>::new();
+    let mut differences = 0;
+    let start = Instant::now();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.chain_err(|| format!("Error reading {}", options.recording.display()))?;
+        let request = match parse(&line) {
+            Some(request) => request,
+            None => bail!("Line {} of the recording isn't valid", i + 1),
+        };
+        let op = request.get("op").unwrap_or("?").to_string();
+
+        if !options.no_wait {
+            if let Ok(at) = request.number::<f64>("start") {
+                let at = Duration::from_secs_f64(at.max(0.0));
+                if let Some(wait) = at.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+        }
+
+        let began = Instant::now();
+        let result = replayer.perform(&op, &request);
+        let replayed = began.elapsed();
+
+        let recorded_errno = request.number::<i32>("errno").ok();
+        let replayed_errno = match &result {
+            Ok(()) => None,
+            Err(err) => Some(err.raw_os_error().unwrap_or(libc::EIO)),
+        };
+        if recorded_errno != replayed_errno {
+            if differences < MAX_REPORTED {
+                eprintln!(
+                    "Line {}: {} of {} {} but was recorded as {}",
+                    i + 1,
+                    op,
+                    request.get("path").unwrap_or("?"),
+                    outcome(replayed_errno),
+                    outcome(recorded_errno)
+                );
+            }
+            differences += 1;
+        }
+
+        let totals = totals.entry(op).or_default();
+        totals.count += 1;
+        totals.recorded += request
+            .number::<f64>("duration")
+            .map(|secs| Duration::from_secs_f64(secs.max(0.0)))
+            .unwrap_or_default();
+        totals.replayed += replayed;
+    }
+
+    println!(
+        "{:<16} {:>8} {:>14} {:>14} {:>8}",
+        "", "count", "recorded", "replayed", "ratio"
+    );
+    for (op, totals) in &totals {
+        println!(
+            "{:<16} {:>8} {:>14} {:>14} {:>7.2}x",
+            op,
+            totals.count,
+            format!("{:.3} ms", totals.recorded.as_secs_f64() * 1000.0),
+            format!("{:.3} ms", totals.replayed.as_secs_f64() * 1000.0),
+            totals.replayed.as_secs_f64() / totals.recorded.as_secs_f64()
+        );
+    }
+    if differences > 0 {
+        println!("{} requests had a different outcome", differences);
+    }
+    Ok(())
+}
+
+fn outcome(errno: Option<i32>) -> String {
+    match errno {
+        Some(errno) => format!("failed: {}", io::Error::from_raw_os_error(errno)),
+        None => "succeeded".into(),
+    }
+}
+
+// Parse a line of a recording, which is a JSON object whose values are
+// strings, numbers or booleans
+fn parse(line: &str) -> Option<Request> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    if chars.peek() == Some(&'}') {
+        return Some(Request { fields });
+    }
+    loop {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let key = string(&mut chars)?;
+        if chars.next()? != ':' {
+            return None;
+        }
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '}' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            value
+        };
+        fields.insert(key, value);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return Some(Request { fields }),
+            _ => return None,
+        }
+    }
+}
+
+// Parse the rest of a JSON string whose opening quote has been read
+fn string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let hex: String = chars.take(4).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+struct Replayer {
+    dir: PathBuf,
+    // Files opened by replayed requests, by their path in the recording.
+    // Files are read and written by path, as file handles aren't recorded.
+    files: HashMap<String, File>,
+}
+
+impl Replayer {
+    // The path in dir of the path recorded as key. Recorded paths are
+    // relative to the root, so one which isn't, such as an absolute one or
+    // one with .., could only come from a recording made to do harm outside
+    // dir.
+    fn path(&self, request: &Request, key: &str) -> io::Result<PathBuf> {
+        let path = match request.get(key) {
+            Some(path) => Path::new(path),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no {}", key),
+                ))
+            }
+        };
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} {:?} is not below the root", key, path),
+            ));
+        }
+        Ok(self.dir.join(path))
+    }
+
+    // The file opened at the path of request, or a new one opened for
+    // reading and writing if it wasn't opened in the recording
+    fn file(&mut self, request: &Request, key: &str) -> io::Result<&File> {
+        let name = request.get(key).unwrap_or_default().to_string();
+        if !self.files.contains_key(&name) {
+            let path = self.path(request, key)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .or_else(|_| File::open(&path))?;
+            self.files.insert(name.clone(), file);
+        }
+        Ok(&self.files[&name])
+    }
+
+    fn perform(&mut self, op: &str, request: &Request) -> io::Result<()> {
+        let path = self.path(request, "path")?;
+        match op {
+            "getattr" | "lookup" => fs::symlink_metadata(&path).map(|_| ()),
+            "opendir" => fs::read_dir(&path).map(|_| ()),
+            // A listing is read whole by the request at offset 0
+            "readdir" if request.number::<i64>("offset")? == 0 => {
+                for entry in fs::read_dir(&path)? {
+                    entry?;
+                }
+                Ok(())
+            }
+            "readdir" | "releasedir" | "flush" => Ok(()),
+            "open" | "create" => {
+                let flags = request.number::<i32>("flags")?;
+                let mut options = OpenOptions::new();
+                match flags & libc::O_ACCMODE {
+                    libc::O_WRONLY => options.write(true),
+                    libc::O_RDWR => options.read(true).write(true),
+                    _ => options.read(true),
+                };
+                options.custom_flags(flags & !libc::O_ACCMODE);
+                if op == "create" {
+                    options.create(true).mode(request.number("mode")?);
+                }
+                let file = options.open(&path)?;
+                self.files.insert(request.get("path").unwrap().into(), file);
+                Ok(())
+            }
+            "release" => {
+                self.files.remove(request.get("path").unwrap());
+                Ok(())
+            }
+            "read" => {
+                let mut buf = vec![0; request.number("size")?];
+                let offset = request.number("offset")?;
+                self.file(request, "path")?.read_at(&mut buf, offset)?;
+                Ok(())
+            }
+            "write" => {
+                let buf = vec![0; request.number("size")?];
+                let offset = request.number("offset")?;
+                self.file(request, "path")?.write_all_at(&buf, offset)
+            }
+            "fsync" => {
+                let file = self.file(request, "path")?;
+                if request.get("datasync") == Some("true") {
+                    file.sync_data()
+                } else {
+                    file.sync_all()
+                }
+            }
+            "fallocate" => {
+                let fd = self.file(request, "path")?.as_raw_fd();
+                let mode = request.number("mode")?;
+                let offset = request.number("offset")?;
+                let length = request.number("length")?;
+                fallocate(fd, mode, offset, length)
+            }
+            "copy_file_range" => {
+                let fd_in = self.file(request, "source")?.as_raw_fd();
+                let fd_out = self.file(request, "path")?.as_raw_fd();
+                let mut offset_in = request.number("offset_in")?;
+                let mut offset_out = request.number("offset_out")?;
+                let len = request.number("len")?;
+                let flags = request.number("flags")?;
+                let copied = unsafe {
+                    libc::copy_file_range(
+                        fd_in,
+                        &mut offset_in,
+                        fd_out,
+                        &mut offset_out,
+                        len,
+                        flags,
+                    )
+                };
+                check(copied)
+            }
+            "setattr" => {
+                if let Ok(mode) = request.number("mode") {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+                }
+                let uid = request.number("uid").ok();
+                let gid = request.number("gid").ok();
+                if uid.is_some() || gid.is_some() {
+                    std::os::unix::fs::lchown(&path, uid, gid)?;
+                }
+                if let Ok(size) = request.number("size") {
+                    OpenOptions::new().write(true).open(&path)?.set_len(size)?;
+                }
+                Ok(())
+            }
+            "readlink" => fs::read_link(&path).map(|_| ()),
+            "mknod" => {
+                let path = c_path(&path)?;
+                let mode = request.number("mode")?;
+                let rdev = request.number::<u32>("rdev")?;
+                check(unsafe { libc::mknod(path.as_ptr(), mode, rdev.into()) })
+            }
+            "mkdir" => fs::DirBuilder::new()
+                .mode(request.number("mode")?)
+                .create(&path),
+            "unlink" => fs::remove_file(&path),
+            "rmdir" => fs::remove_dir(&path),
+            "symlink" => match request.get("target") {
+                Some(target) => std::os::unix::fs::symlink(target, &path),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "no target")),
+            },
+            "rename" => {
+                let newpath = c_path(&self.path(request, "newpath")?)?;
+                rename(&c_path(&path)?, &newpath, request.number("flags")?)?;
+                if let Some(file) = self.files.remove(request.get("path").unwrap()) {
+                    self.files
+                        .insert(request.get("newpath").unwrap().into(), file);
+                }
+                Ok(())
+            }
+            "link" => fs::hard_link(&path, self.path(request, "newpath")?),
+            "statfs" => {
+                let path = c_path(&path)?;
+                let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+                check(unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) })
+            }
+            "access" => {
+                let path = c_path(&path)?;
+                let mask = request.number("mask")?;
+                check(unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mask, 0) })
+            }
+            "setxattr" | "getxattr" | "listxattr" | "removexattr" => {
+                xattr(op, &c_path(&path)?, request)
+            }
+            op => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown op {}", op),
+            )),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate(fd: i32, mode: i32, offset: i64, length: i64) -> io::Result<()> {
+    check(unsafe { libc::fallocate64(fd, mode, offset, length) })
+}
+
+// FreeBSD only has fallocate()'s default mode
+#[cfg(target_os = "freebsd")]
+fn fallocate(fd: i32, mode: i32, offset: i64, length: i64) -> io::Result<()> {
+    let errno = match mode {
+        0 => unsafe { libc::posix_fallocate(fd, offset, length) },
+        _ => libc::EOPNOTSUPP,
+    };
+    match errno {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rename(from: &CString, to: &CString, flags: u32) -> io::Result<()> {
+    check(unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    })
+}
+
+// FreeBSD has no renameat2(), so a rename with flags fails as it does there
+#[cfg(target_os = "freebsd")]
+fn rename(from: &CString, to: &CString, flags: u32) -> io::Result<()> {
+    if flags != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    check(unsafe { libc::rename(from.as_ptr(), to.as_ptr()) })
+}
+
+#[cfg(target_os = "linux")]
+fn xattr(op: &str, path: &CString, request: &Request) -> io::Result<()> {
+    let name = CString::new(request.get("name").unwrap_or_default())?;
+    let mut buf = vec![0u8; request.number::<usize>("size").unwrap_or(0)];
+    let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+    let result = unsafe {
+        match op {
+            "setxattr" => libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf_ptr,
+                buf.len(),
+                request.number("flags")?,
+            ) as isize,
+            "getxattr" => libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf_ptr, buf.len()),
+            "listxattr" => libc::llistxattr(path.as_ptr(), buf_ptr as *mut libc::c_char, buf.len()),
+            _ => libc::lremovexattr(path.as_ptr(), name.as_ptr()) as isize,
+        }
+    };
+    check(result)
+}
+
+// Extended attributes are in namespaces on FreeBSD, named by the prefix of
+// their names on Linux. Only user attributes are listed, and the flags of
+// setxattr are ignored.
+#[cfg(target_os = "freebsd")]
+fn xattr(op: &str, path: &CString, request: &Request) -> io::Result<()> {
+    let name = request.get("name").unwrap_or_default();
+    let (namespace, name) = if let Some(name) = name.strip_prefix("user.") {
+        (libc::EXTATTR_NAMESPACE_USER, name)
+    } else if let Some(name) = name.strip_prefix("system.") {
+        (libc::EXTATTR_NAMESPACE_SYSTEM, name)
+    } else if op == "listxattr" {
+        (libc::EXTATTR_NAMESPACE_USER, "")
+    } else {
+        return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+    };
+    let name = CString::new(name)?;
+    let mut buf = vec![0u8; request.number::<usize>("size").unwrap_or(0)];
+    let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+    let path = path.as_ptr();
+    let result = unsafe {
+        match op {
+            "setxattr" => {
+                libc::extattr_set_link(path, namespace, name.as_ptr(), buf_ptr, buf.len())
+            }
+            "getxattr" => {
+                libc::extattr_get_link(path, namespace, name.as_ptr(), buf_ptr, buf.len())
+            }
+            "listxattr" => libc::extattr_list_link(path, namespace, buf_ptr, buf.len()),
+            _ => libc::extattr_delete_link(path, namespace, name.as_ptr()) as isize,
+        }
+    };
+    check(result)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+// Fail with errno if a libc call returned a negative result
+fn check<T: Default + PartialOrd>(result: T) -> io::Result<()> {
+    if result < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::stat;
+
+// A file as it was when the snapshot was taken. If content is pinned, handle
+// is an O_PATH handle for the file, which keeps it alive even if it is
+// deleted. target is the target of a symlink.
+pub struct SnapshotNode {
+    pub stat: stat,
+    pub path: PathBuf,
+    pub handle: Option<Arc<File>>,
+    pub target: Option<Vec<u8>>,
+    // Entries of a directory, keyed by how their names are looked up
+    children: BTreeMap<Vec<u8>, (OsString, u64)>,
+}
+
+// The tree as it was when the filesystem was mounted, keyed by inode
+pub struct Snapshot {
+    nodes: BTreeMap<u64, SnapshotNode>,
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    // Record the file ino, returning false if it was already recorded
+    // through another hard link
+    pub fn add(
+        &mut self,
+        ino: u64,
+        stat: stat,
+        path: PathBuf,
+        handle: Option<File>,
+        target: Option<Vec<u8>>,
+    ) -> bool {
+        if self.nodes.contains_key(&ino) {
+            return false;
+        }
+        self.nodes.insert(
+            ino,
+            SnapshotNode {
+                stat,
+                path,
+                handle: handle.map(Arc::new),
+                target,
+                children: BTreeMap::new(),
+            },
+        );
+        true
+    }
+
+    // Record that the directory parent contains ino as name, which is found
+    // by key
+    pub fn link(&mut self, parent: u64, key: Vec<u8>, name: OsString, ino: u64) {
+        if let Some(node) = self.nodes.get_mut(&parent) {
+            node.children.insert(key, (name, ino));
+        }
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&SnapshotNode> {
+        self.nodes.get(&ino)
+    }
+
+    // The inode of the entry of directory parent found by key
+    pub fn child(&self, parent: u64, key: &[u8]) -> Option<u64> {
+        let (_, ino) = self.nodes.get(&parent)?.children.get(key)?;
+        Some(*ino)
+    }
+
+    // The name and inode of every entry of the directory ino
+    pub fn children(&self, ino: u64) -> impl Iterator<Item = (&OsStr, u64)> {
+        self.nodes
+            .get(&ino)
+            .into_iter()
+            .flat_map(|node| node.children.values())
+            .map(|(name, ino)| (name.as_os_str(), *ino))
+    }
+}
@@ -0,0 +1,104 @@
+use std::ffi::OsStr;
+use std::time::SystemTime;
+
+use fuser::{FileAttr, FileType};
+
+// The name of the directory in the root
+pub const STATS_DIR: &str = ".passfs";
+
+// The directory and its files are numbered well below the virtual files at the
+// top of the inode space, and above any synthetic inode number
+const STATS_DIR_INODE: u64 = u64::MAX - (1 << 32);
+
+// The files in the directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFile {
+    // Metrics in the Prometheus text format
+    Stats,
+    // The configuration the filesystem was mounted with
+    Config,
+    // The version of passfs
+    Version,
+}
+
+const FILES: [(&str, StatsFile); 3] = [
+    ("stats", StatsFile::Stats),
+    ("config", StatsFile::Config),
+    ("version", StatsFile::Version),
+];
+
+// A read-only directory in the root describing the running filesystem. The
+// content of its files is generated when they are opened, so their size is
+// given as 0 and they must be opened with direct I/O.
+pub struct StatsDir {
+    created: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+impl StatsDir {
+    pub fn new() -> StatsDir {
+        StatsDir {
+            created: SystemTime::now(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    pub fn ino(&self) -> u64 {
+        STATS_DIR_INODE
+    }
+
+    // The inode of the file called name in the directory, if any
+    pub fn find(&self, name: &OsStr) -> Option<u64> {
+        let i = FILES
+            .iter()
+            .position(|(known, _)| OsStr::new(known) == name)?;
+        Some(STATS_DIR_INODE + 1 + i as u64)
+    }
+
+    pub fn file(&self, ino: u64) -> Option<StatsFile> {
+        let i = ino.checked_sub(STATS_DIR_INODE + 1)?;
+        FILES.get(i as usize).map(|(_, file)| *file)
+    }
+
+    // Whether ino is the directory or one of its files
+    pub fn contains(&self, ino: u64) -> bool {
+        ino == STATS_DIR_INODE || self.file(ino).is_some()
+    }
+
+    // The inode and name of every file in the directory
+    pub fn entries(&self) -> impl Iterator<Item = (u64, &OsStr)> {
+        FILES
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (STATS_DIR_INODE + 1 + i as u64, OsStr::new(*name)))
+    }
+
+    pub fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, nlink) = if ino == STATS_DIR_INODE {
+            (FileType::Directory, 0o555, 2)
+        } else {
+            self.file(ino)?;
+            (FileType::RegularFile, 0o444, 1)
+        };
+        Some(FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        })
+    }
+}
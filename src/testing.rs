@@ -0,0 +1,91 @@
+//! Support for tests which need a real passfs mount.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fuser::{BackgroundSession, Session};
+
+use crate::errors::*;
+use crate::{mount_options, Config, PassFs};
+
+// How long to wait for the kernel to show the mount
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Distinguishes the mountpoints of a process
+static NEXT_MOUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A passfs mounted on a temporary directory, which is served by a
+/// background thread. It is unmounted, and the directory removed, when it is
+/// dropped, including when a test panics.
+pub struct TempMount {
+    session: Option<BackgroundSession>,
+    path: PathBuf,
+}
+
+impl TempMount {
+    /// The mountpoint.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempMount {
+    fn drop(&mut self) {
+        // Unmounting ends the session's thread
+        self.session.take();
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// Mount root with config on a new temporary directory, returning once the
+/// mount can be used. Options which change the whole process, such as
+/// run_as, landlock and seccomp, are refused, and metrics and the control
+/// socket aren't served. Mounting needs /dev/fuse, and fusermount on Linux.
+pub fn mount_temp(root: &Path, config: Config) -> Result<TempMount> {
+    if config.run_as.is_some() || config.landlock || config.seccomp {
+        bail!("Unable to mount a test passfs with run_as, landlock or seccomp");
+    }
+
+    let path = env::temp_dir().join(format!(
+        "passfs-test-{}-{}",
+        std::process::id(),
+        NEXT_MOUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir(&path).chain_err(|| format!("Error creating {}", path.display()))?;
+    let mut mount = TempMount {
+        session: None,
+        path,
+    };
+
+    let unmounted = fs::metadata(&mount.path)
+        .chain_err(|| format!("Error reading {}", mount.path.display()))?
+        .dev();
+    let root_path = root.to_string_lossy();
+    let mountopts = mount_options(&config);
+    let passfs = PassFs::new(&root_path, config)?;
+    let session = Session::new(passfs, &mount.path, &mountopts)
+        .and_then(|session| session.spawn())
+        .chain_err(|| format!("Error mounting passfs on {}", mount.path.display()))?;
+    mount.session = Some(session);
+
+    // The session is set up before the kernel finishes the mount, so wait
+    // until the mountpoint is on another device
+    let deadline = Instant::now() + MOUNT_TIMEOUT;
+    loop {
+        if fs::metadata(&mount.path).is_ok_and(|m| m.dev() != unmounted) {
+            return Ok(mount);
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "Timed out waiting for passfs to mount {}",
+                mount.path.display()
+            );
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,715 @@
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use tracing::{debug, warn};
+
+use crate::config::{Redaction, Transform, TransformRule};
+use crate::filter::Pattern;
+use crate::{file_version, fstat, reopen};
+
+// Files stored by Transform::Compress start with COMPRESSED_MAGIC, and then
+// the size of the content, the size of each chunk and the number of chunks,
+// followed by an index of where the zstd frame of each chunk ends, counted
+// from the end of the index. The sizes and the index are little-endian u64s,
+// and the chunk size and count are little-endian u32s.
+const COMPRESSED_MAGIC: &[u8; 8] = b"passfsz1";
+const HEADER_LEN: u64 = 24;
+
+// Larger chunks compress better, but a read decompresses the whole of every
+// chunk it touches
+const CHUNK_SIZE: u64 = 1 << 20;
+
+const COMPRESS: &str = "zstd -cq";
+const DECOMPRESS: &str = "zstd -dcq";
+
+// The transformed content of a file, and the state of the backing file it
+// was produced from. The content is dirty if it has been written since.
+struct Transformed {
+    source: [i64; 5],
+    file: Arc<File>,
+    dirty: bool,
+    loader: Arc<Loader>,
+}
+
+// Produces transformed content. It must be run, and may take a while, so it
+// is run on a worker.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+// Makes transformed content ready to be used. Content is produced by a job,
+// and compressed content is decompressed a chunk at a time as it is used, so
+// anything using the content loads the range it uses first.
+pub struct Loader {
+    chunks: Option<Chunks>,
+    // Set once the content has been produced, to the errno if that failed
+    finished: Mutex<Option<Result<(), i32>>>,
+    changed: Condvar,
+}
+
+impl Loader {
+    fn new(chunks: Option<Chunks>, finished: bool) -> Loader {
+        Loader {
+            chunks,
+            finished: Mutex::new(if finished { Some(Ok(())) } else { None }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // Wait for the content to be produced, and then decompress any chunks
+    // overlapping len bytes at offset
+    pub fn load(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut finished = self.finished.lock().unwrap();
+        while finished.is_none() {
+            finished = self.changed.wait(finished).unwrap();
+        }
+        if let Some(Err(errno)) = *finished {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        drop(finished);
+        match &self.chunks {
+            Some(chunks) => chunks.load(offset, len),
+            None => Ok(()),
+        }
+    }
+
+    // Truncate file, the content, to size once it has been produced
+    fn set_len(&self, file: &File, size: u64) -> io::Result<()> {
+        self.load(0, 0)?;
+        match &self.chunks {
+            Some(chunks) => chunks.set_len(size),
+            None => file.set_len(size),
+        }
+    }
+
+    fn finish(&self, result: &io::Result<()>) {
+        let result = match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.raw_os_error().unwrap_or(libc::EIO)),
+        };
+        *self.finished.lock().unwrap() = Some(result);
+        self.changed.notify_all();
+    }
+
+    // Whether the content was produced, or None if it is still being produced
+    fn succeeded(&self) -> Option<bool> {
+        self.finished.lock().unwrap().map(|result| result.is_ok())
+    }
+}
+
+// The chunks of a compressed file, which are decompressed into content the
+// first time they are used. content is the size of the whole file from the
+// start, so it can be used like any other file once the chunks covering the
+// range in use have been loaded.
+pub struct Chunks {
+    backing: File,
+    // Where the first frame starts in backing
+    start: u64,
+    ends: Vec<u64>,
+    size: u64,
+    chunk_size: u64,
+    content: Arc<File>,
+    // Which chunks are in content, or have been truncated away
+    loaded: Mutex<Vec<bool>>,
+}
+
+impl Chunks {
+    // Read the header and index of backing, which is opened for reading, and
+    // size content to match. Returns None if backing isn't stored in chunks.
+    fn open(backing: File, content: &Arc<File>) -> io::Result<Option<Chunks>> {
+        let (size, chunk_size, count) = match read_header(&backing)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid chunk index");
+        let start = HEADER_LEN + count * 8;
+        let len = fstat(&backing)?.st_size as u64;
+        if start > len {
+            return Err(invalid());
+        }
+        let mut index = vec![0; (count * 8) as usize];
+        backing.read_exact_at(&mut index, HEADER_LEN)?;
+        let ends: Vec<u64> = index
+            .chunks_exact(8)
+            .map(|end| u64::from_le_bytes(end.try_into().unwrap()))
+            .collect();
+        if ends.windows(2).any(|pair| pair[0] > pair[1])
+            || ends
+                .last()
+                .is_some_and(|end| start.saturating_add(*end) > len)
+        {
+            return Err(invalid());
+        }
+
+        content.set_len(size)?;
+        Ok(Some(Chunks {
+            backing,
+            start,
+            ends,
+            size,
+            chunk_size,
+            content: Arc::clone(content),
+            loaded: Mutex::new(vec![false; count as usize]),
+        }))
+    }
+
+    // Decompress every chunk overlapping len bytes at offset which isn't
+    // already loaded
+    pub fn load(&self, offset: u64, len: u64) -> io::Result<()> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let first = offset / self.chunk_size;
+        let last = offset.saturating_add(len).div_ceil(self.chunk_size);
+        for i in first..last.min(loaded.len() as u64) {
+            if loaded[i as usize] {
+                continue;
+            }
+            let end = self.ends[i as usize];
+            let begin = if i == 0 { 0 } else { self.ends[i as usize - 1] };
+            let mut frame = vec![0; (end - begin) as usize];
+            self.backing.read_exact_at(&mut frame, self.start + begin)?;
+
+            let chunk = pipe(DECOMPRESS, &frame)?;
+            let offset = i * self.chunk_size;
+            if chunk.len() as u64 != self.chunk_size.min(self.size - offset) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {} has the wrong size", i),
+                ));
+            }
+            self.content.write_all_at(&chunk, offset)?;
+            loaded[i as usize] = true;
+        }
+        Ok(())
+    }
+
+    // Truncate content to size. What is kept of the chunk it ends in is
+    // loaded first, and nothing past it is ever loaded.
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        if !size.is_multiple_of(self.chunk_size) {
+            self.load(size, 1)?;
+        }
+        let mut loaded = self.loaded.lock().unwrap();
+        let kept = size.div_ceil(self.chunk_size).min(loaded.len() as u64);
+        loaded[kept as usize..].fill(true);
+        self.content.set_len(size)
+    }
+}
+
+// Read the size of the content, the size of each chunk and the number of
+// chunks from the header of backing, or return None if it isn't stored in
+// chunks
+fn read_header(backing: &File) -> io::Result<Option<(u64, u64, u64)>> {
+    let mut header = [0; HEADER_LEN as usize];
+    match backing.read_exact_at(&mut header, 0) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    if header[..8] != COMPRESSED_MAGIC[..] {
+        return Ok(None);
+    }
+    let size = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let chunk_size = u32::from_le_bytes(header[16..20].try_into().unwrap()) as u64;
+    let count = u32::from_le_bytes(header[20..24].try_into().unwrap()) as u64;
+    if chunk_size == 0 || count != size.div_ceil(chunk_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid chunk header",
+        ));
+    }
+    Ok(Some((size, chunk_size, count)))
+}
+
+// Produces the content of files which match a transform rule. Content is kept
+// in anonymous memory files, so it can be read and written like any other open
+// file, and is reused until the backing file changes. Content written to a
+// file with a codec is only stored in the backing file once it is encoded.
+pub struct Transforms {
+    rules: Vec<(Pattern, Transform)>,
+    // The rule applying to each inode, which is decided when it is looked up
+    inodes: BTreeMap<u64, usize>,
+    transformed: BTreeMap<u64, Transformed>,
+    // Content which has been replaced, but is still open
+    retired: Vec<Transformed>,
+}
+
+impl Transforms {
+    pub fn new(rules: &[TransformRule]) -> Transforms {
+        Transforms {
+            rules: rules
+                .iter()
+                .map(|rule| (Pattern::new(&rule.pattern), rule.transform.clone()))
+                .collect(),
+            inodes: BTreeMap::new(),
+            transformed: BTreeMap::new(),
+            retired: Vec::new(),
+        }
+    }
+
+    // Decide whether a rule applies to the regular file ino, which was looked
+    // up at path. Once a rule applies to an inode it applies until the inode
+    // is forgotten, even if it is later looked up through another path.
+    pub fn attach(&mut self, ino: u64, path: &Path) {
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .position(|(pattern, _)| pattern.matches(path, false))
+        {
+            self.inodes.insert(ino, rule);
+        }
+    }
+
+    pub fn applies(&self, ino: u64) -> bool {
+        self.inodes.contains_key(&ino)
+    }
+
+    // Whether the transformed content of ino can be written back
+    pub fn writable(&self, ino: u64) -> bool {
+        self.inodes.get(&ino).is_some_and(|rule| {
+            matches!(
+                self.rules[*rule].1,
+                Transform::Codec { .. } | Transform::Compress
+            )
+        })
+    }
+
+    // Return the transformed content of ino, whose O_PATH handle is handle,
+    // or None if no rule applies to it. If the content has yet to be
+    // produced, the job which produces it is returned too.
+    pub fn start(
+        &mut self,
+        ino: u64,
+        handle: &File,
+    ) -> io::Result<Option<(Arc<File>, Option<Job>)>> {
+        let rule = match self.inodes.get(&ino) {
+            Some(rule) => *rule,
+            None => return Ok(None),
+        };
+
+        // Content which couldn't be produced is tried again
+        let stat = fstat(handle)?;
+        let source = file_version(&stat);
+        if let Some(transformed) = self.transformed.remove(&ino) {
+            if transformed.source == source && transformed.loader.succeeded() != Some(false) {
+                let file = Arc::clone(&transformed.file);
+                self.transformed.insert(ino, transformed);
+                return Ok(Some((file, None)));
+            }
+            self.retire(transformed);
+        }
+
+        // An empty file is presented as empty, so that a file created through
+        // a codec is valid before anything has been written to it. A file
+        // compressed in chunks needs nothing more than its index.
+        debug!("transform inode={}", ino);
+        let file = Arc::new(memfd()?);
+        let mut chunks = None;
+        let mut job = None;
+        if stat.st_size > 0 {
+            let transform = &self.rules[rule].1;
+            if let Transform::Compress = transform {
+                chunks = Chunks::open(reopen(handle, libc::O_RDONLY)?, &file)?;
+            }
+            if chunks.is_none() {
+                job = Some((transform.clone(), reopen(handle, libc::O_RDONLY)?));
+            }
+        }
+
+        let loader = Arc::new(Loader::new(chunks, job.is_none()));
+        let job = job.map(|(transform, input)| {
+            let loader = Arc::clone(&loader);
+            let file = Arc::clone(&file);
+            Box::new(move || {
+                let result = transform_content(&transform, input, &file);
+                if let Err(err) = &result {
+                    warn!("transform inode={}: {}", ino, err);
+                }
+                loader.finish(&result);
+            }) as Job
+        });
+        self.transformed.insert(
+            ino,
+            Transformed {
+                source,
+                file: Arc::clone(&file),
+                dirty: false,
+                loader,
+            },
+        );
+        Ok(Some((file, job)))
+    }
+
+    // Return the transformed content of ino, as start does, once it has been
+    // produced, and its size
+    pub fn get(&mut self, ino: u64, handle: &File) -> io::Result<Option<(Arc<File>, u64)>> {
+        let (file, job) = match self.start(ino, handle)? {
+            Some(started) => started,
+            None => return Ok(None),
+        };
+        if let Some(job) = job {
+            job();
+        }
+        if let Some(loader) = self.loader(ino, &file) {
+            loader.load(0, 0)?;
+        }
+        let size = fstat(&file)?.st_size as u64;
+        Ok(Some((file, size)))
+    }
+
+    // The size of the transformed content of ino, whose O_PATH handle is
+    // handle, if it is known without producing the content: once it has been
+    // produced, or from the header of a file compressed in chunks
+    pub fn size(&self, ino: u64, handle: &File) -> io::Result<Option<u64>> {
+        let rule = match self.inodes.get(&ino) {
+            Some(rule) => *rule,
+            None => return Ok(None),
+        };
+        let stat = fstat(handle)?;
+        if let Some(transformed) = self.transformed.get(&ino) {
+            if transformed.source == file_version(&stat)
+                && transformed.loader.succeeded() == Some(true)
+            {
+                return Ok(Some(fstat(&transformed.file)?.st_size as u64));
+            }
+        }
+        match self.rules[rule].1 {
+            _ if stat.st_size == 0 => Ok(Some(0)),
+            Transform::Compress => {
+                let header = read_header(&reopen(handle, libc::O_RDONLY)?)?;
+                Ok(header.map(|(size, _, _)| size))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // The loader of file, the transformed content of ino, which must load
+    // whatever is used of file first
+    pub fn loader(&self, ino: u64, file: &Arc<File>) -> Option<Arc<Loader>> {
+        self.transformed
+            .get(&ino)
+            .into_iter()
+            .chain(&self.retired)
+            .find(|transformed| Arc::ptr_eq(&transformed.file, file))
+            .map(|transformed| Arc::clone(&transformed.loader))
+    }
+
+    // Keep content which has been replaced for as long as it is open, as
+    // whatever has it open still reads it through its loader
+    fn retire(&mut self, transformed: Transformed) {
+        self.retired
+            .retain(|transformed| Arc::strong_count(&transformed.file) > 1);
+        if Arc::strong_count(&transformed.file) > 1 {
+            self.retired.push(transformed);
+        }
+    }
+
+    // Return the transformed content of ino, whose O_PATH handle is handle,
+    // truncated, as for an open with O_TRUNC. Content which hasn't been
+    // produced yet is started afresh instead, as there is no need to.
+    pub fn truncated(&mut self, ino: u64, handle: &File) -> io::Result<Arc<File>> {
+        let source = file_version(&fstat(handle)?);
+        let current = self
+            .transformed
+            .get(&ino)
+            .filter(|transformed| transformed.source == source)
+            .filter(|transformed| transformed.loader.succeeded() == Some(true))
+            .map(|transformed| Arc::clone(&transformed.file));
+        if let Some(file) = current {
+            self.set_len(ino, &file, 0)?;
+            return Ok(file);
+        }
+
+        if let Some(transformed) = self.transformed.remove(&ino) {
+            self.retire(transformed);
+        }
+        let file = Arc::new(memfd()?);
+        self.transformed.insert(
+            ino,
+            Transformed {
+                source,
+                file: Arc::clone(&file),
+                dirty: true,
+                loader: Arc::new(Loader::new(None, true)),
+            },
+        );
+        Ok(file)
+    }
+
+    // Truncate the transformed content of ino, file, to size
+    pub fn set_len(&mut self, ino: u64, file: &Arc<File>, size: u64) -> io::Result<()> {
+        match self.loader(ino, file) {
+            Some(loader) => loader.set_len(file, size)?,
+            None => file.set_len(size)?,
+        }
+        self.mark_dirty(ino);
+        Ok(())
+    }
+
+    // Record that the transformed content of ino has been written
+    pub fn mark_dirty(&mut self, ino: u64) {
+        if let Some(transformed) = self.transformed.get_mut(&ino) {
+            transformed.dirty = true;
+        }
+    }
+
+    // If the transformed content of ino has been written, return it encoded
+    // as it is to be stored in the backing file
+    pub fn encode(&mut self, ino: u64) -> io::Result<Option<File>> {
+        let transform = match self.inodes.get(&ino) {
+            Some(rule) => &self.rules[*rule].1,
+            None => return Ok(None),
+        };
+        let transformed = match self.transformed.get(&ino) {
+            Some(transformed) if transformed.dirty && self.writable(ino) => transformed,
+            _ => return Ok(None),
+        };
+
+        debug!("write back inode={}", ino);
+        let encoded = memfd()?;
+        let result = transformed
+            .loader
+            .load(0, u64::MAX)
+            .and_then(|()| match transform {
+                Transform::Codec { encode, .. } => reopen(&transformed.file, libc::O_RDONLY)
+                    .and_then(|input| run(encode, input, &encoded)),
+                _ => compress(&transformed.file, &encoded),
+            });
+        if let Err(err) = result {
+            warn!("write back inode={}: {}", ino, err);
+            return Err(err);
+        }
+        Ok(Some(encoded))
+    }
+
+    // Record that the content of ino returned by encode has been stored in
+    // the backing file, whose O_PATH handle is handle
+    pub fn stored(&mut self, ino: u64, handle: &File) -> io::Result<()> {
+        if let Some(transformed) = self.transformed.get_mut(&ino) {
+            transformed.source = file_version(&fstat(handle)?);
+            transformed.dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, ino: u64) {
+        self.inodes.remove(&ino);
+        if let Some(transformed) = self.transformed.remove(&ino) {
+            self.retire(transformed);
+        }
+    }
+}
+
+// Run command with input as stdin and output as stdout
+fn run(command: &str, input: File, output: &File) -> io::Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::from(input))
+        .stdout(Stdio::from(output.try_clone()?))
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command, status
+        )));
+    }
+    Ok(())
+}
+
+// Write the content of input transformed by transform to output. A file
+// compressed as a single stream, rather than in chunks, is decompressed in
+// full.
+fn transform_content(transform: &Transform, input: File, output: &File) -> io::Result<()> {
+    match transform {
+        Transform::Command(command) => run(command, input, output),
+        Transform::Codec { decode, .. } => run(decode, input, output),
+        Transform::Compress => run(DECOMPRESS, input, output),
+        Transform::ToLf => convert_line_endings(input, output, false),
+        Transform::ToCrlf => convert_line_endings(input, output, true),
+        Transform::Substitute(values) => substitute(input, output, values),
+        Transform::Redact(redactions) => redact(input, output, redactions),
+    }
+}
+
+// Run command with input as its stdin, and return its stdout
+fn pipe(command: &str, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // input is written from another thread, so that neither pipe can fill up
+    // while we are waiting on the other
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut output = Vec::new();
+    let written = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let read = stdout.read_to_end(&mut output);
+        writer.join().unwrap().and(read.map(|_| ()))
+    });
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command, status
+        )));
+    }
+    written.map(|()| output)
+}
+
+// Compress content into output in chunks, with the header and index Chunks
+// reads
+fn compress(content: &File, output: &File) -> io::Result<()> {
+    let size = fstat(content)?.st_size as u64;
+    let count = size.div_ceil(CHUNK_SIZE);
+    let count = u32::try_from(count).map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+
+    let start = HEADER_LEN + u64::from(count) * 8;
+    let mut header = Vec::with_capacity(start as usize);
+    header.extend_from_slice(COMPRESSED_MAGIC);
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&(CHUNK_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&count.to_le_bytes());
+
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    let mut end = 0;
+    for i in 0..u64::from(count) {
+        let chunk = &mut buffer[..CHUNK_SIZE.min(size - i * CHUNK_SIZE) as usize];
+        content.read_exact_at(chunk, i * CHUNK_SIZE)?;
+        let frame = pipe(COMPRESS, chunk)?;
+        output.write_all_at(&frame, start + end)?;
+        end += frame.len() as u64;
+        header.extend_from_slice(&end.to_le_bytes());
+    }
+    output.write_all_at(&header, 0)
+}
+
+// Copy input to output, converting CRLF to LF, or LF to CRLF if to_crlf is
+// set. A lone CR is left alone.
+fn convert_line_endings(input: File, output: &File, to_crlf: bool) -> io::Result<()> {
+    let mut writer = BufWriter::new(output);
+    let mut prev = 0;
+    for byte in BufReader::new(input).bytes() {
+        let byte = byte?;
+        if to_crlf {
+            if byte == b'\n' && prev != b'\r' {
+                writer.write_all(b"\r")?;
+            }
+            writer.write_all(&[byte])?;
+        } else {
+            // A CR is held back until we know it isn't followed by LF
+            if prev == b'\r' && byte != b'\n' {
+                writer.write_all(b"\r")?;
+            }
+            if byte != b'\r' {
+                writer.write_all(&[byte])?;
+            }
+        }
+        prev = byte;
+    }
+    if !to_crlf && prev == b'\r' {
+        writer.write_all(b"\r")?;
+    }
+    writer.flush()
+}
+
+// Copy input to output, replacing each ${NAME} with the value of NAME
+fn substitute(mut input: File, output: &File, values: &BTreeMap<String, String>) -> io::Result<()> {
+    let mut content = Vec::new();
+    input.read_to_end(&mut content)?;
+
+    let mut writer = BufWriter::new(output);
+    let mut rest = &content[..];
+    while let Some(start) = rest.windows(2).position(|window| window == b"${") {
+        writer.write_all(&rest[..start])?;
+        rest = &rest[start..];
+
+        let value = rest.iter().position(|c| *c == b'}').and_then(|end| {
+            let name = std::str::from_utf8(&rest[2..end]).ok()?;
+            Some((end, values.get(name)?))
+        });
+        match value {
+            Some((end, value)) => {
+                writer.write_all(value.as_bytes())?;
+                rest = &rest[end + 1..];
+            }
+            None => {
+                writer.write_all(b"$")?;
+                rest = &rest[1..];
+            }
+        }
+    }
+    writer.write_all(rest)?;
+    writer.flush()
+}
+
+// Copy input to output, replacing every byte hidden by redactions with *,
+// other than newlines
+fn redact(mut input: File, output: &File, redactions: &[Redaction]) -> io::Result<()> {
+    let mut content = Vec::new();
+    input.read_to_end(&mut content)?;
+
+    let mut hidden = vec![false; content.len()];
+    let mut hide = |start: usize, end: usize| hidden[start..end].fill(true);
+    let find = |needle: &[u8]| {
+        let content = &content;
+        (0..content.len())
+            .filter(move |i| !needle.is_empty() && content[*i..].starts_with(needle))
+            .collect::<Vec<_>>()
+    };
+    for redaction in redactions {
+        match redaction {
+            Redaction::Bytes { offset, len } => {
+                let start = content.len().min(*offset as usize);
+                let end = content.len().min(start.saturating_add(*len as usize));
+                hide(start, end);
+            }
+            Redaction::Text(text) => {
+                for start in find(text.as_bytes()) {
+                    hide(start, start + text.len());
+                }
+            }
+            Redaction::Value(marker) => {
+                for start in find(marker.as_bytes()) {
+                    let start = start + marker.len();
+                    let end = content[start..]
+                        .iter()
+                        .position(|c| *c == b'\n')
+                        .map_or(content.len(), |i| start + i);
+                    hide(start, end);
+                }
+            }
+        }
+    }
+
+    for (byte, hidden) in content.iter_mut().zip(hidden) {
+        if hidden && *byte != b'\n' {
+            *byte = b'*';
+        }
+    }
+    let mut output = output;
+    output.write_all(&content)
+}
+
+// Create an anonymous file in memory
+fn memfd() -> io::Result<File> {
+    let name = b"passfs-transform\0";
+    #[cfg(target_os = "linux")]
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) }
+        as libc::c_int;
+    #[cfg(target_os = "freebsd")]
+    let fd = unsafe { libc::memfd_create(name.as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
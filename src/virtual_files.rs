@@ -0,0 +1,106 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use fuser::{FileAttr, FileType};
+
+use crate::config::{VirtualContent, VirtualFile};
+
+// Virtual files are numbered down from the top of the inode space, far above
+// any synthetic inode number we will hand out
+const LAST_VIRTUAL_INODE: u64 = u64::MAX;
+
+// The files presented in the root which have no backing file. Their content
+// is fixed when the filesystem is mounted.
+pub struct VirtualFiles {
+    files: Vec<(OsString, Arc<Vec<u8>>)>,
+    created: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+impl VirtualFiles {
+    // Run the command of every virtual file which has one, failing if any
+    // command fails
+    pub fn new(files: &[VirtualFile]) -> io::Result<VirtualFiles> {
+        let mut contents = Vec::with_capacity(files.len());
+        for file in files {
+            let content = match &file.content {
+                VirtualContent::Literal(content) => content.clone(),
+                VirtualContent::Command(command) => run(command)?,
+            };
+            contents.push((OsString::from(&file.name), Arc::new(content)));
+        }
+
+        Ok(VirtualFiles {
+            files: contents,
+            created: SystemTime::now(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // The inode of the virtual file called name in the root, if any
+    pub fn find(&self, name: &OsStr) -> Option<u64> {
+        let i = self.files.iter().position(|(known, _)| known == name)?;
+        Some(LAST_VIRTUAL_INODE - i as u64)
+    }
+
+    pub fn content(&self, ino: u64) -> Option<&Arc<Vec<u8>>> {
+        self.files
+            .get(LAST_VIRTUAL_INODE.checked_sub(ino)? as usize)
+            .map(|(_, content)| content)
+    }
+
+    pub fn contains(&self, ino: u64) -> bool {
+        self.content(ino).is_some()
+    }
+
+    // The inode and name of every virtual file
+    pub fn entries(&self) -> impl Iterator<Item = (u64, &OsStr)> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (LAST_VIRTUAL_INODE - i as u64, name.as_os_str()))
+    }
+
+    pub fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let size = self.content(ino)?.len() as u64;
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        })
+    }
+}
+
+// Return the output of running command with sh
+fn run(command: &str) -> io::Result<Vec<u8>> {
+    let output = Command::new("sh").arg("-c").arg(command).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command, output.status
+        )));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::iter;
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{warn, Span};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// What the timer thread is sent
+enum TimerJob {
+    // A job, and when it is due to run on a worker
+    Delayed(Instant, Job),
+    // A job to run on the timer thread itself, straight away
+    Here(Job),
+}
+
+// A pool of threads which run jobs handed to them by the FUSE dispatch
+// thread. fuser delivers requests to us one at a time, but a reply may be sent
+// from any thread, so the dispatch thread can move on to the next request
+// while a worker completes a slow one.
+//
+// Jobs which must wait before they run are held by a timer thread until they
+// are due, so that waiting doesn't take up a worker, or the dispatch thread.
+pub struct Workers {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    timer: Timer,
+    timer_thread: Option<JoinHandle<()>>,
+}
+
+impl Workers {
+    // With no threads, every job is run immediately on the calling thread,
+    // and delayed jobs on the timer thread
+    pub fn new(count: usize) -> Workers {
+        let (sender, threads) = if count == 0 {
+            (None, Vec::new())
+        } else {
+            let (sender, threads) = Workers::start(count);
+            (Some(sender), threads)
+        };
+
+        let (timer, receiver) = mpsc::channel();
+        let jobs = sender.clone();
+        let timer_thread = thread::Builder::new()
+            .name("passfs-timer".into())
+            .spawn(move || run_timer(receiver, jobs))
+            .expect("Unable to start timer thread");
+
+        Workers {
+            sender,
+            threads,
+            timer: Timer(Arc::new(Mutex::new(Some(timer)))),
+            timer_thread: Some(timer_thread),
+        }
+    }
+
+    fn start(count: usize) -> (mpsc::Sender<Job>, Vec<JoinHandle<()>>) {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..count)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("passfs-worker-{}", i))
+                    .spawn(move || loop {
+                        // The lock is only held while waiting for a job
+                        let job = match receiver.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            // The pool has been dropped
+                            Err(_) => return,
+                        };
+                        job();
+                    })
+                    .expect("Unable to start worker thread")
+            })
+            .collect();
+        (sender, threads)
+    }
+
+    pub fn run<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // The job runs in the span of the request which handed it over
+        let span = Span::current();
+        let job = move || span.in_scope(job);
+        match &self.sender {
+            Some(sender) => {
+                if let Err(mpsc::SendError(job)) = sender.send(Box::new(job)) {
+                    warn!("Worker threads have exited: running job inline");
+                    job();
+                }
+            }
+            None => job(),
+        }
+    }
+
+    // Run job as run does, once delay has passed
+    pub fn run_after<F>(&self, delay: Duration, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if delay.is_zero() {
+            return self.run(job);
+        }
+        self.timer.run_after(delay, job)
+    }
+
+    // A handle on the timer thread, for jobs handed over once the request
+    // has been handled
+    pub fn timer(&self) -> Timer {
+        Timer(Arc::clone(&self.timer.0))
+    }
+
+    // Run job on every worker thread, on the timer thread and on the calling
+    // thread, returning the first error any of them fails with once they have
+    // all finished. Every thread runs it exactly once, as none takes another
+    // job until they have all taken one. Only Landlock needs this, which is
+    // only on Linux.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn run_on_all<F>(&self, job: F) -> io::Result<()>
+    where
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        let job = Arc::new(job);
+        let barrier = Arc::new(Barrier::new(self.threads.len() + 2));
+        let (sender, receiver) = mpsc::channel();
+        let on_thread = || {
+            let job = Arc::clone(&job);
+            let barrier = Arc::clone(&barrier);
+            let sender = sender.clone();
+            move || {
+                let _ = sender.send(job());
+                barrier.wait();
+            }
+        };
+        for _ in &self.threads {
+            self.run(on_thread());
+        }
+        // A delayed job would be handed on to a worker, all of which are
+        // waiting at the barrier by then
+        let timer = self.timer.0.lock().unwrap().as_ref().map(|timer| {
+            let job: Job = Box::new(on_thread());
+            timer.send(TimerJob::Here(job))
+        });
+        if !matches!(timer, Some(Ok(()))) {
+            return Err(io::Error::other("timer thread has exited"));
+        }
+        drop(sender);
+
+        let result = job();
+        barrier.wait();
+        iter::once(result).chain(receiver).collect()
+    }
+}
+
+// Runs jobs on the workers of a pool once a delay has passed. Handles share
+// the pool's channel to the timer thread, rather than each holding one open,
+// so that the pool can close it while jobs holding handles are still queued.
+pub struct Timer(Arc<Mutex<Option<mpsc::Sender<TimerJob>>>>);
+
+impl Timer {
+    pub fn run_after<F>(&self, delay: Duration, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let span = Span::current();
+        let job = move || span.in_scope(job);
+        let due = Instant::now() + delay;
+        let job: Job = Box::new(job);
+        let unsent = match &*self.0.lock().unwrap() {
+            Some(timer) => match timer.send(TimerJob::Delayed(due, job)) {
+                Err(mpsc::SendError(TimerJob::Delayed(_, job))) => Some(job),
+                _ => None,
+            },
+            None => Some(job),
+        };
+        // Once the pool is shutting down, jobs are run without waiting
+        if let Some(job) = unsent {
+            job();
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        // The timer thread runs what it is holding and exits, and then closing
+        // the channel tells the workers to exit once it is drained
+        self.timer.0.lock().unwrap().take();
+        if let Some(thread) = self.timer_thread.take() {
+            if thread.join().is_err() {
+                warn!("Timer thread panicked");
+            }
+        }
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+// Hold each delayed job received until it is due, and then run it on a
+// worker, or on this thread if there are none. Once the channel is closed,
+// jobs are run without waiting any longer.
+fn run_timer(receiver: mpsc::Receiver<TimerJob>, jobs: Option<mpsc::Sender<Job>>) {
+    let run = |job: Job| match &jobs {
+        Some(jobs) => {
+            if let Err(mpsc::SendError(job)) = jobs.send(job) {
+                job();
+            }
+        }
+        None => job(),
+    };
+
+    // Keyed by when they are due, and then in order of arrival
+    let mut pending: BTreeMap<(Instant, u64), Job> = BTreeMap::new();
+    let mut next = 0u64;
+    loop {
+        let received = match pending.keys().next() {
+            Some((due, _)) => receiver.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => receiver
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(TimerJob::Delayed(due, job)) => {
+                pending.insert((due, next), job);
+                next += 1;
+            }
+            Ok(TimerJob::Here(job)) => job(),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                pending.into_values().for_each(run);
+                return;
+            }
+        }
+
+        let now = Instant::now();
+        while let Some(entry) = pending.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            run(entry.remove());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // run_on_all returns, having run the job once on every worker, on the
+    // timer thread and on the calling thread
+    fn run_on_all(count: usize) {
+        let workers = Workers::new(count);
+        let runs = Arc::new(Mutex::new(BTreeMap::new()));
+        let job_runs = Arc::clone(&runs);
+        workers
+            .run_on_all(move || {
+                let name = thread::current().name().map(String::from);
+                *job_runs.lock().unwrap().entry(name).or_insert(0) += 1;
+                Ok(())
+            })
+            .unwrap();
+
+        let runs = runs.lock().unwrap();
+        assert_eq!(runs.len(), count + 2, "{:?}", runs);
+        assert!(runs.values().all(|&n| n == 1), "{:?}", runs);
+        assert_eq!(runs.get(&Some("passfs-timer".into())), Some(&1));
+    }
+
+    #[test]
+    fn run_on_all_without_workers() {
+        run_on_all(0);
+    }
+
+    #[test]
+    fn run_on_all_workers() {
+        run_on_all(4);
+    }
+
+    // The first error is returned once every thread has run the job
+    #[test]
+    fn run_on_all_error() {
+        let workers = Workers::new(2);
+        let err = workers
+            .run_on_all(|| Err(io::Error::from_raw_os_error(libc::EPERM)))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    }
+}
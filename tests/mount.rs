@@ -0,0 +1,57 @@
+// Tests of a real passfs mount, which are skipped where FUSE isn't available
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use passfs::testing::mount_temp;
+use passfs::Config;
+
+// Whether we can mount a FUSE filesystem here. Mounting as an unprivileged
+// user on Linux goes through fusermount.
+fn fuse_available() -> bool {
+    if !Path::new("/dev/fuse").exists() {
+        return false;
+    }
+    cfg!(not(target_os = "linux")) || Command::new("fusermount").arg("-V").output().is_ok()
+}
+
+// A new directory to use as the root of a mount, which the caller removes
+fn temp_root(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("passfs-root-{}-{}", std::process::id(), name));
+    fs::create_dir(&root).unwrap();
+    root
+}
+
+#[test]
+fn read_only_mount() {
+    if !fuse_available() {
+        eprintln!("Skipping: /dev/fuse or fusermount is unavailable");
+        return;
+    }
+
+    let root = temp_root("read_only_mount");
+    fs::create_dir(root.join("dir")).unwrap();
+    fs::write(root.join("dir/file"), b"content").unwrap();
+
+    let result = std::panic::catch_unwind(|| {
+        let mount = mount_temp(&root, Config::default()).unwrap();
+        let names: Vec<_> = fs::read_dir(mount.path().join("dir"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["file"]);
+        assert_eq!(fs::read(mount.path().join("dir/file")).unwrap(), b"content");
+        assert!(fs::metadata(mount.path().join("missing")).is_err());
+
+        // Without read_write, nothing can be changed
+        assert!(fs::write(mount.path().join("dir/file"), b"changed").is_err());
+        assert!(fs::remove_file(mount.path().join("dir/file")).is_err());
+    });
+
+    fs::remove_dir_all(&root).unwrap();
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}